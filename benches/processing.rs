@@ -0,0 +1,31 @@
+//! Throughput of parsing and processing CSV input, in rows per second
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use payengine::processor::InMemoryProcessor;
+use payengine::synthetic::synthetic_csv;
+
+fn rows() -> Vec<usize> {
+    match std::env::var("PAYENGINE_BENCH_ROWS") {
+        Ok(rows) => vec![rows.parse().expect("PAYENGINE_BENCH_ROWS must be a number")],
+        Err(_) => vec![1_000, 100_000],
+    }
+}
+
+fn process_reader(c: &mut Criterion) {
+    let mut group = c.benchmark_group("process_reader");
+    for rows in rows() {
+        let input = synthetic_csv(rows, 1_000);
+        group.throughput(Throughput::Elements(rows as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(rows), &input, |b, input| {
+            b.iter(|| {
+                let mut processor = InMemoryProcessor::default();
+                processor
+                    .process_reader(input.as_bytes())
+                    .expect("reading from memory can't fail")
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, process_reader);
+criterion_main!(benches);
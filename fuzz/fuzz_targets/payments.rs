@@ -0,0 +1,22 @@
+//! Random streams of payments must never panic the processor
+//! or break the balance invariants
+//!
+//! Run with `cargo fuzz run payments`.
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use payengine::payment::Payment;
+use payengine::processor::{InMemoryProcessor, Processor};
+
+fuzz_target!(|payments: Vec<Payment>| {
+    let mut processor = InMemoryProcessor::default();
+    for payment in payments {
+        let _ = processor.process(payment);
+        for (_, account) in processor.get_all_accounts() {
+            assert!(account.held_funds <= account.total_funds);
+            assert_eq!(
+                account.available_funds().checked_add(account.held_funds),
+                Some(account.total_funds)
+            );
+        }
+    }
+});
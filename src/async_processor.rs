@@ -0,0 +1,141 @@
+//! Processing of payments submitted from many async tasks
+//!
+//! The processor runs in a task of its own and gets requests over a bounded
+//! channel, so while it is busy, submitters wait instead of piling up an
+//! unlimited queue.
+use crate::payment::{ClientID, Payment, RoundingMode};
+use crate::processor::{AccountState, InMemoryProcessor, Processor, Result};
+use std::future::Future;
+use std::pin::Pin;
+use tokio::sync::{mpsc, oneshot};
+
+/// Future returned by the methods of `AsyncProcessor`
+///
+/// Boxed, as traits can't have `async fn`s (or return `impl Future`)
+/// before Rust 1.75.
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// Async counterpart of `Processor`
+///
+/// Methods take `&self`, so a single processor can serve many tasks.
+pub trait AsyncProcessor {
+    /// Process a payment
+    fn process(&self, payment: Payment) -> BoxFuture<'_, Result<()>>;
+    fn get_account(&self, client_id: ClientID) -> BoxFuture<'_, Option<AccountState>>;
+    /// Number of transactions (deposits and withdrawals) of a client
+    fn get_transaction_count(&self, client_id: ClientID) -> BoxFuture<'_, Option<usize>>;
+}
+
+enum Request {
+    Process(Payment, oneshot::Sender<Result<()>>),
+    GetAccount(ClientID, oneshot::Sender<Option<AccountState>>),
+    GetTransactionCount(ClientID, oneshot::Sender<Option<usize>>),
+}
+
+/// Handle to an `InMemoryProcessor` running in its own task
+///
+/// Cheap to clone; the task ends once all the handles are dropped.
+#[derive(Clone)]
+pub struct ProcessorHandle {
+    requests: mpsc::Sender<Request>,
+    // of the processor, for parsing submitted amounts
+    amount_decimal_places: usize,
+    amount_rounding: Option<RoundingMode>,
+}
+
+impl ProcessorHandle {
+    /// Move `processor` into a new task, with room for `capacity` pending requests
+    ///
+    /// Must be called from within a tokio runtime.
+    pub fn spawn(mut processor: InMemoryProcessor, capacity: usize) -> Self {
+        let amount_decimal_places = processor.amount_decimal_places();
+        let amount_rounding = processor.amount_rounding();
+        let (requests, mut receiver) = mpsc::channel(capacity);
+        tokio::spawn(async move {
+            while let Some(request) = receiver.recv().await {
+                // the requester may have stopped waiting for the reply, that's fine
+                match request {
+                    Request::Process(payment, reply) => {
+                        let _ = reply.send(processor.process(payment));
+                    }
+                    Request::GetAccount(client_id, reply) => {
+                        let _ = reply.send(processor.get_account(client_id).cloned());
+                    }
+                    Request::GetTransactionCount(client_id, reply) => {
+                        let _ = reply.send(processor.get_transaction_count(client_id));
+                    }
+                }
+            }
+        });
+        Self {
+            requests,
+            amount_decimal_places,
+            amount_rounding,
+        }
+    }
+
+    /// Decimal places of the amounts of the processor
+    pub fn amount_decimal_places(&self) -> usize {
+        self.amount_decimal_places
+    }
+
+    /// How the processor rounds input amounts with excess decimal places
+    pub fn amount_rounding(&self) -> Option<RoundingMode> {
+        self.amount_rounding
+    }
+
+    // the task only goes away by panicking, so pass that on
+    async fn request<T>(&self, request: impl FnOnce(oneshot::Sender<T>) -> Request) -> T {
+        let (reply, response) = oneshot::channel();
+        self.requests
+            .send(request(reply))
+            .await
+            .expect("processor task stopped");
+        response.await.expect("processor task stopped")
+    }
+}
+
+impl AsyncProcessor for ProcessorHandle {
+    fn process(&self, payment: Payment) -> BoxFuture<'_, Result<()>> {
+        Box::pin(self.request(|reply| Request::Process(payment, reply)))
+    }
+
+    fn get_account(&self, client_id: ClientID) -> BoxFuture<'_, Option<AccountState>> {
+        Box::pin(self.request(move |reply| Request::GetAccount(client_id, reply)))
+    }
+
+    fn get_transaction_count(&self, client_id: ClientID) -> BoxFuture<'_, Option<usize>> {
+        Box::pin(self.request(move |reply| Request::GetTransactionCount(client_id, reply)))
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn concurrent_deposits() -> anyhow::Result<()> {
+    use crate::payment::{Amount, Deposit};
+
+    // less room than tasks, so some of them have to wait
+    let processor = ProcessorHandle::spawn(InMemoryProcessor::default(), 8);
+    let tasks: Vec<_> = (1..=100u32)
+        .map(|tx| {
+            let processor = processor.clone();
+            tokio::spawn(async move {
+                processor
+                    .process(Payment::Deposit(Deposit {
+                        client: 1,
+                        tx,
+                        amount: Amount(u64::from(tx)),
+                    }))
+                    .await
+            })
+        })
+        .collect();
+    for task in tasks {
+        task.await??;
+    }
+
+    let account = processor.get_account(1).await.unwrap();
+    assert_eq!(*account.total_funds, (1..=100).sum::<u64>());
+    assert_eq!(processor.get_transaction_count(1).await, Some(100));
+    assert!(processor.get_account(2).await.is_none());
+    Ok(())
+}
@@ -0,0 +1,594 @@
+//! Replayable log of payments and administrative changes
+//!
+//! The log is written ahead: every event is logged before it's applied,
+//! so the log is never behind the state. Rejected events are logged as
+//! well; replaying (with the same config and fee policy) rejects them
+//! again the same way.
+//!
+//! The log starts with a header: `MAGIC` followed by the format version
+//! (`u16`, little endian). Then each event is stored as a frame:
+//! payload length (`u32`, little endian) followed by the payload, which
+//! is a single header-less CSV record of `Frame`.
+//!
+//! Readers reject logs with a version they don't know, so the frame
+//! format can evolve (with a migration if necessary). Version 1 had
+//! payments only, without the `delta` and `reason` columns.
+use crate::payment::{
+    Amount, ClientID, Deposit, Dispute, Payment, SignedAmount, TransactionID, Withdrawal,
+};
+use crate::processor::{self, AccountState, FeePolicy, FreezeReason, InMemoryProcessor, Processor};
+use serde::{Deserialize, Serialize};
+use std::io::{Read, Write};
+use std::path::Path;
+use thiserror::Error;
+
+pub const MAGIC: &[u8; 6] = b"PAYLOG";
+pub const LOG_VERSION: u16 = 2;
+
+type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("invalid frame: {0}")]
+    InvalidFrame(#[from] csv::Error),
+    #[error("not an event log")]
+    InvalidHeader,
+    #[error("unsupported log version: {0}")]
+    UnsupportedLogVersion(u16),
+    #[error("invalid event in frame: {0}")]
+    InvalidType(String),
+}
+
+/// Entry of the event log
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    Payment(Payment),
+    /// See `InMemoryProcessor::freeze_account`
+    Freeze {
+        client: ClientID,
+        reason: FreezeReason,
+    },
+    /// See `InMemoryProcessor::unfreeze_account`
+    Unfreeze {
+        client: ClientID,
+    },
+    /// See `InMemoryProcessor::adjust_balance`
+    Adjustment {
+        client: ClientID,
+        delta: SignedAmount,
+        reason: String,
+    },
+}
+
+// Note: amounts are stored as raw `Amount` values to keep the log exact
+#[derive(Serialize, Deserialize, Debug)]
+struct Frame {
+    r#type: String,
+    client: ClientID,
+    // only set for payments
+    tx: Option<TransactionID>,
+    amount: Option<u64>,
+    // only set for administrative changes; not in version 1 frames
+    #[serde(default)]
+    delta: Option<i128>,
+    #[serde(default)]
+    reason: Option<String>,
+}
+
+impl From<&Payment> for Frame {
+    fn from(payment: &Payment) -> Self {
+        let (r#type, tx, amount) = match payment {
+            Payment::Deposit(d) => ("deposit", d.tx, Some(*d.amount)),
+            Payment::Withdrawal(d) => ("withdrawal", d.tx, Some(*d.amount)),
+            Payment::Dispute(d) => ("dispute", d.tx, d.amount.map(|amount| *amount)),
+            Payment::Resolve(d) => ("resolve", d.tx, d.amount.map(|amount| *amount)),
+            Payment::Chargeback(d) => ("chargeback", d.tx, d.amount.map(|amount| *amount)),
+            Payment::Reversal(d) => ("reversal", d.tx, None),
+        };
+        Frame {
+            r#type: r#type.to_string(),
+            client: payment.get_client_id(),
+            tx: Some(tx),
+            amount,
+            delta: None,
+            reason: None,
+        }
+    }
+}
+
+impl From<&Event> for Frame {
+    fn from(event: &Event) -> Self {
+        let (r#type, client, delta, reason) = match event {
+            Event::Payment(payment) => return Frame::from(payment),
+            Event::Freeze { client, reason } => ("freeze", client, None, Some(reason.to_string())),
+            Event::Unfreeze { client } => ("unfreeze", client, None, None),
+            Event::Adjustment {
+                client,
+                delta,
+                reason,
+            } => ("adjustment", client, Some(delta.0), Some(reason.clone())),
+        };
+        Frame {
+            r#type: r#type.to_string(),
+            client: *client,
+            tx: None,
+            amount: None,
+            delta,
+            reason,
+        }
+    }
+}
+
+impl Frame {
+    fn into_event(self) -> Result<Event> {
+        let Frame {
+            r#type,
+            client,
+            tx,
+            amount,
+            delta,
+            reason,
+        } = self;
+        let payment = match (r#type.as_str(), tx, amount, delta) {
+            ("deposit", Some(tx), Some(amount), None) => Payment::Deposit(Deposit {
+                client,
+                tx,
+                amount: Amount(amount),
+            }),
+            ("withdrawal", Some(tx), Some(amount), None) => Payment::Withdrawal(Withdrawal {
+                client,
+                tx,
+                amount: Amount(amount),
+            }),
+            ("dispute", Some(tx), amount, None) => Payment::Dispute(Dispute {
+                client,
+                tx,
+                amount: amount.map(Amount),
+            }),
+            ("resolve", Some(tx), amount, None) => Payment::Resolve(Dispute {
+                client,
+                tx,
+                amount: amount.map(Amount),
+            }),
+            ("chargeback", Some(tx), amount, None) => Payment::Chargeback(Dispute {
+                client,
+                tx,
+                amount: amount.map(Amount),
+            }),
+            ("reversal", Some(tx), None, None) => Payment::Reversal(Dispute {
+                client,
+                tx,
+                amount: None,
+            }),
+            ("freeze", None, None, None) => {
+                let reason = match reason.as_deref() {
+                    Some("chargeback") => FreezeReason::Chargeback,
+                    Some("administrative") => FreezeReason::Administrative,
+                    _ => return Err(Error::InvalidType(r#type)),
+                };
+                return Ok(Event::Freeze { client, reason });
+            }
+            ("unfreeze", None, None, None) => return Ok(Event::Unfreeze { client }),
+            ("adjustment", None, None, Some(delta)) => {
+                return Ok(Event::Adjustment {
+                    client,
+                    delta: SignedAmount(delta),
+                    reason: reason.unwrap_or_default(),
+                })
+            }
+            _ => return Err(Error::InvalidType(r#type)),
+        };
+        Ok(Event::Payment(payment))
+    }
+}
+
+/// Writes payments into an event log
+pub struct EventLogWriter<W: Write> {
+    inner: W,
+}
+
+impl<W: Write> EventLogWriter<W> {
+    /// Start a new log, writing the header
+    pub fn new(mut inner: W) -> Result<Self> {
+        inner.write_all(MAGIC)?;
+        inner.write_all(&LOG_VERSION.to_le_bytes())?;
+        Ok(Self { inner })
+    }
+
+    pub fn append(&mut self, payment: &Payment) -> Result<()> {
+        self.append_frame(Frame::from(payment))
+    }
+
+    pub fn append_event(&mut self, event: &Event) -> Result<()> {
+        self.append_frame(Frame::from(event))
+    }
+
+    fn append_frame(&mut self, frame: Frame) -> Result<()> {
+        let mut payload = csv::WriterBuilder::new()
+            .has_headers(false)
+            .from_writer(vec![]);
+        payload.serialize(frame)?;
+        let payload = payload
+            .into_inner()
+            .map_err(|e| std::io::Error::new(e.error().kind(), e.to_string()))?;
+
+        self.inner
+            .write_all(&(payload.len() as u32).to_le_bytes())?;
+        self.inner.write_all(&payload)?;
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<()> {
+        Ok(self.inner.flush()?)
+    }
+}
+
+// `None` on a clean end of the log (at a frame boundary)
+fn read_frame_len<R: Read>(input: &mut R) -> Result<Option<u32>> {
+    let mut buf = [0u8; 4];
+    let mut read = 0;
+    while read < buf.len() {
+        match input.read(&mut buf[read..]) {
+            Ok(0) if read == 0 => return Ok(None),
+            Ok(0) => return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into()),
+            Ok(n) => read += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(Some(u32::from_le_bytes(buf)))
+}
+
+/// Read all events from an event log
+pub fn read_log<R: Read>(mut input: R) -> Result<Vec<Event>> {
+    let mut magic = [0u8; 6];
+    input.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(Error::InvalidHeader);
+    }
+    let mut version = [0u8; 2];
+    input.read_exact(&mut version)?;
+    let version = u16::from_le_bytes(version);
+    // version 1 frames lack the trailing columns, which default to empty
+    if !(1..=LOG_VERSION).contains(&version) {
+        return Err(Error::UnsupportedLogVersion(version));
+    }
+
+    let mut events = vec![];
+    while let Some(len) = read_frame_len(&mut input)? {
+        let mut payload = vec![0u8; len as usize];
+        input.read_exact(&mut payload)?;
+        let frame: Frame = csv::ReaderBuilder::new()
+            .has_headers(false)
+            .from_reader(payload.as_slice())
+            .deserialize()
+            .next()
+            .ok_or_else(|| std::io::Error::from(std::io::ErrorKind::UnexpectedEof))??;
+        events.push(frame.into_event()?);
+    }
+    Ok(events)
+}
+
+/// Rebuild a processor from the event log at `path`
+///
+/// `config` and `fee_policy` must be the ones the log was written with,
+/// otherwise the events may not have the same effect.
+pub fn replay_log(
+    path: &Path,
+    config: processor::Config,
+    fee_policy: impl FeePolicy + Send + 'static,
+) -> Result<InMemoryProcessor> {
+    let mut processor = InMemoryProcessor::new(config).with_fee_policy(fee_policy);
+    let input = std::io::BufReader::new(std::fs::File::open(path)?);
+    for event in read_log(input)? {
+        // rejected when it was logged too, so it changes nothing
+        let _ = match event {
+            Event::Payment(payment) => processor.process(payment),
+            Event::Freeze { client, reason } => processor.freeze_account(client, reason),
+            Event::Unfreeze { client } => processor.unfreeze_account(client),
+            Event::Adjustment {
+                client,
+                delta,
+                reason,
+            } => processor.adjust_balance(client, delta, reason),
+        };
+    }
+    Ok(processor)
+}
+
+fn log_write_failed(e: Error) -> processor::Error {
+    processor::Error::LogWriteFailed(e.to_string())
+}
+
+/// Processor wrapper appending every payment to an event log, before
+/// processing it
+///
+/// An event that can't be logged isn't processed. Administrative changes
+/// are only logged when made through the wrapper (see the methods of
+/// `LoggedProcessor<InMemoryProcessor, _>`). Undoing is not supported,
+/// since the log is append-only.
+pub struct LoggedProcessor<P, W: Write> {
+    inner: P,
+    log: EventLogWriter<W>,
+}
+
+impl<P: Processor, W: Write> LoggedProcessor<P, W> {
+    pub fn new(inner: P, log: EventLogWriter<W>) -> Self {
+        Self { inner, log }
+    }
+
+    pub fn into_inner(self) -> (P, EventLogWriter<W>) {
+        (self.inner, self.log)
+    }
+}
+
+impl<W: Write> LoggedProcessor<InMemoryProcessor, W> {
+    /// Logged `InMemoryProcessor::freeze_account`
+    pub fn freeze_account(
+        &mut self,
+        client_id: ClientID,
+        reason: FreezeReason,
+    ) -> processor::Result<()> {
+        self.log
+            .append_event(&Event::Freeze {
+                client: client_id,
+                reason,
+            })
+            .map_err(log_write_failed)?;
+        self.inner.freeze_account(client_id, reason)
+    }
+
+    /// Logged `InMemoryProcessor::unfreeze_account`
+    pub fn unfreeze_account(&mut self, client_id: ClientID) -> processor::Result<()> {
+        self.log
+            .append_event(&Event::Unfreeze { client: client_id })
+            .map_err(log_write_failed)?;
+        self.inner.unfreeze_account(client_id)
+    }
+
+    /// Logged `InMemoryProcessor::adjust_balance`
+    pub fn adjust_balance(
+        &mut self,
+        client_id: ClientID,
+        delta: SignedAmount,
+        reason: String,
+    ) -> processor::Result<()> {
+        self.log
+            .append_event(&Event::Adjustment {
+                client: client_id,
+                delta,
+                reason: reason.clone(),
+            })
+            .map_err(log_write_failed)?;
+        self.inner.adjust_balance(client_id, delta, reason)
+    }
+}
+
+impl<P: Processor, W: Write> Processor for LoggedProcessor<P, W> {
+    fn process(&mut self, payment: Payment) -> processor::Result<()> {
+        self.log.append(&payment).map_err(log_write_failed)?;
+        self.inner.process(payment)
+    }
+
+    fn get_all_accounts(&self) -> Box<dyn Iterator<Item = (&ClientID, &AccountState)> + '_> {
+        self.inner.get_all_accounts()
+    }
+
+    fn get_all_clients(&self) -> Box<dyn Iterator<Item = &ClientID> + '_> {
+        self.inner.get_all_clients()
+    }
+
+    fn get_account(&self, client_id: ClientID) -> Option<&AccountState> {
+        self.inner.get_account(client_id)
+    }
+
+    fn get_transaction_count(&self, client_id: ClientID) -> Option<usize> {
+        self.inner.get_transaction_count(client_id)
+    }
+
+    fn is_disputed(&self, client_id: ClientID, tx: TransactionID) -> bool {
+        self.inner.is_disputed(client_id, tx)
+    }
+
+    fn amount_decimal_places(&self) -> usize {
+        self.inner.amount_decimal_places()
+    }
+
+    fn account_count(&self) -> usize {
+        self.inner.account_count()
+    }
+
+    fn transaction_count(&self) -> usize {
+        self.inner.transaction_count()
+    }
+}
+
+#[test]
+fn log_round_trip() -> anyhow::Result<()> {
+    struct FlatFee;
+
+    impl FeePolicy for FlatFee {
+        fn on_chargeback(&self, amount: Amount) -> Amount {
+            amount + Amount(1)
+        }
+    }
+
+    // removes the log once the test is done, even if it fails
+    struct TempFile(std::path::PathBuf);
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    let log_file = TempFile(std::env::temp_dir().join(format!(
+        "payengine-test-{}-log_round_trip.log",
+        std::process::id()
+    )));
+    let path = &log_file.0;
+    let config = processor::Config {
+        allow_unfreeze_after_chargeback: true,
+        ..Default::default()
+    };
+
+    let log = EventLogWriter::new(std::fs::File::create(path)?)?;
+    let mut processor = LoggedProcessor::new(
+        InMemoryProcessor::new(config.clone()).with_fee_policy(FlatFee),
+        log,
+    );
+    let errors = [
+        Payment::Deposit(Deposit {
+            client: 1,
+            tx: 1,
+            amount: Amount(12345),
+        }),
+        Payment::Deposit(Deposit {
+            client: 2,
+            tx: 2,
+            amount: Amount(3),
+        }),
+        Payment::Deposit(Deposit {
+            client: 2,
+            tx: 5,
+            amount: Amount(2),
+        }),
+        Payment::Withdrawal(Withdrawal {
+            client: 1,
+            tx: 3,
+            amount: Amount(5),
+        }),
+        // rejected, and again when replayed
+        Payment::Withdrawal(Withdrawal {
+            client: 2,
+            tx: 4,
+            amount: Amount(50),
+        }),
+        Payment::Dispute(Dispute {
+            client: 2,
+            tx: 2,
+            amount: None,
+        }),
+        Payment::Chargeback(Dispute {
+            client: 2,
+            tx: 2,
+            amount: None,
+        }),
+    ]
+    .iter()
+    .filter_map(|payment| processor.process(payment.clone()).err())
+    .count();
+    assert_eq!(errors, 1);
+    processor.unfreeze_account(2)?;
+    processor.adjust_balance(1, SignedAmount(-5), "correction".into())?;
+    processor.freeze_account(1, FreezeReason::Administrative)?;
+    let (processor, mut log) = processor.into_inner();
+    log.flush()?;
+
+    let replayed = replay_log(path, config, FlatFee)?;
+    assert!(processor::accounts_eq(&processor, &replayed));
+    assert_eq!(*replayed.get_account(2).unwrap().total_funds, 1);
+    assert!(!replayed.get_account(2).unwrap().locked());
+    assert_eq!(*replayed.get_account(1).unwrap().total_funds, 12335);
+    assert!(replayed.get_account(1).unwrap().locked());
+    let events = read_log(std::fs::File::open(path)?)?;
+    assert_eq!(events.len(), 10);
+    assert_eq!(
+        events[8],
+        Event::Adjustment {
+            client: 1,
+            delta: SignedAmount(-5),
+            reason: "correction".into(),
+        }
+    );
+
+    // the unfreeze and the fee depend on the config and fee policy
+    let replayed = replay_log(path, processor::Config::default(), processor::NoFees)?;
+    assert!(!processor::accounts_eq(&processor, &replayed));
+
+    Ok(())
+}
+
+#[test]
+fn read_version_1_log() -> anyhow::Result<()> {
+    let mut bytes = MAGIC.to_vec();
+    bytes.extend_from_slice(&1u16.to_le_bytes());
+    for payload in [&b"deposit,1,1,15000\n"[..], b"dispute,1,1,\n"] {
+        bytes.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(payload);
+    }
+
+    assert_eq!(
+        read_log(bytes.as_slice())?,
+        [
+            Event::Payment(Payment::Deposit(Deposit {
+                client: 1,
+                tx: 1,
+                amount: Amount(1_5000),
+            })),
+            Event::Payment(Payment::Dispute(Dispute {
+                client: 1,
+                tx: 1,
+                amount: None
+            })),
+        ]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn unlogged_payments_are_not_processed() {
+    struct FailingWriter;
+
+    impl Write for FailingWriter {
+        fn write(&mut self, _: &[u8]) -> std::io::Result<usize> {
+            Err(std::io::ErrorKind::Other.into())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let log = EventLogWriter {
+        inner: FailingWriter,
+    };
+    let mut processor = LoggedProcessor::new(InMemoryProcessor::default(), log);
+    assert!(matches!(
+        processor.process(Payment::Deposit(Deposit {
+            client: 1,
+            tx: 1,
+            amount: Amount(1),
+        })),
+        Err(processor::Error::LogWriteFailed(_))
+    ));
+    assert_eq!(processor.get_account(1), None);
+}
+
+#[test]
+fn log_version_mismatch() -> anyhow::Result<()> {
+    let mut log = EventLogWriter::new(vec![])?;
+    log.append(&Payment::Dispute(Dispute {
+        client: 1,
+        tx: 1,
+        amount: None,
+    }))?;
+    let mut bytes = log.inner;
+    assert_eq!(read_log(bytes.as_slice())?.len(), 1);
+
+    bytes[MAGIC.len()..MAGIC.len() + 2].copy_from_slice(&(LOG_VERSION + 1).to_le_bytes());
+    assert!(matches!(
+        read_log(bytes.as_slice()),
+        Err(Error::UnsupportedLogVersion(v)) if v == LOG_VERSION + 1
+    ));
+
+    assert!(matches!(
+        read_log(&b"NOTLOG\x01\x00"[..]),
+        Err(Error::InvalidHeader)
+    ));
+
+    Ok(())
+}
@@ -0,0 +1,9 @@
+#[cfg(feature = "async")]
+pub mod async_processor;
+pub mod event_log;
+pub mod multi_currency;
+pub mod payment;
+pub mod processor;
+#[cfg(feature = "server")]
+pub mod server;
+pub mod synthetic;
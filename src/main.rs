@@ -1,55 +1,1251 @@
-use processor::Processor;
-use std::convert::TryInto;
+use anyhow::Context;
+use fnv::FnvHashSet;
+use payengine::multi_currency::MultiCurrencyProcessor;
+use payengine::{payment, processor, processor::Processor};
+use std::collections::{BTreeMap, BTreeSet};
+use std::hash::{Hash, Hasher};
+use std::io::{Read, Write};
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
 use structopt::StructOpt;
 
 mod opts;
-mod payment;
-mod processor;
+
+enum Engine {
+    Single(Box<processor::InMemoryProcessor>),
+    Multi(MultiCurrencyProcessor),
+}
+
+impl Engine {
+    // `timestamped` payments go through the rate limit,
+    // so they need a valid timestamp
+    fn process(
+        &mut self,
+        payment_raw: &payment::RawInputRecord,
+        payment: payment::Payment,
+        timestamped: bool,
+    ) -> processor::Result<()> {
+        match self {
+            Engine::Single(processor) if timestamped => {
+                processor.process_at(payment, payment_raw.parse_timestamp()?)
+            }
+            Engine::Single(processor) => processor.process(payment),
+            Engine::Multi(processor) => processor.process(payment_raw.currency.as_deref(), payment),
+        }
+    }
+}
+
+/// Counts of processed records, reported every `every` records (if set)
+///
+/// Failed records also go to the `--dead-letter` file, if any, and are
+/// reported one by one unless `quiet`.
+#[derive(Default)]
+struct Progress {
+    every: Option<NonZeroUsize>,
+    quiet: bool,
+    records: usize,
+    errors: usize,
+    // records skipped for being after the `--as-of` cutoff
+    after_cutoff: usize,
+    // records skipped with `--skip-empty-type` and `--ignore-unknown-types`
+    empty_type: usize,
+    unknown_type: usize,
+    dead_letter: Option<csv::Writer<std::fs::File>>,
+}
+
+impl Progress {
+    fn record(&mut self, failed: bool) {
+        self.records += 1;
+        if failed {
+            self.errors += 1;
+        }
+        if let Some(every) = self.every {
+            if self.records % every.get() == 0 {
+                eprintln!("Processed {} records, {} errors", self.records, self.errors);
+            }
+        }
+    }
+
+    // `record` is `None` if not even the raw form could be read
+    fn dead_letter(
+        &mut self,
+        record: Option<&payment::RawInputRecord>,
+        error: &dyn std::fmt::Display,
+    ) -> anyhow::Result<()> {
+        let writer = match &mut self.dead_letter {
+            Some(writer) => writer,
+            None => return Ok(()),
+        };
+        writer
+            .write_record([
+                record.map_or("", |record| &record.r#type),
+                record.map_or("", |record| &record.client),
+                record.map_or("", |record| &record.tx),
+                record
+                    .and_then(|record| record.amount.as_deref())
+                    .unwrap_or_default(),
+                record
+                    .and_then(|record| record.currency.as_deref())
+                    .unwrap_or_default(),
+                record
+                    .and_then(|record| record.timestamp.as_deref())
+                    .unwrap_or_default(),
+                &error.to_string(),
+            ])
+            .context("failed to write to the dead letter file")
+    }
+}
+
+fn process_record(
+    engine: &mut Engine,
+    progress: &mut Progress,
+    path: &Path,
+    i: usize,
+    payment_raw: &payment::RawInputRecord,
+    payment: payment::Payment,
+    timestamped: bool,
+) -> anyhow::Result<()> {
+    let result = engine.process(payment_raw, payment, timestamped);
+    progress.record(result.is_err());
+    if let Err(e) = result {
+        // just report any errors - even ones that were explicitily listed
+        // as conditions we should tolerate;
+        // TODO: it remains unclear if we should
+        // ever have any conditions that should fail the whole execution
+        if !progress.quiet {
+            eprintln!(
+                "Error while processing record {} of {} {:?}: {}",
+                i,
+                path.display(),
+                payment_raw,
+                e
+            );
+        }
+        progress.dead_letter(Some(payment_raw), &e)?;
+    }
+    Ok(())
+}
+
+// parsed input records, along with the raw form used for error messages
+//
+// the outer error is fatal for the whole input, the inner one only for the record
+type Record = Result<(payment::RawInputRecord, payment::Payment), BadRecord>;
+
+// a record that failed to parse, with its raw form if that much worked
+struct BadRecord {
+    raw: Option<Box<payment::RawInputRecord>>,
+    error: anyhow::Error,
+}
+
+impl BadRecord {
+    // the type of the record, if that's what is wrong with it
+    fn invalid_type(&self) -> Option<&str> {
+        match self.error.downcast_ref() {
+            Some(payment::DeserializationError::InvalidType(r#type)) => Some(r#type),
+            _ => None,
+        }
+    }
+}
+
+impl From<csv::Error> for BadRecord {
+    fn from(e: csv::Error) -> Self {
+        BadRecord {
+            raw: None,
+            error: e.into(),
+        }
+    }
+}
+type Records = Box<dyn Iterator<Item = anyhow::Result<Record>>>;
+
+// `None` if the record is after the `--as-of` cutoff;
+// without a valid timestamp it's bad
+fn within_cutoff(
+    record: (payment::RawInputRecord, payment::Payment),
+    as_of: Option<payment::Timestamp>,
+) -> Result<Option<(payment::RawInputRecord, payment::Payment)>, BadRecord> {
+    let as_of = match as_of {
+        Some(as_of) => as_of,
+        None => return Ok(Some(record)),
+    };
+    match record.0.parse_timestamp() {
+        Ok(timestamp) if as_of < timestamp => Ok(None),
+        Ok(_) => Ok(Some(record)),
+        Err(e) => Err(BadRecord {
+            raw: Some(Box::new(record.0)),
+            error: e.into(),
+        }),
+    }
+}
+
+fn csv_records(
+    input: Box<dyn Read>,
+    thousands_sep: Option<char>,
+    decimal_places: Option<usize>,
+    rounding: Option<payment::RoundingMode>,
+) -> anyhow::Result<Records> {
+    if thousands_sep == Some('.') {
+        anyhow::bail!("`.` is the decimal separator, it can't separate thousands");
+    }
+
+    let mut reader = payment::input_reader(input)?;
+    let headers = reader.headers()?.clone();
+    Ok(Box::new(reader.into_byte_records().map(move |record| {
+        let record = match record {
+            Ok(record) => record,
+            // the reader can't go on after an io error
+            Err(e) if e.is_io_error() => return Err(e.into()),
+            Err(e) => return Ok(Err(e.into())),
+        };
+        let record = match csv::StringRecord::from_byte_record(record) {
+            Ok(record) => record,
+            Err(e) => {
+                let column = headers.get(e.utf8_error().field()).unwrap_or_default();
+                let error = anyhow::anyhow!("invalid UTF-8 in the {} column", column);
+                // a lossy copy, so the row still makes it to the dead letter file
+                let lossy = csv::StringRecord::from_byte_record_lossy(e.into_byte_record());
+                return Ok(Err(BadRecord {
+                    raw: payment::RawInputRecord::from_record(&lossy, &headers, thousands_sep)
+                        .ok()
+                        .map(Box::new),
+                    error,
+                }));
+            }
+        };
+        Ok(
+            payment::RawInputRecord::from_record(&record, &headers, thousands_sep)
+                .map_err(BadRecord::from)
+                .and_then(|payment_raw| {
+                    match payment_raw.clone().into_payment(decimal_places, rounding) {
+                        Ok(payment) => Ok((payment_raw, payment)),
+                        Err(e) => Err(BadRecord {
+                            raw: Some(Box::new(payment_raw)),
+                            error: anyhow::Error::from(e),
+                        }),
+                    }
+                }),
+        )
+    })))
+}
+
+#[cfg(feature = "bincode")]
+fn bincode_records(input: Box<dyn Read>) -> anyhow::Result<Records> {
+    // a broken record leaves the decoder at an unknown position,
+    // so there's no way to continue after it
+    Ok(Box::new(payment::bincode_payments(input).map(|payment| {
+        let payment = payment?;
+        Ok(Ok((payment.to_raw(), payment)))
+    })))
+}
+
+#[cfg(not(feature = "bincode"))]
+fn bincode_records(_input: Box<dyn Read>) -> anyhow::Result<Records> {
+    anyhow::bail!("bincode input is not supported, rebuild with the `bincode` feature")
+}
+
+#[cfg(feature = "json")]
+fn json_records(
+    input: Box<dyn Read>,
+    decimal_places: Option<usize>,
+    rounding: Option<payment::RoundingMode>,
+) -> anyhow::Result<Records> {
+    let payments = payment::json_payments(input, decimal_places, rounding)?;
+    Ok(Box::new(payments.into_iter().map(|payment| {
+        let payment = payment?;
+        Ok(Ok((payment.to_raw(), payment)))
+    })))
+}
+
+#[cfg(not(feature = "json"))]
+fn json_records(
+    _input: Box<dyn Read>,
+    _decimal_places: Option<usize>,
+    _rounding: Option<payment::RoundingMode>,
+) -> anyhow::Result<Records> {
+    anyhow::bail!("json input is not supported, rebuild with the `json` feature")
+}
+
+#[cfg(feature = "gzip")]
+fn gzip_decoder(file: std::fs::File) -> anyhow::Result<Box<dyn Read>> {
+    // handles files made of several concatenated gzip members too
+    Ok(Box::new(flate2::read::MultiGzDecoder::new(file)))
+}
+
+#[cfg(not(feature = "gzip"))]
+fn gzip_decoder(_file: std::fs::File) -> anyhow::Result<Box<dyn Read>> {
+    anyhow::bail!("gzip input is not supported, rebuild with the `gzip` feature")
+}
+
+/// Opening balance of a client, as a list of deposits
+#[cfg(feature = "json")]
+#[derive(serde::Deserialize)]
+struct SeedAccount {
+    client: payment::ClientID,
+    deposits: Vec<SeedDeposit>,
+}
+
+#[cfg(feature = "json")]
+#[derive(serde::Deserialize)]
+struct SeedDeposit {
+    tx: payment::TransactionID,
+    /// kept as written, to be parsed exactly
+    amount: Box<serde_json::value::RawValue>,
+}
+
+/// Apply the deposits of a `--seed-balance` file
+///
+/// They are regular deposits, so the input can dispute them later.
+/// Any failure is fatal, a partially seeded ledger is of no use.
+#[cfg(feature = "json")]
+fn seed_balances(processor: &mut processor::InMemoryProcessor, path: &Path) -> anyhow::Result<()> {
+    let file = std::fs::File::open(path)
+        .with_context(|| format!("failed to open seed file {}", path.display()))?;
+    let accounts: Vec<SeedAccount> = serde_json::from_reader(std::io::BufReader::new(file))
+        .with_context(|| format!("failed to parse seed file {}", path.display()))?;
+    for SeedAccount { client, deposits } in accounts {
+        for deposit in deposits {
+            let context = || format!("failed to seed deposit {} of client {}", deposit.tx, client);
+            // a number, or a string holding one
+            let amount = serde_json::from_str::<String>(deposit.amount.get())
+                .unwrap_or_else(|_| deposit.amount.get().to_string());
+            let payment = payment::Payment::Deposit(payment::Deposit {
+                client,
+                tx: deposit.tx,
+                amount: payment::Amount::from_decimal_str_with_places(
+                    &amount,
+                    processor.amount_decimal_places(),
+                )
+                .with_context(context)?,
+            });
+            processor.process(payment).with_context(context)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(feature = "json"))]
+fn seed_balances(
+    _processor: &mut processor::InMemoryProcessor,
+    _path: &Path,
+) -> anyhow::Result<()> {
+    anyhow::bail!("seed balance files are not supported, rebuild with the `json` feature")
+}
+
+/// Reader failing once more than `limit` bytes were read through it
+struct ByteLimit<R> {
+    inner: R,
+    limit: u64,
+    read: u64,
+}
+
+impl<R: Read> Read for ByteLimit<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.read += n as u64;
+        if self.limit < self.read {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                format!("input file is larger than {} bytes", self.limit),
+            ));
+        }
+        Ok(n)
+    }
+}
+
+// a record as far as `--dedup` is concerned, to detect re-delivered rows;
+// built from the payment, so eg. amounts `1.5` and `1.50` are the same
+#[derive(PartialEq, Eq, Hash)]
+struct SeenRecord {
+    r#type: std::mem::Discriminant<payment::Payment>,
+    client: payment::ClientID,
+    tx: payment::TransactionID,
+    amount: Option<u64>,
+    currency: Option<payment::Currency>,
+    // the instant if valid, so the same one in another offset is the same
+    timestamp: Option<Result<i128, String>>,
+}
+
+impl SeenRecord {
+    fn new(payment_raw: &payment::RawInputRecord, payment: &payment::Payment) -> Self {
+        Self {
+            r#type: std::mem::discriminant(payment),
+            client: payment.get_client_id(),
+            tx: payment.get_tx(),
+            amount: payment.get_amount().map(|amount| amount.0),
+            currency: payment_raw.currency.clone(),
+            timestamp: payment_raw.timestamp.as_ref().map(|timestamp| {
+                payment_raw
+                    .parse_timestamp()
+                    .map(|timestamp| timestamp.0)
+                    .map_err(|_| timestamp.clone())
+            }),
+        }
+    }
+}
+
+/// Process a single input file
+///
+/// With `--dedup`, rows already in `seen` are skipped;
+/// returns the number of such rows. `rows` counts the rows of all inputs,
+/// for `--max-rows`.
+fn process_input(
+    engine: &mut Engine,
+    progress: &mut Progress,
+    path: &Path,
+    opts: &opts::Opts,
+    seen: &mut FnvHashSet<SeenRecord>,
+    rows: &mut usize,
+) -> anyhow::Result<usize> {
+    let file = std::fs::File::open(path)
+        .map_err(|e| match e.kind() {
+            std::io::ErrorKind::NotFound => {
+                anyhow::anyhow!("file does not exist, is the path correct?")
+            }
+            _ => e.into(),
+        })
+        .with_context(|| format!("failed to open input file {}", path.display()))?;
+    let mut input = if opts.gzip || path.extension().is_some_and(|ext| ext == "gz") {
+        gzip_decoder(file)?
+    } else {
+        Box::new(file)
+    };
+    if let Some(limit) = opts.max_bytes {
+        input = Box::new(ByteLimit {
+            inner: input,
+            limit,
+            read: 0,
+        });
+    }
+    let records: Records = match opts.input_format {
+        opts::InputFormat::Csv => csv_records(
+            input,
+            opts.amount_thousands_sep,
+            opts.amount_decimal_places,
+            amount_rounding(opts),
+        )?,
+        opts::InputFormat::Bincode => bincode_records(input)?,
+        opts::InputFormat::Json => {
+            json_records(input, opts.amount_decimal_places, amount_rounding(opts))?
+        }
+    };
+
+    // in two-pass mode disputes, resolves and chargebacks
+    // are applied only after all deposits and withdrawals
+    let mut deferred = vec![];
+    let mut skipped = 0;
+    for (i, record) in records.enumerate() {
+        *rows += 1;
+        if let Some(max_rows) = opts.max_rows {
+            if max_rows < *rows {
+                anyhow::bail!("input has more than {} rows", max_rows);
+            }
+        }
+        let (payment_raw, payment) =
+            match record?.and_then(|record| within_cutoff(record, opts.as_of)) {
+                Ok(Some(record)) => record,
+                Ok(None) => {
+                    progress.after_cutoff += 1;
+                    continue;
+                }
+                // eg. separator lines of some feeds
+                Err(bad) if opts.skip_empty_type && bad.invalid_type() == Some("") => {
+                    progress.empty_type += 1;
+                    continue;
+                }
+                Err(bad)
+                    if opts.ignore_unknown_types
+                        && bad.invalid_type().is_some_and(|r#type| !r#type.is_empty()) =>
+                {
+                    progress.unknown_type += 1;
+                    continue;
+                }
+                Err(BadRecord { raw, error }) => {
+                    // bad records are reported, same as the rejected ones
+                    progress.record(true);
+                    if !progress.quiet {
+                        eprintln!(
+                            "Error while parsing record {} of {}: {}",
+                            i,
+                            path.display(),
+                            error
+                        );
+                    }
+                    progress.dead_letter(raw.as_deref(), &error)?;
+                    continue;
+                }
+            };
+        if opts.dedup && !seen.insert(SeenRecord::new(&payment_raw, &payment)) {
+            skipped += 1;
+            continue;
+        }
+        match payment {
+            payment::Payment::Dispute(_)
+            | payment::Payment::Resolve(_)
+            | payment::Payment::Chargeback(_)
+                if opts.two_pass =>
+            {
+                deferred.push((i, payment_raw, payment))
+            }
+            _ => process_record(
+                engine,
+                progress,
+                path,
+                i,
+                &payment_raw,
+                payment,
+                opts.rate_limit.is_some(),
+            )?,
+        }
+    }
+
+    for (i, payment_raw, payment) in two_pass_order(deferred) {
+        process_record(
+            engine,
+            progress,
+            path,
+            i,
+            &payment_raw,
+            payment,
+            opts.rate_limit.is_some(),
+        )?;
+    }
+
+    Ok(skipped)
+}
+
+/// Order of the `--two-pass` disputes, resolves and chargebacks
+///
+/// A resolve or chargeback that comes before the dispute it settles is
+/// moved right after the next dispute of the same transaction (of the
+/// same client); everything else keeps the file order, so eg. a
+/// transaction disputed again after a resolve is settled in order.
+fn two_pass_order<R>(
+    deferred: Vec<(usize, R, payment::Payment)>,
+) -> Vec<(usize, R, payment::Payment)> {
+    use fnv::FnvHashMap;
+    use std::collections::VecDeque;
+
+    // position of each payment: its own index, or that of
+    // the dispute it was moved after
+    let mut positions = Vec::with_capacity(deferred.len());
+    let mut open_disputes: FnvHashMap<_, usize> = FnvHashMap::default();
+    let mut early: FnvHashMap<_, VecDeque<usize>> = FnvHashMap::default();
+    for (n, (i, _, payment)) in deferred.iter().enumerate() {
+        let key = (payment.get_client_id(), payment.get_tx());
+        positions.push((*i, 0));
+        if let payment::Payment::Dispute(_) = payment {
+            match early.get_mut(&key).and_then(VecDeque::pop_front) {
+                Some(settling) => positions[settling] = (*i, 1),
+                None => *open_disputes.entry(key).or_default() += 1,
+            }
+        } else {
+            match open_disputes.get_mut(&key) {
+                Some(open) if 0 < *open => *open -= 1,
+                _ => early.entry(key).or_default().push_back(n),
+            }
+        }
+    }
+
+    let mut ordered: Vec<_> = positions.into_iter().zip(deferred).collect();
+    ordered.sort_by_key(|(position, _)| *position);
+    ordered.into_iter().map(|(_, payment)| payment).collect()
+}
+
+/// Print the funds flow of every account, ordered by (currency and) client
+fn explain_balances(engine: &Engine) {
+    let ledgers = match engine {
+        Engine::Single(processor) => vec![(None, &**processor)],
+        Engine::Multi(processor) => processor
+            .ledgers()
+            .map(|(currency, ledger)| (Some(currency.as_str()), ledger))
+            .collect(),
+    };
+    for (currency, ledger) in ledgers {
+        let places = ledger.amount_decimal_places();
+        for (client, account) in ledger.sorted_accounts(processor::SortBy::Client) {
+            let flow = match ledger.account_funds_flow(*client) {
+                Some(flow) => flow,
+                None => continue,
+            };
+            eprintln!(
+                "Client {}{}: deposited {}, withdrawn {}, charged back {}, reversed {}, \
+                 adjusted {} => total {}, held {}, available {}",
+                client,
+                currency.map(|c| format!(" ({})", c)).unwrap_or_default(),
+                flow.deposited.to_decimal_string(places),
+                flow.withdrawn.to_decimal_string(places),
+                flow.charged_back.to_decimal_string(places),
+                flow.reversed.to_decimal_string(places),
+                flow.adjusted.to_decimal_string(places),
+                account.total_funds.to_decimal_string(places),
+                account.held_funds.to_decimal_string(places),
+                account.available_funds().to_decimal_string(places),
+            );
+        }
+    }
+}
+
+/// Row of the `--audit` file
+#[derive(serde::Serialize)]
+struct AuditRecord<'a> {
+    client: payment::ClientID,
+    tx: payment::TransactionID,
+    r#type: processor::TransactionKind,
+    amount: String,
+    status: processor::TransactionStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    currency: Option<&'a str>,
+}
+
+/// Write the history of all accounts, ordered by (currency and) client
+fn write_audit(engine: &Engine, path: &Path) -> anyhow::Result<()> {
+    let ledgers = match engine {
+        Engine::Single(processor) => vec![(None, &**processor)],
+        Engine::Multi(processor) => processor
+            .ledgers()
+            .map(|(currency, ledger)| (Some(currency.as_str()), ledger))
+            .collect(),
+    };
+    let mut writer = csv::Writer::from_path(path)?;
+    for (currency, ledger) in ledgers {
+        let places = ledger.amount_decimal_places();
+        for (client, _) in ledger.sorted_accounts(processor::SortBy::Client) {
+            let client = *client;
+            let history = ledger.get_history_ordered(client).unwrap_or_default();
+            for (tx, past_tx) in history {
+                writer.serialize(AuditRecord {
+                    client,
+                    tx,
+                    r#type: past_tx.kind,
+                    amount: past_tx.amount.to_decimal_string(places),
+                    status: past_tx.status,
+                    currency,
+                })?;
+            }
+        }
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Hashes of the input files processed by earlier runs, see `--manifest`
+///
+/// One `<hash> <path>` line per file; only the hash, of the whole file
+/// contents, matters.
+struct Manifest {
+    path: PathBuf,
+    hashes: FnvHashSet<u64>,
+    // processed in this run, to be added
+    new: Vec<(u64, PathBuf)>,
+}
+
+impl Manifest {
+    fn load(path: &Path) -> anyhow::Result<Self> {
+        let contents = match std::fs::read_to_string(path) {
+            Ok(contents) => contents,
+            // the first run creates it
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e.into()),
+        };
+        let hashes = contents
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| {
+                let hash = line.split_whitespace().next().unwrap_or_default();
+                u64::from_str_radix(hash, 16)
+                    .with_context(|| format!("invalid manifest line: {}", line))
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Manifest {
+            path: path.to_path_buf(),
+            hashes,
+            new: vec![],
+        })
+    }
+
+    /// Should `input` be processed; if so, it's noted to be added
+    fn check(&mut self, input: &Path) -> anyhow::Result<bool> {
+        let hash = file_hash(input)
+            .with_context(|| format!("failed to hash input file {}", input.display()))?;
+        if !self.hashes.insert(hash) {
+            return Ok(false);
+        }
+        self.new.push((hash, input.to_path_buf()));
+        Ok(true)
+    }
+
+    /// Append the files processed in this run
+    fn save(&self) -> anyhow::Result<()> {
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        for (hash, input) in &self.new {
+            writeln!(file, "{:016x} {}", hash, input.display())?;
+        }
+        Ok(())
+    }
+}
+
+// FNV over the raw contents (still compressed, for `.gz` files)
+fn file_hash(path: &Path) -> std::io::Result<u64> {
+    let mut file = std::fs::File::open(path)?;
+    let mut hasher = fnv::FnvHasher::default();
+    let mut buf = [0; 64 * 1024];
+    loop {
+        match file.read(&mut buf)? {
+            0 => return Ok(hasher.finish()),
+            n => hasher.write(&buf[..n]),
+        }
+    }
+}
+
+fn amount_decimal_places(opts: &opts::Opts) -> usize {
+    opts.amount_decimal_places
+        .unwrap_or(payment::AMOUNT_DECIMAL_PLACES)
+}
+
+// `None` to reject amounts with excess decimal places
+fn amount_rounding(opts: &opts::Opts) -> Option<payment::RoundingMode> {
+    if opts.strict_precision {
+        None
+    } else {
+        Some(opts.round_amounts.unwrap_or(payment::RoundingMode::HalfUp))
+    }
+}
+
+// fresh engine, with any seed balances applied
+fn new_engine(opts: &opts::Opts) -> anyhow::Result<Engine> {
+    let max_amount = opts
+        .max_amount
+        .as_deref()
+        .map(|amount| {
+            payment::Amount::from_decimal_str_with_places(amount, amount_decimal_places(opts))
+        })
+        .transpose()
+        .context("invalid --max-amount")?;
+    let config = processor::Config {
+        max_clients: opts.max_clients,
+        deny_withdrawal_overdraft_after_dispute: opts.deny_withdrawal_overdraft_after_dispute,
+        max_amount,
+        amount_decimal_places: opts.amount_decimal_places,
+        amount_rounding: amount_rounding(opts),
+        rate_limit: opts.rate_limit.map(|max| processor::RateLimit {
+            max,
+            window: std::time::Duration::from_secs(opts.rate_window),
+        }),
+        ..Default::default()
+    };
+    let mut engine = if opts.multi_currency {
+        Engine::Multi(MultiCurrencyProcessor::new(config))
+    } else {
+        let processor = processor::InMemoryProcessor::new(config);
+        Engine::Single(Box::new(match opts.expected_clients {
+            Some(clients) => processor.with_capacity(clients),
+            None => processor,
+        }))
+    };
+
+    if let Some(path) = &opts.seed_balance {
+        match &mut engine {
+            Engine::Single(processor) => seed_balances(processor, path)?,
+            Engine::Multi(_) => unreachable!("--seed-balance conflicts with --multi-currency"),
+        }
+    }
+    Ok(engine)
+}
 
 fn run() -> anyhow::Result<()> {
     let opts = opts::Opts::from_args();
+    match &opts.cmd {
+        Some(opts::Command::Diff { a, b }) => {
+            return diff_outputs(a, b, amount_decimal_places(&opts))
+        }
+        Some(opts::Command::Verify { output }) => {
+            return verify_output(output, amount_decimal_places(&opts))
+        }
+        _ => {}
+    }
+    if opts.input_cvs.is_empty() {
+        anyhow::bail!("no input files given");
+    }
+    if opts.per_file && opts.cmd.is_some() {
+        anyhow::bail!("--per-file writes all the accounts, it can't be used with a query");
+    }
+
+    let mut engine = new_engine(&opts)?;
+    let mut seen = FnvHashSet::default();
+    let mut skipped = 0;
+    let mut rows = 0;
+    let dead_letter = match &opts.dead_letter {
+        Some(path) => {
+            let mut writer = csv::Writer::from_path(path)
+                .with_context(|| format!("failed to create dead letter file {}", path.display()))?;
+            writer.write_record([
+                "type",
+                "client",
+                "tx",
+                "amount",
+                "currency",
+                "timestamp",
+                "error",
+            ])?;
+            Some(writer)
+        }
+        None => None,
+    };
+    let mut progress = Progress {
+        every: opts.progress,
+        quiet: opts.quiet,
+        dead_letter,
+        ..Default::default()
+    };
+    let mut manifest = match &opts.manifest {
+        Some(path) => Some(
+            Manifest::load(path)
+                .with_context(|| format!("failed to read manifest {}", path.display()))?,
+        ),
+        None => None,
+    };
+    for path in &opts.input_cvs {
+        if let Some(manifest) = &mut manifest {
+            if !manifest.check(path)? {
+                eprintln!("Skipping {}, it was processed already", path.display());
+                continue;
+            }
+        }
+        skipped += process_input(
+            &mut engine,
+            &mut progress,
+            path,
+            &opts,
+            &mut seen,
+            &mut rows,
+        )?;
+        if opts.per_file {
+            write_per_file_output(&engine, path, &opts)?;
+            // each file is a ledger of its own; clearing keeps the
+            // allocated memory, but a seeded one has to start over
+            match &mut engine {
+                Engine::Single(processor) if opts.seed_balance.is_none() => processor.clear(),
+                _ => engine = new_engine(&opts)?,
+            }
+            seen.clear();
+        }
+    }
+    if opts.dedup {
+        eprintln!("Skipped {} duplicate records", skipped);
+    }
+    if opts.as_of.is_some() {
+        eprintln!("Skipped {} records after the cutoff", progress.after_cutoff);
+    }
+    if opts.skip_empty_type {
+        eprintln!("Skipped {} records without a type", progress.empty_type);
+    }
+    if opts.ignore_unknown_types {
+        eprintln!("Skipped {} records of unknown types", progress.unknown_type);
+    }
+    if opts.quiet {
+        eprintln!(
+            "Processed {} records, {} errors",
+            progress.records, progress.errors
+        );
+    }
+    if let Some(writer) = &mut progress.dead_letter {
+        writer
+            .flush()
+            .context("failed to write to the dead letter file")?;
+    }
+    if opts.explain {
+        explain_balances(&engine);
+    }
+
+    let result = match &engine {
+        // written file by file already
+        _ if opts.per_file => Ok(()),
+        Engine::Single(processor) => write_output(processor, &opts),
+        Engine::Multi(processor) => write_multi_currency_output(processor, &opts),
+    };
+    match result {
+        // the reader went away (eg. piped to `head`), that's not an error
+        Err(e) if is_broken_pipe(&e) => {}
+        result => result?,
+    }
+    // only once the output is there, so a failed run can be repeated
+    if let Some(manifest) = &manifest {
+        manifest
+            .save()
+            .with_context(|| format!("failed to write manifest {}", manifest.path.display()))?;
+    }
+
+    if let Some(path) = &opts.audit {
+        write_audit(&engine, path)
+            .with_context(|| format!("failed to write audit file {}", path.display()))?;
+    }
 
-    let mut processor = processor::InMemoryProcessor::default();
-
-    // Note: Note that the CSV reader is buffered automatically,
-    // so no need for `BufReader`.
-    let mut reader = csv::ReaderBuilder::new()
-        .trim(csv::Trim::All)
-        .from_reader(std::fs::File::open(opts.input_cvs)?);
-
-    for (i, payment) in reader.deserialize().enumerate() {
-        let payment_raw: payment::RawInputRecord = payment?;
-        let payment: payment::Payment = payment_raw.clone().try_into()?;
-        if let Err(e) = processor.process(payment) {
-            // just report any errors - even ones that were explicitily listed
-            // as conditions we should tolerate;
-            // TODO: it remains unclear if we should
-            // ever have any conditions that should fail the whole execution
+    if opts.fail_on_locked {
+        let locked = match &engine {
+            Engine::Single(processor) => processor
+                .get_all_accounts()
+                .filter(|(_, account)| account.locked())
+                .count(),
+            Engine::Multi(processor) => processor
+                .accounts()
+                .into_iter()
+                .filter(|(_, _, account)| account.locked())
+                .count(),
+        };
+        if locked != 0 {
+            anyhow::bail!("{} account(s) ended up locked", locked);
+        }
+    }
+
+    if opts.reconcile {
+        let reconciliations = match &engine {
+            Engine::Single(processor) => vec![(None, processor.reconcile())],
+            Engine::Multi(processor) => processor
+                .reconcile()
+                .into_iter()
+                .map(|(currency, reconciliation)| (Some(currency), reconciliation))
+                .collect(),
+        };
+        let places = amount_decimal_places(&opts);
+        let mut discrepancies = 0;
+        for (currency, reconciliation) in reconciliations {
+            let discrepancy = reconciliation.discrepancy();
             eprintln!(
-                "Error while processing record {} {:?}: {}",
-                i, payment_raw, e
+                "Reconciliation{}: expected {}, actual {}, discrepancy {}",
+                currency.map(|c| format!(" ({})", c)).unwrap_or_default(),
+                reconciliation.expected.to_decimal_string(places),
+                reconciliation.actual.to_decimal_string(places),
+                discrepancy.to_decimal_string(places),
             );
+            if discrepancy.0 != 0 {
+                discrepancies += 1;
+            }
+        }
+        if discrepancies != 0 {
+            anyhow::bail!("funds don't reconcile in {} ledger(s)", discrepancies);
         }
     }
 
-    let mut writer = csv::Writer::from_writer(std::io::stdout());
-    for (client_id, account) in processor.get_all_accounts() {
-        writer.serialize(payment::RawOutputRecord {
-            client: *client_id,
-            available: account.available_funds().to_f64(),
-            held: account.held_funds.to_f64(),
-            total: account.total_funds.to_f64(),
-            locked: account.locked,
-        })?;
+    Ok(())
+}
+
+fn is_broken_pipe(err: &anyhow::Error) -> bool {
+    err.chain().any(|e| {
+        let io_error = match e.downcast_ref::<csv::Error>() {
+            Some(e) => match e.kind() {
+                csv::ErrorKind::Io(e) => Some(e),
+                _ => None,
+            },
+            None => e.downcast_ref::<std::io::Error>(),
+        };
+        matches!(io_error, Some(e) if e.kind() == std::io::ErrorKind::BrokenPipe)
+    })
+}
+
+/// Write the accounts as a table with aligned columns, for people to read
+fn write_preview(
+    processor: &processor::InMemoryProcessor,
+    sort_by: processor::SortBy,
+    mut w: impl std::io::Write,
+) -> std::io::Result<()> {
+    let places = processor.amount_decimal_places();
+    let mut rows = vec![["client", "available", "held", "total", "locked"].map(String::from)];
+    for (client_id, account) in processor.sorted_accounts(sort_by) {
+        rows.push([
+            client_id.to_string(),
+            account.available_funds().to_decimal_string(places),
+            account.held_funds.to_decimal_string(places),
+            account.total_funds.to_decimal_string(places),
+            account.locked().to_string(),
+        ]);
+    }
+
+    let mut widths = [0; 5];
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+    for row in &rows {
+        let cells: Vec<_> = row
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{:>width$}", cell, width = width))
+            .collect();
+        writeln!(w, "{}", cells.join(" | "))?;
+    }
+    Ok(())
+}
+
+fn output_options(opts: &opts::Opts) -> processor::OutputOptions {
+    processor::OutputOptions {
+        sort_by: opts.sort_by,
+        with_counts: opts.with_counts,
+        with_lock_reason: opts.with_lock_reason,
+        integer_amounts: opts.integer_amounts,
+        skip_zero: opts.skip_zero,
+    }
+}
+
+/// Write the accounts of a single input file next to it, as `<name>.out.csv`
+fn write_per_file_output(engine: &Engine, input: &Path, opts: &opts::Opts) -> anyhow::Result<()> {
+    let path = input.with_extension("out.csv");
+    let file = std::fs::File::create(&path)
+        .with_context(|| format!("failed to create output file {}", path.display()))?;
+    match engine {
+        Engine::Single(processor) => {
+            write_stderr_reports(processor, opts)?;
+            processor.write_accounts(file, &output_options(opts))
+        }
+        Engine::Multi(processor) => processor.write_accounts(file, &output_options(opts)),
+    }
+    .with_context(|| format!("failed to write output file {}", path.display()))
+}
+
+/// Print the `--preview` table and the `--alerts` to stderr
+fn write_stderr_reports(
+    processor: &processor::InMemoryProcessor,
+    opts: &opts::Opts,
+) -> anyhow::Result<()> {
+    if opts.preview {
+        write_preview(processor, opts.sort_by, std::io::stderr())?;
+    }
+    if let Some(percent) = opts.alerts {
+        for client in processor.held_alerts(percent) {
+            eprintln!(
+                "Alert: client {} has over {}% of funds held",
+                client, percent
+            );
+        }
+    }
+    Ok(())
+}
+
+fn write_output(processor: &processor::InMemoryProcessor, opts: &opts::Opts) -> anyhow::Result<()> {
+    write_stderr_reports(processor, opts)?;
+    match opts.cmd {
+        None => processor.write_accounts(std::io::stdout(), &output_options(opts))?,
+        Some(opts::Command::Query { client }) => {
+            let account = processor
+                .get_account(client)
+                .ok_or_else(|| anyhow::anyhow!("client {} not found", client))?;
+            let mut record = account.to_output_record(client);
+            if opts.with_counts {
+                record.transactions = processor.get_transaction_count(client);
+            }
+            if opts.with_lock_reason {
+                record.set_lock_reason(account.lock_reason());
+            }
+            let mut writer = csv::Writer::from_writer(std::io::stdout());
+            if opts.integer_amounts {
+                writer.serialize(account.with_unit_amounts(record))?;
+            } else {
+                writer.serialize(record)?;
+            }
+            writer.flush()?;
+        }
+        Some(opts::Command::Diff { .. } | opts::Command::Verify { .. }) => {
+            unreachable!("diff and verify don't process any input")
+        }
+    }
+
+    Ok(())
+}
+
+fn write_multi_currency_output(
+    processor: &MultiCurrencyProcessor,
+    opts: &opts::Opts,
+) -> anyhow::Result<()> {
+    match opts.cmd {
+        None => processor.write_accounts(std::io::stdout(), &output_options(opts))?,
+        Some(opts::Command::Query { client }) => {
+            let mut writer = csv::Writer::from_writer(std::io::stdout());
+            let mut found = false;
+            for (_, currency, account) in processor
+                .accounts()
+                .into_iter()
+                .filter(|(client_id, _, _)| *client_id == client)
+            {
+                let mut record = account.to_output_record(client);
+                record.currency = Some(currency.clone());
+                if opts.with_counts {
+                    record.transactions = processor.get_transaction_count(client, currency);
+                }
+                writer.serialize(record)?;
+                found = true;
+            }
+            if !found {
+                anyhow::bail!("client {} not found", client);
+            }
+            writer.flush()?;
+        }
+        Some(opts::Command::Diff { .. } | opts::Command::Verify { .. }) => {
+            unreachable!("diff and verify don't process any input")
+        }
     }
-    writer.flush()?;
 
     Ok(())
 }
 
+/// Balances of an output file, by client
+fn read_output(
+    path: &Path,
+) -> anyhow::Result<BTreeMap<payment::ClientID, payment::RawOutputRecord>> {
+    let mut reader = csv::Reader::from_path(path)
+        .with_context(|| format!("failed to open output file {}", path.display()))?;
+    let mut records = BTreeMap::new();
+    for record in reader.deserialize() {
+        let record: payment::RawOutputRecord = record?;
+        let client = record.client;
+        if records.insert(client, record).is_some() {
+            anyhow::bail!(
+                "client {} appears more than once in {}",
+                client,
+                path.display()
+            );
+        }
+    }
+    Ok(records)
+}
+
+/// Print every difference between the balances of two output files
+///
+/// Amounts are compared (and shown) exactly, rounded to the given
+/// decimal places.
+fn diff_outputs(a: &Path, b: &Path, decimal_places: usize) -> anyhow::Result<()> {
+    let (a, b) = (read_output(a)?, read_output(b)?);
+    let clients: BTreeSet<_> = a.keys().chain(b.keys()).copied().collect();
+    let amount = |value: &str| {
+        payment::Amount::from_decimal_str_rounded(
+            value,
+            decimal_places,
+            payment::RoundingMode::HalfUp,
+        )
+    };
+
+    let mut differing = 0;
+    for client in clients {
+        let diff = |field, left: String, right: String| processor::ProcessorDiff {
+            client: client.to_string(),
+            field,
+            left,
+            right,
+        };
+        let diffs = match (a.get(&client), b.get(&client)) {
+            (Some(left), Some(right)) => {
+                let mut diffs = vec![];
+                let amounts = [
+                    ("available", &left.available, &right.available),
+                    ("held", &left.held, &right.held),
+                    ("total", &left.total, &right.total),
+                ];
+                for (field, left, right) in amounts.iter() {
+                    let (left, right) = (amount(left)?, amount(right)?);
+                    if left != right {
+                        diffs.push(diff(
+                            field,
+                            left.to_decimal_string(decimal_places),
+                            right.to_decimal_string(decimal_places),
+                        ));
+                    }
+                }
+                if left.locked != right.locked {
+                    diffs.push(diff(
+                        "locked",
+                        left.locked.to_string(),
+                        right.locked.to_string(),
+                    ));
+                }
+                diffs
+            }
+            (left, _) => {
+                let presence = |present: bool| if present { "present" } else { "missing" };
+                vec![diff(
+                    "account",
+                    presence(left.is_some()).to_string(),
+                    presence(left.is_none()).to_string(),
+                )]
+            }
+        };
+        if !diffs.is_empty() {
+            differing += 1;
+        }
+        for diff in diffs {
+            println!("{}", diff);
+        }
+    }
+
+    if differing != 0 {
+        anyhow::bail!("{} client(s) differ", differing);
+    }
+    Ok(())
+}
+
+// exact value of an output amount, which may be negative
+fn output_amount(value: &str, decimal_places: usize) -> anyhow::Result<payment::SignedAmount> {
+    let negative = value.starts_with('-');
+    let units = payment::Amount::from_decimal_str_rounded(
+        value.trim_start_matches('-'),
+        decimal_places,
+        payment::RoundingMode::HalfUp,
+    )?;
+    let units = i128::from(units.0);
+    Ok(payment::SignedAmount(if negative { -units } else { units }))
+}
+
+/// Print every row of an output file with inconsistent balances
+///
+/// Each row is checked on its own: funds can't be negative and the
+/// available ones must be the total minus the held ones, exactly.
+fn verify_output(path: &Path, decimal_places: usize) -> anyhow::Result<()> {
+    let mut reader = csv::Reader::from_path(path)
+        .with_context(|| format!("failed to open output file {}", path.display()))?;
+    let mut inconsistent = 0;
+    for record in reader.deserialize() {
+        let record: payment::RawOutputRecord = record?;
+        let account = match &record.currency {
+            Some(currency) => format!("client {} ({})", record.client, currency),
+            None => format!("client {}", record.client),
+        };
+        let available = output_amount(&record.available, decimal_places)?;
+        let held = output_amount(&record.held, decimal_places)?;
+        let total = output_amount(&record.total, decimal_places)?;
+
+        let show = |amount: payment::SignedAmount| amount.to_decimal_string(decimal_places);
+        let mut problems = vec![];
+        if held.0 < 0 {
+            problems.push(format!("held {} is negative", show(held)));
+        }
+        if total.0 < 0 {
+            problems.push(format!("total {} is negative", show(total)));
+        }
+        if available.0 != total.0 - held.0 {
+            problems.push(format!(
+                "available {} is not total {} minus held {}",
+                show(available),
+                show(total),
+                show(held)
+            ));
+        }
+        if !problems.is_empty() {
+            inconsistent += 1;
+        }
+        for problem in problems {
+            println!("{}: {}", account, problem);
+        }
+    }
+
+    if inconsistent != 0 {
+        anyhow::bail!("{} row(s) are inconsistent", inconsistent);
+    }
+    Ok(())
+}
+
 fn main() {
     if let Err(err) = run() {
-        println!("terminated due to error: {}", err);
+        println!("terminated due to error: {:#}", err);
         std::process::exit(1);
     }
 }
@@ -0,0 +1,342 @@
+//! Processing of payments in multiple currencies
+//!
+//! Every currency is a separate ledger (an `InMemoryProcessor`), so each
+//! client has an independent account per currency. Deposits and withdrawals
+//! go to the ledger of their own currency; disputes, resolves, chargebacks
+//! and reversals go to the ledger of the transaction they refer to.
+//!
+//! Locks are the exception: a chargeback in any currency locks the client
+//! in all of them.
+use crate::payment::{ClientID, Currency, Payment, TransactionID, AMOUNT_DECIMAL_PLACES};
+use crate::processor::{
+    AccountState, Config, Error, FreezeReason, InMemoryProcessor, OutputOptions, Processor,
+    Reconciliation, Result, SortBy,
+};
+use fnv::{FnvHashMap, FnvHashSet};
+use std::collections::BTreeMap;
+
+pub struct MultiCurrencyProcessor {
+    config: Config,
+    ledgers: BTreeMap<Currency, InMemoryProcessor>,
+    // currency of every accepted deposit and withdrawal
+    tx_currencies: FnvHashMap<(ClientID, TransactionID), Currency>,
+    // clients locked by a chargeback in any currency
+    locked: FnvHashSet<ClientID>,
+}
+
+impl MultiCurrencyProcessor {
+    /// `config` applies to each currency separately (eg. `max_clients`)
+    pub fn new(config: Config) -> Self {
+        Self {
+            config,
+            ledgers: Default::default(),
+            tx_currencies: Default::default(),
+            locked: Default::default(),
+        }
+    }
+
+    /// Process a payment
+    ///
+    /// `currency` is required for deposits and withdrawals only; other
+    /// types always use the currency of the referenced transaction.
+    pub fn process(&mut self, currency: Option<&str>, payment: Payment) -> Result<()> {
+        let client_id = payment.get_client_id();
+        match &payment {
+            Payment::Deposit(_) | Payment::Withdrawal(_) => {
+                let tx = payment.get_tx();
+                let currency = currency.ok_or(Error::MissingCurrency {
+                    client: client_id.into(),
+                    tx: tx.into(),
+                })?;
+                // also covers currencies the client has no account in yet
+                if self.locked.contains(&client_id) {
+                    return Err(Error::AccountLocked {
+                        client: client_id.into(),
+                    });
+                }
+                // transaction ids are unique across all currencies
+                if self.tx_currencies.contains_key(&(client_id, tx)) {
+                    return Err(Error::TransactionAlreadyExists {
+                        client: client_id.into(),
+                        tx: tx.into(),
+                    });
+                }
+
+                let config = &self.config;
+                self.ledgers
+                    .entry(currency.to_string())
+                    .or_insert_with(|| InMemoryProcessor::new(config.clone()))
+                    .process(payment.clone())?;
+                self.tx_currencies
+                    .insert((client_id, tx), currency.to_string());
+                Ok(())
+            }
+            Payment::Dispute(details)
+            | Payment::Resolve(details)
+            | Payment::Chargeback(details)
+            | Payment::Reversal(details) => {
+                let currency = self
+                    .tx_currencies
+                    .get(&(client_id, details.tx))
+                    .ok_or_else(|| Error::TransactionNotFound {
+                        client: client_id.into(),
+                        tx: details.tx.into(),
+                    })?;
+                let ledger = self
+                    .ledgers
+                    .get_mut(currency)
+                    .expect("ledger of a known transaction exists");
+                ledger.process(payment)?;
+                let locked = ledger
+                    .get_account(client_id)
+                    .and_then(AccountState::lock_reason)
+                    == Some(FreezeReason::Chargeback);
+                if locked && self.locked.insert(client_id) {
+                    self.lock_everywhere(client_id);
+                }
+                Ok(())
+            }
+        }
+    }
+
+    // freeze the accounts of a charged back client in every currency
+    fn lock_everywhere(&mut self, client_id: ClientID) {
+        for ledger in self.ledgers.values_mut() {
+            if ledger.get_account(client_id).is_some() {
+                ledger
+                    .freeze_account(client_id, FreezeReason::Chargeback)
+                    .expect("account exists");
+            }
+        }
+    }
+
+    pub fn get_account(&self, client_id: ClientID, currency: &str) -> Option<&AccountState> {
+        self.ledgers.get(currency)?.get_account(client_id)
+    }
+
+    /// Number of deposits and withdrawals of a client in a currency
+    pub fn get_transaction_count(&self, client_id: ClientID, currency: &str) -> Option<usize> {
+        self.ledgers.get(currency)?.get_transaction_count(client_id)
+    }
+
+    /// All accounts, ordered by client id and then currency
+    pub fn accounts(&self) -> Vec<(ClientID, &Currency, &AccountState)> {
+        let mut accounts: Vec<_> = self
+            .ledgers
+            .iter()
+            .flat_map(|(currency, ledger)| {
+                ledger
+                    .get_all_accounts()
+                    .map(move |(client_id, account)| (*client_id, currency, account))
+            })
+            .collect();
+        accounts.sort_by_key(|(client_id, currency, _)| (*client_id, *currency));
+        accounts
+    }
+
+    /// Ledgers of all currencies, ordered by currency
+    pub fn ledgers(&self) -> impl Iterator<Item = (&Currency, &InMemoryProcessor)> {
+        self.ledgers.iter()
+    }
+
+    /// Reconciliation of every currency separately
+    pub fn reconcile(&self) -> Vec<(&Currency, Reconciliation)> {
+        self.ledgers
+            .iter()
+            .map(|(currency, ledger)| (currency, ledger.reconcile()))
+            .collect()
+    }
+
+    /// Write all accounts as CSV output records, with the `currency` column
+    ///
+    /// Sorting by an amount compares the amounts of different currencies
+    /// as they are.
+    pub fn write_accounts<W: std::io::Write>(
+        &self,
+        w: W,
+        options: &OutputOptions,
+    ) -> csv::Result<()> {
+        let decimal_places = self
+            .config
+            .amount_decimal_places
+            .unwrap_or(AMOUNT_DECIMAL_PLACES);
+        let mut accounts = self.accounts();
+        // stable, so ties stay ordered by client id and currency
+        match options.sort_by {
+            SortBy::Client => {}
+            SortBy::Total => {
+                accounts.sort_by_key(|(_, _, account)| std::cmp::Reverse(account.total_funds))
+            }
+            SortBy::Available => {
+                accounts.sort_by_key(|(_, _, account)| std::cmp::Reverse(account.available_funds()))
+            }
+        }
+        let mut writer = csv::Writer::from_writer(w);
+        for (client_id, currency, account) in accounts {
+            if options.skip_zero && account.is_empty() {
+                continue;
+            }
+            let mut record = account.to_output_record_with_places(client_id, decimal_places);
+            record.currency = Some(currency.clone());
+            if options.with_counts {
+                record.transactions = self.get_transaction_count(client_id, currency);
+            }
+            if options.with_lock_reason {
+                record.set_lock_reason(account.lock_reason());
+            }
+            if options.integer_amounts {
+                writer.serialize(account.with_unit_amounts(record))?;
+            } else {
+                writer.serialize(record)?;
+            }
+        }
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+#[test]
+fn separate_balances_per_currency() -> anyhow::Result<()> {
+    use crate::payment::{Amount, Deposit, Dispute, Withdrawal};
+
+    let mut processor = MultiCurrencyProcessor::new(Config::default());
+    processor.process(
+        Some("USD"),
+        Payment::Deposit(Deposit {
+            client: 1,
+            tx: 1,
+            amount: Amount(10_0000),
+        }),
+    )?;
+    processor.process(
+        Some("EUR"),
+        Payment::Deposit(Deposit {
+            client: 1,
+            tx: 2,
+            amount: Amount(3_0000),
+        }),
+    )?;
+    assert_eq!(
+        processor.process(
+            Some("EUR"),
+            Payment::Withdrawal(Withdrawal {
+                client: 1,
+                tx: 3,
+                amount: Amount(5_0000),
+            }),
+        ),
+        Err(Error::Underflow { client: 1 })
+    );
+    assert_eq!(
+        processor.process(
+            Some("EUR"),
+            Payment::Deposit(Deposit {
+                client: 1,
+                tx: 1,
+                amount: Amount(1_0000),
+            }),
+        ),
+        Err(Error::TransactionAlreadyExists { client: 1, tx: 1 })
+    );
+    assert_eq!(
+        processor.process(
+            None,
+            Payment::Deposit(Deposit {
+                client: 1,
+                tx: 4,
+                amount: Amount(1_0000),
+            }),
+        ),
+        Err(Error::MissingCurrency { client: 1, tx: 4 })
+    );
+
+    // the dispute row's currency doesn't matter, the transaction's does
+    processor.process(
+        None,
+        Payment::Dispute(Dispute {
+            client: 1,
+            tx: 1,
+            amount: None,
+        }),
+    )?;
+
+    let usd = processor.get_account(1, "USD").unwrap();
+    assert_eq!(*usd.total_funds, 10_0000);
+    assert_eq!(*usd.held_funds, 10_0000);
+    let eur = processor.get_account(1, "EUR").unwrap();
+    assert_eq!(*eur.total_funds, 3_0000);
+    assert_eq!(*eur.held_funds, 0);
+    assert!(processor.get_account(1, "GBP").is_none());
+
+    let mut output = vec![];
+    processor.write_accounts(&mut output, &OutputOptions::default())?;
+    assert_eq!(
+        String::from_utf8(output)?,
+        "client,currency,available,held,total,locked\n\
+         1,EUR,3.0,0.0,3.0,false\n\
+         1,USD,0.0,10.0,10.0,false\n"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn chargeback_locks_all_currencies() -> anyhow::Result<()> {
+    use crate::payment::{Amount, Chargeback, Deposit, Dispute, Withdrawal};
+
+    let mut processor = MultiCurrencyProcessor::new(Config::default());
+    let deposit = |tx, amount| {
+        Payment::Deposit(Deposit {
+            client: 1,
+            tx,
+            amount: Amount(amount),
+        })
+    };
+    processor.process(Some("USD"), deposit(1, 10_0000))?;
+    processor.process(Some("EUR"), deposit(2, 3_0000))?;
+    processor.process(
+        None,
+        Payment::Dispute(Dispute {
+            client: 1,
+            tx: 1,
+            amount: None,
+        }),
+    )?;
+    processor.process(
+        None,
+        Payment::Chargeback(Chargeback {
+            client: 1,
+            tx: 1,
+            amount: None,
+        }),
+    )?;
+
+    assert_eq!(
+        processor.process(
+            Some("EUR"),
+            Payment::Withdrawal(Withdrawal {
+                client: 1,
+                tx: 3,
+                amount: Amount(1_0000),
+            }),
+        ),
+        Err(Error::AccountLocked { client: 1 })
+    );
+    // no new accounts in other currencies either
+    assert_eq!(
+        processor.process(Some("GBP"), deposit(4, 1_0000)),
+        Err(Error::AccountLocked { client: 1 })
+    );
+    assert!(processor.get_account(1, "GBP").is_none());
+
+    let mut output = vec![];
+    processor.write_accounts(&mut output, &OutputOptions::default())?;
+    assert_eq!(
+        String::from_utf8(output)?,
+        "client,currency,available,held,total,locked\n\
+         1,EUR,3.0,0.0,3.0,true\n\
+         1,USD,0.0,0.0,0.0,true\n"
+    );
+
+    Ok(())
+}
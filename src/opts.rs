@@ -1,3 +1,6 @@
+use payengine::payment::{ClientID, RoundingMode, Timestamp};
+use payengine::processor::SortBy;
+use std::num::NonZeroUsize;
 use std::path::PathBuf;
 use structopt::StructOpt;
 
@@ -5,7 +8,231 @@ use structopt::StructOpt;
 #[structopt(about = "Toy payment processor")]
 #[structopt(global_setting = structopt::clap::AppSettings::ColoredHelp)]
 #[structopt(global_setting = structopt::clap::AppSettings::InferSubcommands)]
+// `diff` works on output files, without any input
+#[structopt(setting = structopt::clap::AppSettings::SubcommandsNegateReqs)]
 pub struct Opts {
-    // An input file to process
-    pub input_cvs: PathBuf,
+    // Input files to process, in order
+    #[structopt(required = true)]
+    pub input_cvs: Vec<PathBuf>,
+
+    /// Format of the input files
+    #[structopt(long, default_value = "csv", possible_values = &["csv", "bincode", "json"])]
+    pub input_format: InputFormat,
+
+    /// Decompress the input files with gzip; done for `.gz` files regardless
+    /// (needs the `gzip` feature)
+    #[structopt(long)]
+    pub gzip: bool,
+
+    /// Thousands separator to ignore in amounts (eg. `,` for "1,234.5")
+    #[structopt(long)]
+    pub amount_thousands_sep: Option<char>,
+
+    /// Decimal places of amounts, in the input and in all the output
+    /// (including `diff` and `verify`); 4 if not given
+    #[structopt(long)]
+    pub amount_decimal_places: Option<usize>,
+
+    /// How to round amounts with more decimal places than supported;
+    /// half up if not given
+    #[structopt(long, possible_values = &["truncate", "half-up", "half-even"])]
+    pub round_amounts: Option<RoundingMode>,
+
+    /// Reject amounts with more decimal places than supported, instead
+    /// of rounding them
+    #[structopt(long, conflicts_with = "round-amounts")]
+    pub strict_precision: bool,
+
+    /// JSON file of opening balances, given as deposits that are applied
+    /// before the input files (needs the `json` feature); eg.
+    /// `[{"client": 1, "deposits": [{"tx": 1, "amount": 10.0}]}]`
+    #[structopt(long, conflicts_with = "multi-currency")]
+    pub seed_balance: Option<PathBuf>,
+
+    /// Don't report failed records one by one, only their count at the end
+    #[structopt(long)]
+    pub quiet: bool,
+
+    /// Write the records that failed to parse or were rejected to this CSV
+    /// file, with the error in an extra column, so they can be fixed and
+    /// fed in again
+    #[structopt(long)]
+    pub dead_letter: Option<PathBuf>,
+
+    /// Also write every accepted deposit and withdrawal, with its final
+    /// status, to this CSV file
+    #[structopt(long)]
+    pub audit: Option<PathBuf>,
+
+    /// Abort once more than this many rows (in all input files together)
+    /// are read
+    #[structopt(long)]
+    pub max_rows: Option<usize>,
+
+    /// Abort once more than this many bytes are read from any single input
+    /// file (after decompression)
+    #[structopt(long)]
+    pub max_bytes: Option<u64>,
+
+    /// Maximum number of distinct clients to accept
+    #[structopt(long)]
+    pub max_clients: Option<usize>,
+
+    /// Reject payments of a client beyond this many within `--rate-window`
+    /// seconds; needs a `timestamp` column
+    #[structopt(long, conflicts_with = "multi-currency")]
+    pub rate_limit: Option<usize>,
+
+    /// Window of `--rate-limit`, in seconds
+    #[structopt(long, default_value = "60")]
+    pub rate_window: u64,
+
+    /// Number of distinct clients expected in the input, to size the
+    /// accounts map up front
+    #[structopt(long, conflicts_with = "multi-currency")]
+    pub expected_clients: Option<usize>,
+
+    /// Don't allow withdrawing funds of previously disputed deposits
+    #[structopt(long)]
+    pub deny_withdrawal_overdraft_after_dispute: bool,
+
+    /// Reject deposits and withdrawals larger than this
+    #[structopt(long)]
+    pub max_amount: Option<String>,
+
+    /// Apply disputes, resolves and chargebacks of each file only after
+    /// all its deposits and withdrawals, regardless of their order
+    #[structopt(long)]
+    pub two_pass: bool,
+
+    /// Order of the output rows
+    #[structopt(long, default_value = "client", possible_values = &["client", "total", "available"])]
+    pub sort_by: SortBy,
+
+    /// Add the number of transactions of each client to the output
+    #[structopt(long)]
+    pub with_counts: bool,
+
+    /// Add why each account is locked (`chargeback` or `administrative`,
+    /// empty if it's not) to the output
+    #[structopt(long, conflicts_with = "multi-currency")]
+    pub with_lock_reason: bool,
+
+    /// Output amounts as integers, in units of the last decimal place
+    /// (eg. `15000` for `1.5`)
+    #[structopt(long, conflicts_with = "multi-currency")]
+    pub integer_amounts: bool,
+
+    /// Print how the balances of each client add up to stderr: their
+    /// deposits, withdrawals, chargebacks, reversals and adjustments
+    #[structopt(long)]
+    pub explain: bool,
+
+    /// Leave out accounts with all balances zero, unless locked
+    #[structopt(long)]
+    pub skip_zero: bool,
+
+    /// Keep separate balances per currency, given by an extra `currency`
+    /// input column; adds the `currency` column to the output
+    #[structopt(long)]
+    pub multi_currency: bool,
+
+    /// Report the number of processed records (and errors) to stderr
+    /// every given number of records
+    #[structopt(long)]
+    pub progress: Option<NonZeroUsize>,
+
+    /// Also print the accounts as an aligned table to stderr
+    #[structopt(long, conflicts_with = "multi-currency")]
+    pub preview: bool,
+
+    /// List clients with more than the given percentage of their funds held
+    /// on stderr
+    #[structopt(long, conflicts_with = "multi-currency")]
+    pub alerts: Option<u32>,
+
+    /// Skip records identical to an already seen one (in any input file)
+    #[structopt(long)]
+    pub dedup: bool,
+
+    /// Process every input file as an independent ledger, writing its
+    /// accounts next to it as `<name>.out.csv` instead of to stdout;
+    /// `--dedup` then only looks within each file, and `--preview` and
+    /// `--alerts` are printed for each file
+    #[structopt(long, conflicts_with_all = &["audit", "fail-on-locked", "reconcile", "explain"])]
+    pub per_file: bool,
+
+    /// Skip input files whose contents are listed in this manifest file,
+    /// and add the ones processed (once the output is written), so re-fed
+    /// files aren't counted twice
+    #[structopt(long)]
+    pub manifest: Option<PathBuf>,
+
+    /// Only apply records timestamped at or before this RFC 3339 time
+    /// (eg. `2021-03-01T00:00:00Z`), giving the balances as of then;
+    /// needs a `timestamp` column, later records are skipped
+    #[structopt(long)]
+    pub as_of: Option<Timestamp>,
+
+    /// Skip records with an empty type (eg. separator lines) instead of
+    /// reporting them as bad
+    #[structopt(long)]
+    pub skip_empty_type: bool,
+
+    /// Skip records of unknown (non-empty) types instead of reporting
+    /// them as bad
+    #[structopt(long)]
+    pub ignore_unknown_types: bool,
+
+    /// Exit with an error (after writing the output) if any account
+    /// ends up locked
+    #[structopt(long)]
+    pub fail_on_locked: bool,
+
+    /// Check (after writing the output) that the total funds of all
+    /// accounts match the accepted deposits, withdrawals, chargebacks
+    /// and reversals, reporting the totals to stderr
+    #[structopt(long)]
+    pub reconcile: bool,
+
+    #[structopt(subcommand)]
+    pub cmd: Option<Command>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum InputFormat {
+    Csv,
+    /// Back to back bincode encoded `Payment`s (needs the `bincode` feature)
+    Bincode,
+    /// A JSON array of payment objects tagged with their `type`
+    /// (needs the `json` feature)
+    Json,
+}
+
+impl std::str::FromStr for InputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(InputFormat::Csv),
+            "bincode" => Ok(InputFormat::Bincode),
+            "json" => Ok(InputFormat::Json),
+            _ => Err(format!("unknown input format: {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, StructOpt, Clone)]
+pub enum Command {
+    /// Print only the account of a single client
+    Query {
+        #[structopt(long)]
+        client: ClientID,
+    },
+    /// Compare the balances of two output files (of a single currency),
+    /// printing every difference; fails if there are any
+    Diff { a: PathBuf, b: PathBuf },
+    /// Check that the balances of every row of an output file are
+    /// consistent, printing the ones that aren't; fails if there are any
+    Verify { output: PathBuf },
 }
@@ -1,13 +1,16 @@
 use serde::{Deserialize, Serialize};
 use shrinkwraprs::Shrinkwrap;
 use std::convert::{TryFrom, TryInto};
+use std::io::{BufRead, BufReader, Read};
 use thiserror::Error;
 
 // TODO: wrap in newtypes?
 pub type ClientID = u16;
 pub type TransactionID = u32;
+/// Currency code, eg. `USD`; only used with multiple currencies
+pub type Currency = String;
 
-#[derive(Error, Debug)]
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
 pub enum DeserializationError {
     #[error("missing amount value")]
     MissingAmount,
@@ -15,133 +18,815 @@ pub enum DeserializationError {
     SuperfluousAmount,
     #[error("invalid type value: {0}")]
     InvalidType(String),
+    #[error("invalid amount value: {0}")]
+    InvalidAmount(String),
+    #[error("amount has more decimal places than supported: {0}")]
+    ExcessPrecision(String),
+    #[error("invalid client value: {0}")]
+    InvalidClient(String),
+    #[error("invalid transaction value: {0}")]
+    InvalidTransaction(String),
+    #[error("amount must be positive")]
+    NonPositiveAmount,
+    #[error("missing timestamp value")]
+    MissingTimestamp,
+    #[error("invalid timestamp value: {0}")]
+    InvalidTimestamp(String),
+}
+
+/// Error parsing a single CSV line into a `Payment`
+#[derive(Error, Debug)]
+pub enum LineParseError {
+    #[error("malformed line: {0}")]
+    Malformed(#[from] csv::Error),
+    #[error("empty line")]
+    Empty,
+    #[error(transparent)]
+    Invalid(#[from] DeserializationError),
+}
+
+/// `DeserializationError` along with the line of the input it comes from
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+#[error("line {line}: {source}")]
+pub struct LocatedError {
+    pub line: u64,
+    pub source: DeserializationError,
 }
 
 // TODO: I don't like this type as is right now
 // with some boilerplate it could be made into something
 // better: checking overflow/underflow, verifying precision
-#[derive(Debug, Default, Copy, Clone, Shrinkwrap, PartialOrd, Ord, Eq, PartialEq)]
+#[derive(
+    Debug, Default, Copy, Clone, Shrinkwrap, PartialOrd, Ord, Eq, PartialEq, Serialize, Deserialize,
+)]
+#[cfg_attr(feature = "fuzz", derive(arbitrary::Arbitrary))]
 #[shrinkwrap(mutable)]
 pub struct Amount(pub u64);
 
-// TODO: bad name
-const AMOUNT_PRECISION: f64 = 0.0001;
+/// Signed amount in the same units as `Amount`, for reporting only
+///
+/// `i128` holds the difference of any two `Amount`s.
+#[derive(Debug, Default, Copy, Clone, PartialOrd, Ord, Eq, PartialEq, Serialize)]
+pub struct SignedAmount(pub i128);
+
+impl SignedAmount {
+    /// Same as `Amount::to_decimal_string`, with a `-` if negative
+    pub fn to_decimal_string(self, decimal_places: usize) -> String {
+        let sign = if self.0 < 0 { "-" } else { "" };
+        format!(
+            "{}{}",
+            sign,
+            decimal_string(self.0.unsigned_abs(), decimal_places)
+        )
+    }
+}
+
+/// Exact, with all four decimal places (`-1.5000`)
+impl std::fmt::Display for SignedAmount {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.to_decimal_string(AMOUNT_DECIMAL_PLACES))
+    }
+}
+
+// `units` with the given decimal places; works on the digits rather than
+// dividing by a power of ten, so it can't overflow for any value or
+// number of decimal places
+fn decimal_string(units: u128, decimal_places: usize) -> String {
+    if decimal_places == 0 {
+        return units.to_string();
+    }
+    // at least one digit before the point
+    let digits = format!("{:0>width$}", units, width = decimal_places + 1);
+    let (int_part, frac_part) = digits.split_at(digits.len() - decimal_places);
+    format!("{}.{}", int_part, frac_part)
+}
+
+/// Default number of decimal places of amounts, ie. `Amount(1)` is `0.0001`
+///
+/// `Amount` itself is just a count of units; a processor can be configured
+/// to use units of another decimal place (eg. 0 for JPY, 8 for bitcoin).
+pub const AMOUNT_DECIMAL_PLACES: usize = 4;
+
+/// How to round values with more decimal places than `Amount` can store
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum RoundingMode {
+    /// Drop the excess digits (round toward zero)
+    #[default]
+    Truncate,
+    /// Round to nearest, ties away from zero
+    HalfUp,
+    /// Round to nearest, ties to even ("banker's rounding")
+    HalfEven,
+}
+
+impl std::str::FromStr for RoundingMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "truncate" => RoundingMode::Truncate,
+            "half-up" => RoundingMode::HalfUp,
+            "half-even" => RoundingMode::HalfEven,
+            _ => return Err(format!("invalid rounding mode: {}", s)),
+        })
+    }
+}
 
 impl Amount {
-    // TODO: FIXME: This way of converting to float can possibly
-    // still lead to precision loss. It would be better to just
-    // output the number as fixed precision, but since I'm using
-    // csv + server, this is not trivial.
-    pub fn to_f64(self) -> f64 {
-        self.0 as f64 * AMOUNT_PRECISION
+    pub fn checked_add(self, other: Amount) -> Option<Amount> {
+        self.0.checked_add(other.0).map(Amount)
+    }
+
+    pub fn checked_sub(self, other: Amount) -> Option<Amount> {
+        self.0.checked_sub(other.0).map(Amount)
+    }
+
+    /// Difference that can go negative, eg. for net flow in reports
+    pub fn signed_sub(self, other: Amount) -> SignedAmount {
+        SignedAmount(i128::from(self.0) - i128::from(other.0))
+    }
+
+    /// Same as `from_decimal_str_with_places`, but excess decimal
+    /// places are rounded with `mode` instead of rejected
+    pub fn from_decimal_str_rounded(
+        amount: &str,
+        decimal_places: usize,
+        mode: RoundingMode,
+    ) -> Result<Self, DeserializationError> {
+        match Amount::from_decimal_str_with_places(amount, decimal_places) {
+            Err(DeserializationError::ExcessPrecision(_)) => {}
+            result => return result,
+        }
+        // only reached with a valid number, with a fraction longer than kept
+        let point = amount
+            .find('.')
+            .expect("excess decimal places need a point");
+        let (truncated, excess) = amount.split_at(point + 1 + decimal_places);
+        let mut value =
+            Amount::from_decimal_str_with_places(truncated.trim_end_matches('.'), decimal_places)?
+                .0;
+
+        let first_excess = excess.as_bytes().first().map_or(0, |d| d - b'0');
+        let round_up = match mode {
+            RoundingMode::Truncate => false,
+            RoundingMode::HalfUp => 5 <= first_excess,
+            RoundingMode::HalfEven => {
+                let is_tie = first_excess == 5 && excess[1..].bytes().all(|d| d == b'0');
+                if is_tie {
+                    value % 2 == 1
+                } else {
+                    5 <= first_excess
+                }
+            }
+        };
+        if round_up {
+            value = value
+                .checked_add(1)
+                .ok_or_else(|| DeserializationError::InvalidAmount(amount.to_string()))?;
+        }
+
+        Ok(Amount(value))
+    }
+
+    /// Parse a decimal number like `1.2345` exactly, without going through `f64`
+    ///
+    /// Accepts at most `AMOUNT_DECIMAL_PLACES` decimal places; anything
+    /// more precise, signed, in exponent notation or otherwise not a plain
+    /// decimal number is rejected rather than rounded.
+    pub fn from_decimal_str(amount: &str) -> Result<Self, DeserializationError> {
+        Amount::from_decimal_str_with_places(amount, AMOUNT_DECIMAL_PLACES)
+    }
+
+    /// Same as `from_decimal_str`, into units of the given decimal place
+    pub fn from_decimal_str_with_places(
+        amount: &str,
+        decimal_places: usize,
+    ) -> Result<Self, DeserializationError> {
+        let invalid = || DeserializationError::InvalidAmount(amount.to_string());
+        let (int_part, frac_part) = match amount.find('.') {
+            Some(i) => (&amount[..i], Some(&amount[i + 1..])),
+            None => (amount, None),
+        };
+        let is_number = |part: &str| !part.is_empty() && part.bytes().all(|d| d.is_ascii_digit());
+        if !is_number(int_part) || !frac_part.map_or(true, is_number) {
+            return Err(invalid());
+        }
+        let frac_part = frac_part.unwrap_or("");
+        if decimal_places < frac_part.len() {
+            return Err(DeserializationError::ExcessPrecision(amount.to_string()));
+        }
+
+        let mut value: u64 = 0;
+        let frac_digits = frac_part.bytes().chain(std::iter::repeat(b'0'));
+        for digit in int_part.bytes().chain(frac_digits.take(decimal_places)) {
+            value = value
+                .checked_mul(10)
+                .and_then(|v| v.checked_add(u64::from(digit - b'0')))
+                .ok_or_else(invalid)?;
+        }
+
+        Ok(Amount(value))
+    }
+
+    /// Exact decimal representation, with all of the given decimal places
+    ///
+    /// Can't overflow for any value or number of decimal places.
+    pub fn to_decimal_string(self, decimal_places: usize) -> String {
+        decimal_string(u128::from(self.0), decimal_places)
+    }
+
+    /// Exact, as in the output: like `to_decimal_string`, without the
+    /// trailing zeros but with at least one decimal place (`1.5`, `2.0`)
+    pub fn to_output_string(self, decimal_places: usize) -> String {
+        let mut amount = self.to_decimal_string(decimal_places);
+        if decimal_places == 0 {
+            amount.push_str(".0");
+            return amount;
+        }
+        let len = amount.trim_end_matches('0').len();
+        amount.truncate(len);
+        if amount.ends_with('.') {
+            amount.push('0');
+        }
+        amount
     }
 }
-impl TryFrom<f64> for Amount {
-    type Error = DeserializationError;
-    fn try_from(amount: f64) -> Result<Self, Self::Error> {
-        // TODO: add sanity checks: too large values, precision loss, negative values
-        let amount = (amount / AMOUNT_PRECISION) as u64;
 
-        Ok(Amount(amount))
+/// Exact, with all four decimal places (`1.5000`)
+impl std::fmt::Display for Amount {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.to_decimal_string(AMOUNT_DECIMAL_PLACES))
     }
 }
 
-impl TryFrom<Option<f64>> for Amount {
-    type Error = DeserializationError;
-    fn try_from(amount: Option<f64>) -> Result<Self, Self::Error> {
-        match amount {
-            None => Err(DeserializationError::MissingAmount),
-            Some(v) => v.try_into(),
+/// Exact, see `Amount::from_decimal_str`
+impl std::str::FromStr for Amount {
+    type Err = DeserializationError;
+    fn from_str(amount: &str) -> Result<Self, Self::Err> {
+        Amount::from_decimal_str(amount)
+    }
+}
+
+/// Point in time, as nanoseconds since the Unix epoch
+///
+/// Parsed from RFC 3339 (`2021-03-01T12:00:00Z`, `2021-03-01T13:00:00.5+01:00`);
+/// only used for ordering, so the offset is folded in and not kept.
+#[derive(Debug, Copy, Clone, PartialOrd, Ord, Eq, PartialEq)]
+pub struct Timestamp(pub i128);
+
+impl std::str::FromStr for Timestamp {
+    type Err = DeserializationError;
+    fn from_str(timestamp: &str) -> Result<Self, Self::Err> {
+        let invalid = || DeserializationError::InvalidTimestamp(timestamp.to_string());
+        let number = |part: Option<&str>| -> Result<i64, DeserializationError> {
+            match part {
+                Some(part) if part.bytes().all(|d| d.is_ascii_digit()) => {
+                    part.parse().map_err(|_| invalid())
+                }
+                _ => Err(invalid()),
+            }
+        };
+        // fixed width fields, so all found by their position
+        let digits = |range: std::ops::Range<usize>| number(timestamp.get(range));
+        let byte = |i: usize| timestamp.as_bytes().get(i).copied();
+        if byte(4) != Some(b'-')
+            || byte(7) != Some(b'-')
+            || !matches!(byte(10), Some(b'T' | b't' | b' '))
+            || byte(13) != Some(b':')
+            || byte(16) != Some(b':')
+        {
+            return Err(invalid());
         }
+        let (year, month, day) = (digits(0..4)?, digits(5..7)?, digits(8..10)?);
+        let (hour, minute, second) = (digits(11..13)?, digits(14..16)?, digits(17..19)?);
+
+        let mut rest = &timestamp[19..];
+        let mut nanos = 0;
+        if let Some(frac) = rest.strip_prefix('.') {
+            let len = frac.bytes().take_while(u8::is_ascii_digit).count();
+            if len == 0 {
+                return Err(invalid());
+            }
+            // digits beyond nanoseconds are dropped
+            for (i, digit) in frac[..len]
+                .bytes()
+                .chain(std::iter::repeat(b'0'))
+                .take(9)
+                .enumerate()
+            {
+                nanos += i128::from(digit - b'0') * 10i128.pow(8 - i as u32);
+            }
+            rest = &frac[len..];
+        }
+        let offset = match rest {
+            "Z" | "z" => 0,
+            _ => {
+                let sign = match rest.as_bytes().first() {
+                    Some(b'+') => 1,
+                    Some(b'-') => -1,
+                    _ => return Err(invalid()),
+                };
+                if rest.len() != 6 || rest.as_bytes().get(3) != Some(&b':') {
+                    return Err(invalid());
+                }
+                let (hours, minutes) = (number(rest.get(1..3))?, number(rest.get(4..6))?);
+                if 23 < hours || 59 < minutes {
+                    return Err(invalid());
+                }
+                sign * (hours * 60 + minutes) * 60
+            }
+        };
+
+        let leap_year = year % 4 == 0 && (year % 100 != 0 || year % 400 == 0);
+        let month_days = match month {
+            1 | 3 | 5 | 7 | 8 | 10 | 12 => 31,
+            4 | 6 | 9 | 11 => 30,
+            2 if leap_year => 29,
+            2 => 28,
+            _ => return Err(invalid()),
+        };
+        // a leap second (`:60`) sorts along with the second before it
+        if day < 1 || month_days < day || 23 < hour || 59 < minute || 60 < second {
+            return Err(invalid());
+        }
+
+        let seconds =
+            days_from_civil(year, month, day) * 86_400 + hour * 3600 + minute * 60 + second.min(59)
+                - offset;
+        Ok(Timestamp(i128::from(seconds) * 1_000_000_000 + nanos))
+    }
+}
+
+// days since 1970-01-01 of a proleptic Gregorian date
+// (http://howardhinnant.github.io/date_algorithms.html#days_from_civil)
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// Panics on overflow; use `checked_add` where it can happen
+impl std::ops::Add for Amount {
+    type Output = Amount;
+    fn add(self, other: Amount) -> Amount {
+        self.checked_add(other).expect("amount overflow")
+    }
+}
+
+/// Panics on underflow; use `checked_sub` where it can happen
+impl std::ops::Sub for Amount {
+    type Output = Amount;
+    fn sub(self, other: Amount) -> Amount {
+        self.checked_sub(other).expect("amount underflow")
     }
 }
 
 // I wanted to go with straight to internally tagged enum
 // with `#[serde(tag = "type")]` but that will not fly with CSV,
 // it seems, and I don't have time to dig into it.
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, Serialize, Debug, Clone)]
 #[serde(rename_all = "lowercase")]
 pub struct RawInputRecord {
     pub r#type: String,
-    pub client: ClientID,
-    pub tx: TransactionID,
-    pub amount: Option<f64>,
+    // `client` and `tx` are kept as text and parsed in the `TryFrom`
+    // impls, so bad values can be reported with a precise error
+    pub client: String,
+    pub tx: String,
+    // Note: an empty (or whitespace-only, after trimming) cell
+    // deserializes as `None`, same as an absent value, for all types.
+    // Kept as text too, so it's parsed exactly, without going through `f64`.
+    pub amount: Option<String>,
+    // optional column, only used by the multi-currency processing
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub currency: Option<Currency>,
+    // optional column, only used by `--as-of` and `--rate-limit`;
+    // RFC 3339, see `Timestamp`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<String>,
 }
 
-#[derive(Debug, Serialize)]
-pub struct RawOutputRecord {
-    pub client: ClientID,
-    pub available: f64,
-    pub held: f64,
-    pub total: f64,
+/// Row of the output; amounts are exact decimal numbers (see
+/// `Amount::to_output_string`), unless `with_amounts` puts something
+/// else (eg. raw `Amount` units) there
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct RawOutputRecord<C = ClientID, A = String> {
+    pub client: C,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub currency: Option<Currency>,
+    pub available: A,
+    pub held: A,
+    pub total: A,
     pub locked: bool,
+    // optional columns are left out completely when not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub transactions: Option<usize>,
+    // empty for active accounts, so the column is there in every row
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lock_reason: Option<String>,
+}
+
+impl<C, A> RawOutputRecord<C, A> {
+    /// Fill in the `lock_reason` column, empty if there's no `reason`
+    pub fn set_lock_reason(&mut self, reason: Option<impl std::fmt::Display>) {
+        self.lock_reason = Some(reason.map_or_else(String::new, |reason| reason.to_string()));
+    }
+
+    /// Same record, with other `available`, `held` and `total` values
+    pub fn with_amounts<B>(self, available: B, held: B, total: B) -> RawOutputRecord<C, B> {
+        RawOutputRecord {
+            client: self.client,
+            currency: self.currency,
+            available,
+            held,
+            total,
+            locked: self.locked,
+            transactions: self.transactions,
+            lock_reason: self.lock_reason,
+        }
+    }
+}
+
+const UTF8_BOM: &[u8] = b"\xEF\xBB\xBF";
+
+/// Create a CSV reader of `RawInputRecord`s with the settings we use
+///
+/// Skips the UTF-8 BOM that Windows tools like to put at the start,
+/// which would otherwise end up in the first header name. CRLF line
+/// endings are handled by the csv crate already.
+pub fn input_reader<R: Read>(input: R) -> std::io::Result<csv::Reader<BufReader<R>>> {
+    let mut input = BufReader::new(input);
+    if input.fill_buf()?.starts_with(UTF8_BOM) {
+        input.consume(UTF8_BOM.len());
+    }
+
+    Ok(csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .from_reader(input))
 }
 
-#[derive(Debug, Clone)]
-pub struct DepositDetails {
-    pub client: ClientID,
-    pub tx: TransactionID,
+impl RawInputRecord {
+    /// Time of the record, from the optional `timestamp` column
+    pub fn parse_timestamp(&self) -> Result<Timestamp, DeserializationError> {
+        self.timestamp
+            .as_deref()
+            .ok_or(DeserializationError::MissingTimestamp)?
+            .parse()
+    }
+
+    fn parse_client(&self) -> Result<ClientID, DeserializationError> {
+        self.client
+            .parse()
+            .map_err(|_| DeserializationError::InvalidClient(self.client.clone()))
+    }
+
+    fn parse_tx(&self) -> Result<TransactionID, DeserializationError> {
+        self.tx
+            .parse()
+            .map_err(|_| DeserializationError::InvalidTransaction(self.tx.clone()))
+    }
+
+    fn parse_amount(
+        &self,
+        decimal_places: Option<usize>,
+        rounding: Option<RoundingMode>,
+    ) -> Result<Amount, DeserializationError> {
+        let amount = self
+            .amount
+            .as_deref()
+            .ok_or(DeserializationError::MissingAmount)?;
+        parse_amount(amount, decimal_places, rounding)
+    }
+
+    fn parse_deposit(
+        &self,
+        decimal_places: Option<usize>,
+        rounding: Option<RoundingMode>,
+    ) -> Result<DepositDetails, DeserializationError> {
+        Ok(DepositDetails {
+            client: self.parse_client()?,
+            tx: self.parse_tx()?,
+            amount: self.parse_amount(decimal_places, rounding)?,
+        })
+    }
+
+    fn parse_withdrawal(
+        &self,
+        decimal_places: Option<usize>,
+        rounding: Option<RoundingMode>,
+    ) -> Result<WithdrawalDetails, DeserializationError> {
+        Ok(WithdrawalDetails {
+            client: self.parse_client()?,
+            tx: self.parse_tx()?,
+            amount: self.parse_amount(decimal_places, rounding)?,
+        })
+    }
+
+    fn parse_dispute(
+        &self,
+        decimal_places: Option<usize>,
+        rounding: Option<RoundingMode>,
+    ) -> Result<DisputeDetails, DeserializationError> {
+        Ok(DisputeDetails {
+            client: self.parse_client()?,
+            tx: self.parse_tx()?,
+            amount: match self.amount {
+                Some(_) => Some(self.parse_amount(decimal_places, rounding)?),
+                None => None,
+            },
+        })
+    }
+
+    /// Same as `try_into()`, but with amounts in units of `decimal_places`
+    /// (`AMOUNT_DECIMAL_PLACES` if not set), and amounts with more decimal
+    /// places rounded with `rounding` (rejected if not set)
+    pub fn into_payment(
+        self,
+        decimal_places: Option<usize>,
+        rounding: Option<RoundingMode>,
+    ) -> Result<Payment, DeserializationError> {
+        Ok(match self.r#type.as_str() {
+            "deposit" => Payment::Deposit(self.parse_deposit(decimal_places, rounding)?),
+            "withdrawal" => Payment::Withdrawal(self.parse_withdrawal(decimal_places, rounding)?),
+            "dispute" => Payment::Dispute(self.parse_dispute(decimal_places, rounding)?),
+            "resolve" => Payment::Resolve(self.parse_dispute(decimal_places, rounding)?),
+            "chargeback" => Payment::Chargeback(self.parse_dispute(decimal_places, rounding)?),
+            "reversal" if self.amount.is_some() => {
+                return Err(DeserializationError::SuperfluousAmount)
+            }
+            "reversal" => Payment::Reversal(self.parse_dispute(None, None)?),
+            _ => return Err(DeserializationError::InvalidType(self.r#type)),
+        })
+    }
+
+    /// Same as `try_into()`, but with amounts in units of the given decimal place
+    ///
+    /// Amounts with more decimal places are rejected, same as with the
+    /// default ones.
+    pub fn into_payment_with_places(
+        self,
+        decimal_places: usize,
+    ) -> Result<Payment, DeserializationError> {
+        self.into_payment(Some(decimal_places), None)
+    }
+
+    /// Same as `try_into()`, but amounts with more decimal places are
+    /// rounded with `mode` instead of rejected
+    pub fn into_payment_rounded(self, mode: RoundingMode) -> Result<Payment, DeserializationError> {
+        self.into_payment(None, Some(mode))
+    }
+
+    /// Deserialize from a CSV `record` of a file with the given `headers`
+    ///
+    /// With `thousands_sep`, its occurrences are removed from the amount
+    /// before parsing it, so `1,234.5` (quoted in the CSV) is read as
+    /// `1234.5`. That's only if they group the integer part by thousands;
+    /// otherwise (eg. `12,34.5`) the amount is kept as is, and so rejected.
+    /// The decimal separator is always `.`.
+    pub fn from_record(
+        record: &csv::StringRecord,
+        headers: &csv::StringRecord,
+        thousands_sep: Option<char>,
+    ) -> csv::Result<Self> {
+        let amount_column = headers.iter().position(|header| header == "amount");
+        match (thousands_sep, amount_column) {
+            (Some(sep), Some(column)) => {
+                let mut stripped: csv::StringRecord = record
+                    .iter()
+                    .enumerate()
+                    .map(|(i, field)| {
+                        if i == column {
+                            strip_thousands_sep(field, sep).unwrap_or_else(|| field.to_string())
+                        } else {
+                            field.to_string()
+                        }
+                    })
+                    .collect();
+                stripped.set_position(record.position().cloned());
+                stripped.deserialize(Some(headers))
+            }
+            _ => record.deserialize(Some(headers)),
+        }
+    }
+
+    /// Same as `try_into()`, but a failure carries the source `line`
+    pub fn try_into_located(self, line: u64) -> Result<Payment, LocatedError> {
+        self.try_into()
+            .map_err(|source| LocatedError { line, source })
+    }
+}
+
+// without `decimal_places`, the default ones are used;
+// without `rounding`, excess decimal places are rejected
+fn parse_amount(
+    amount: &str,
+    decimal_places: Option<usize>,
+    rounding: Option<RoundingMode>,
+) -> Result<Amount, DeserializationError> {
+    let decimal_places = decimal_places.unwrap_or(AMOUNT_DECIMAL_PLACES);
+    match rounding {
+        Some(mode) => Amount::from_decimal_str_rounded(amount, decimal_places, mode),
+        None => Amount::from_decimal_str_with_places(amount, decimal_places),
+    }
+}
+
+// `amount` without the thousands separators, unless they
+// don't group its integer part by exactly three digits
+fn strip_thousands_sep(amount: &str, sep: char) -> Option<String> {
+    let (int_part, frac_part) = amount.split_at(amount.find('.').unwrap_or(amount.len()));
+    if frac_part.contains(sep) {
+        return None;
+    }
+    if int_part.contains(sep) {
+        let mut groups = int_part.split(sep);
+        let first = groups.next().unwrap_or_default();
+        if !(1..=3).contains(&first.len()) || !groups.all(|group| group.len() == 3) {
+            return None;
+        }
+    }
+    Some(format!("{}{}", int_part.replace(sep, ""), frac_part))
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "fuzz", derive(arbitrary::Arbitrary))]
+pub struct DepositDetails<C = ClientID, T = TransactionID> {
+    pub client: C,
+    pub tx: T,
     pub amount: Amount,
 }
 
 impl TryFrom<RawInputRecord> for DepositDetails {
     type Error = DeserializationError;
     fn try_from(raw: RawInputRecord) -> Result<Self, Self::Error> {
-        Ok(DepositDetails {
-            client: raw.client,
-            tx: raw.tx,
-            amount: raw.amount.try_into()?,
-        })
+        raw.parse_deposit(None, None)
     }
 }
 
-#[derive(Debug, Clone)]
-pub struct DisputeDetails {
-    pub client: ClientID,
-    pub tx: TransactionID,
+/// Same fields as `DepositDetails`, but a distinct type, so
+/// a withdrawal can't be applied as a deposit or vice versa:
+///
+/// ```compile_fail
+/// use payengine::payment::{Amount, Deposit, Withdrawal};
+///
+/// let withdrawal = Withdrawal { client: 1, tx: 1, amount: Amount(1) };
+/// let deposit: Deposit = withdrawal;
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "fuzz", derive(arbitrary::Arbitrary))]
+pub struct WithdrawalDetails<C = ClientID, T = TransactionID> {
+    pub client: C,
+    pub tx: T,
+    pub amount: Amount,
 }
 
-impl TryFrom<RawInputRecord> for DisputeDetails {
+impl TryFrom<RawInputRecord> for WithdrawalDetails {
     type Error = DeserializationError;
     fn try_from(raw: RawInputRecord) -> Result<Self, Self::Error> {
-        if raw.amount.is_some() {
-            return Err(DeserializationError::SuperfluousAmount);
-        }
+        raw.parse_withdrawal(None, None)
+    }
+}
 
-        Ok(DisputeDetails {
-            client: raw.client,
-            tx: raw.tx,
-        })
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "fuzz", derive(arbitrary::Arbitrary))]
+pub struct DisputeDetails<C = ClientID, T = TransactionID> {
+    pub client: C,
+    pub tx: T,
+    /// Part of the transaction a dispute is about, the whole (undisputed
+    /// rest of it) if not set; for a resolve or chargeback, the dispute it
+    /// settles, all outstanding ones of the transaction if not set.
+    /// Reversals don't take one.
+    pub amount: Option<Amount>,
+}
+
+impl TryFrom<RawInputRecord> for DisputeDetails {
+    type Error = DeserializationError;
+    fn try_from(raw: RawInputRecord) -> Result<Self, Self::Error> {
+        raw.parse_dispute(None, None)
     }
 }
 
-pub type Deposit = DepositDetails;
-pub type Withdrawal = DepositDetails;
-pub type Dispute = DisputeDetails;
-pub type Resolve = DisputeDetails;
-pub type Chargeback = DisputeDetails;
+pub type Deposit<C = ClientID, T = TransactionID> = DepositDetails<C, T>;
+pub type Withdrawal<C = ClientID, T = TransactionID> = WithdrawalDetails<C, T>;
+pub type Dispute<C = ClientID, T = TransactionID> = DisputeDetails<C, T>;
+pub type Resolve<C = ClientID, T = TransactionID> = DisputeDetails<C, T>;
+pub type Chargeback<C = ClientID, T = TransactionID> = DisputeDetails<C, T>;
+pub type Reversal<C = ClientID, T = TransactionID> = DisputeDetails<C, T>;
 
-#[derive(Clone, Debug)]
-pub enum Payment {
-    Deposit(Deposit),
-    Withdrawal(Withdrawal),
-    Dispute(Dispute),
-    Resolve(Resolve),
-    Chargeback(Chargeback),
+/// A single payment
+///
+/// New payment types may be added in the future, so matches
+/// on it need a catch-all arm:
+///
+/// ```
+/// use payengine::payment::Payment;
+/// use std::convert::TryInto;
+///
+/// fn describe(payment: &Payment) -> &'static str {
+///     match payment {
+///         Payment::Deposit(_) => "money in",
+///         Payment::Withdrawal(_) => "money out",
+///         _ => "something else",
+///     }
+/// }
+///
+/// let payment: Payment = "deposit,1,1,1.0".try_into().unwrap();
+/// assert_eq!(describe(&payment), "money in");
+/// ```
+///
+/// The client and transaction id types default to `ClientID` and
+/// `TransactionID`, which is what the CSV input uses.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "fuzz", derive(arbitrary::Arbitrary))]
+#[non_exhaustive]
+pub enum Payment<C = ClientID, T = TransactionID> {
+    Deposit(Deposit<C, T>),
+    Withdrawal(Withdrawal<C, T>),
+    Dispute(Dispute<C, T>),
+    Resolve(Resolve<C, T>),
+    Chargeback(Chargeback<C, T>),
+    /// Reverse (credit back) a past withdrawal
+    Reversal(Reversal<C, T>),
 }
 
-impl Payment {
+impl<C: Copy, T> Payment<C, T> {
     /// Get client id
     ///
     /// Since all payment types have it, it's useful to
     /// have it.
-    pub fn get_client_id(&self) -> ClientID {
+    pub fn get_client_id(&self) -> C {
         match self {
             Payment::Deposit(d) => d.client,
             Payment::Withdrawal(d) => d.client,
             Payment::Dispute(d) => d.client,
             Payment::Resolve(d) => d.client,
             Payment::Chargeback(d) => d.client,
+            Payment::Reversal(d) => d.client,
+        }
+    }
+
+    /// Check for values that make no sense regardless of any account state
+    ///
+    /// Any amount given must be positive, and so deposits and withdrawals
+    /// need one that is; the other types don't have to carry one. Parsing doesn't
+    /// call it, so it's up to the caller when to use it.
+    pub fn validate(&self) -> Result<(), DeserializationError> {
+        match self.get_amount() {
+            Some(Amount(0)) => Err(DeserializationError::NonPositiveAmount),
+            _ => Ok(()),
+        }
+    }
+
+    /// Get transaction id, of the payment itself or the one it refers to
+    pub fn get_tx(&self) -> T
+    where
+        T: Copy,
+    {
+        match self {
+            Payment::Deposit(d) => d.tx,
+            Payment::Withdrawal(d) => d.tx,
+            Payment::Dispute(d) => d.tx,
+            Payment::Resolve(d) => d.tx,
+            Payment::Chargeback(d) => d.tx,
+            Payment::Reversal(d) => d.tx,
+        }
+    }
+
+    /// Amount of the payment, `None` for reversals and for
+    /// disputes (and their resolves and chargebacks) without one
+    pub fn get_amount(&self) -> Option<Amount> {
+        match self {
+            Payment::Deposit(d) => Some(d.amount),
+            Payment::Withdrawal(d) => Some(d.amount),
+            Payment::Dispute(d) | Payment::Resolve(d) | Payment::Chargeback(d) => d.amount,
+            Payment::Reversal(_) => None,
+        }
+    }
+}
+
+impl Payment {
+    /// Convert back into the raw record
+    ///
+    /// Inverse of `TryFrom<RawInputRecord>`, useful for building
+    /// CSV inputs (eg. in tests) out of `Payment`s.
+    pub fn to_raw(&self) -> RawInputRecord {
+        let (r#type, client, tx, amount) = match self {
+            Payment::Deposit(d) => ("deposit", d.client, d.tx, Some(d.amount)),
+            Payment::Withdrawal(d) => ("withdrawal", d.client, d.tx, Some(d.amount)),
+            Payment::Dispute(d) => ("dispute", d.client, d.tx, d.amount),
+            Payment::Resolve(d) => ("resolve", d.client, d.tx, d.amount),
+            Payment::Chargeback(d) => ("chargeback", d.client, d.tx, d.amount),
+            Payment::Reversal(d) => ("reversal", d.client, d.tx, None),
+        };
+        let amount = amount.map(|amount| amount.to_string());
+        RawInputRecord {
+            r#type: r#type.to_string(),
+            client: client.to_string(),
+            tx: tx.to_string(),
+            amount,
+            currency: None,
+            timestamp: None,
         }
     }
 }
@@ -149,17 +834,202 @@ impl Payment {
 impl TryFrom<RawInputRecord> for Payment {
     type Error = DeserializationError;
     fn try_from(raw: RawInputRecord) -> Result<Payment, Self::Error> {
-        Ok(match raw.r#type.as_str() {
-            "deposit" => Payment::Deposit(raw.try_into()?),
-            "withdrawal" => Payment::Withdrawal(raw.try_into()?),
-            "dispute" => Payment::Dispute(raw.try_into()?),
-            "resolve" => Payment::Resolve(raw.try_into()?),
-            "chargeback" => Payment::Chargeback(raw.try_into()?),
-            _ => return Err(DeserializationError::InvalidType(raw.r#type)),
+        raw.into_payment(None, None)
+    }
+}
+
+/// Iterate over bincode encoded `Payment`s, written back to back
+///
+/// Unlike CSV, the records are decoded straight into `Payment`s, so there's
+/// no validation beyond what the types enforce. Ends at the end of `input`;
+/// one cut off in the middle of a record gives an error.
+#[cfg(feature = "bincode")]
+pub fn bincode_payments<R: Read>(input: R) -> impl Iterator<Item = bincode::Result<Payment>> {
+    let mut input = BufReader::new(input);
+    std::iter::from_fn(move || match input.fill_buf() {
+        Ok([]) => None,
+        Ok(_) => Some(bincode::deserialize_from(&mut input)),
+        Err(e) => Some(Err(e.into())),
+    })
+}
+
+/// `Payment` as a JSON object, eg.
+/// `{"type": "deposit", "client": 1, "tx": 1, "amount": 1.5}`
+///
+/// The amount is parsed the same way as in CSV, from a string (`"1.5"`)
+/// or a JSON number. Being internally tagged, the object is buffered by
+/// serde, which turns fractional numbers into `f64`s; they are read back
+/// from their shortest representation, which is the number as written for
+/// up to 15 significant digits. Longer amounts need to be strings.
+///
+/// An unknown type, or an amount given (or missing) where the type doesn't
+/// take one, makes it not a payment object at all, so it fails the
+/// deserialization; an invalid amount only fails the conversion.
+#[cfg(feature = "json")]
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub(crate) enum JsonPayment {
+    Deposit(JsonDetails),
+    Withdrawal(JsonDetails),
+    Dispute(JsonDetails<Option<JsonAmount>>),
+    Resolve(JsonDetails<Option<JsonAmount>>),
+    Chargeback(JsonDetails<Option<JsonAmount>>),
+    Reversal(JsonReference),
+}
+
+#[cfg(feature = "json")]
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct JsonDetails<A = JsonAmount> {
+    client: ClientID,
+    tx: TransactionID,
+    amount: A,
+}
+
+#[cfg(feature = "json")]
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct JsonReference {
+    client: ClientID,
+    tx: TransactionID,
+}
+
+/// Amount as written, to be parsed like a CSV one
+///
+/// Anything else than a string or a number is taken verbatim too,
+/// so it only fails the amount parsing.
+#[cfg(feature = "json")]
+pub(crate) struct JsonAmount(String);
+
+#[cfg(feature = "json")]
+impl<'de> Deserialize<'de> for JsonAmount {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct AmountVisitor;
+
+        impl<'de> serde::de::Visitor<'de> for AmountVisitor {
+            type Value = JsonAmount;
+
+            fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+                formatter.write_str("an amount")
+            }
+
+            fn visit_str<E: serde::de::Error>(self, amount: &str) -> Result<JsonAmount, E> {
+                Ok(JsonAmount(amount.to_string()))
+            }
+
+            fn visit_u64<E: serde::de::Error>(self, amount: u64) -> Result<JsonAmount, E> {
+                Ok(JsonAmount(amount.to_string()))
+            }
+
+            fn visit_i64<E: serde::de::Error>(self, amount: i64) -> Result<JsonAmount, E> {
+                Ok(JsonAmount(amount.to_string()))
+            }
+
+            fn visit_f64<E: serde::de::Error>(self, amount: f64) -> Result<JsonAmount, E> {
+                Ok(JsonAmount(amount.to_string()))
+            }
+
+            fn visit_bool<E: serde::de::Error>(self, amount: bool) -> Result<JsonAmount, E> {
+                Ok(JsonAmount(amount.to_string()))
+            }
+        }
+
+        deserializer.deserialize_any(AmountVisitor)
+    }
+}
+
+#[cfg(feature = "json")]
+impl JsonPayment {
+    /// Same as `RawInputRecord::into_payment`
+    pub(crate) fn into_payment(
+        self,
+        decimal_places: Option<usize>,
+        rounding: Option<RoundingMode>,
+    ) -> Result<Payment, DeserializationError> {
+        let amount = |amount: JsonAmount| parse_amount(&amount.0, decimal_places, rounding);
+        let dispute = |json: JsonDetails<Option<JsonAmount>>| -> Result<_, DeserializationError> {
+            Ok(DisputeDetails {
+                client: json.client,
+                tx: json.tx,
+                amount: json.amount.map(amount).transpose()?,
+            })
+        };
+        Ok(match self {
+            JsonPayment::Deposit(json) => Payment::Deposit(DepositDetails {
+                client: json.client,
+                tx: json.tx,
+                amount: amount(json.amount)?,
+            }),
+            JsonPayment::Withdrawal(json) => Payment::Withdrawal(WithdrawalDetails {
+                client: json.client,
+                tx: json.tx,
+                amount: amount(json.amount)?,
+            }),
+            JsonPayment::Dispute(json) => Payment::Dispute(dispute(json)?),
+            JsonPayment::Resolve(json) => Payment::Resolve(dispute(json)?),
+            JsonPayment::Chargeback(json) => Payment::Chargeback(dispute(json)?),
+            JsonPayment::Reversal(JsonReference { client, tx }) => {
+                Payment::Reversal(DisputeDetails {
+                    client,
+                    tx,
+                    amount: None,
+                })
+            }
         })
     }
 }
 
+/// Read a JSON array of payments (see `JsonPayment` for the format)
+///
+/// A malformed document, or any element that is not a payment object at
+/// all, fails the whole input; an invalid amount only fails its payment.
+/// `decimal_places` and `rounding` are as in `RawInputRecord::into_payment`.
+#[cfg(feature = "json")]
+pub fn json_payments<R: Read>(
+    input: R,
+    decimal_places: Option<usize>,
+    rounding: Option<RoundingMode>,
+) -> serde_json::Result<Vec<Result<Payment, DeserializationError>>> {
+    let payments: Vec<JsonPayment> = serde_json::from_reader(BufReader::new(input))?;
+    Ok(payments
+        .into_iter()
+        .map(|payment| payment.into_payment(decimal_places, rounding))
+        .collect())
+}
+
+/// Iterate over the payments of a `reader`, tagging invalid ones with their line
+///
+/// Line numbers are 1-based and count the header, so they match what
+/// an editor shows. Malformed CSV is reported as the outer `csv::Error`.
+pub fn located_payments<R: Read>(
+    reader: &mut csv::Reader<R>,
+) -> csv::Result<impl Iterator<Item = csv::Result<Result<Payment, LocatedError>>> + '_> {
+    let headers = reader.headers()?.clone();
+    Ok(reader.records().map(move |record| {
+        let record = record?;
+        let line = record.position().map_or(0, csv::Position::line);
+        let raw: RawInputRecord = record.deserialize(Some(&headers))?;
+        Ok(raw.try_into_located(line))
+    }))
+}
+
+/// Parse a single CSV line (without a header), eg. `deposit,1,1,1.0`
+///
+/// Same as in files, all four columns are required: `dispute,1,1,`.
+impl TryFrom<&str> for Payment {
+    type Error = LineParseError;
+    fn try_from(line: &str) -> Result<Payment, Self::Error> {
+        let raw: RawInputRecord = csv::ReaderBuilder::new()
+            .has_headers(false)
+            .trim(csv::Trim::All)
+            .from_reader(line.as_bytes())
+            .deserialize()
+            .next()
+            .ok_or(LineParseError::Empty)??;
+        Ok(raw.try_into()?)
+    }
+}
+
 #[test]
 fn test_payment_deserialization() -> anyhow::Result<()> {
     let input = r#"type,client,tx,amount
@@ -178,5 +1048,795 @@ chargeback,1,1,
         println!("{:?}", payment);
         let _payment: Payment = payment.try_into()?;
     }
+
+    // partial disputes take an amount, reversals don't
+    let dispute: Payment = "dispute,1,1,0.5".try_into()?;
+    assert_eq!(dispute.get_amount(), Some(Amount(5000)));
+    let reversal: Result<Payment, _> = "reversal,1,1,0.5".try_into();
+    assert!(reversal.is_err());
+    Ok(())
+}
+
+#[test]
+fn test_payment_raw_round_trip() -> anyhow::Result<()> {
+    let payments = vec![
+        Payment::Deposit(Deposit {
+            client: 1,
+            tx: 1,
+            amount: Amount(15000),
+        }),
+        Payment::Withdrawal(Withdrawal {
+            client: 1,
+            tx: 2,
+            amount: Amount(5000),
+        }),
+        Payment::Dispute(Dispute {
+            client: 1,
+            tx: 1,
+            amount: None,
+        }),
+        Payment::Resolve(Resolve {
+            client: 1,
+            tx: 1,
+            amount: Some(Amount(5000)),
+        }),
+        Payment::Chargeback(Chargeback {
+            client: 1,
+            tx: 1,
+            amount: None,
+        }),
+        Payment::Reversal(Reversal {
+            client: 1,
+            tx: 2,
+            amount: None,
+        }),
+    ];
+
+    let mut writer = csv::Writer::from_writer(vec![]);
+    for payment in &payments {
+        let round_tripped: Payment = payment.to_raw().try_into()?;
+        assert_eq!(&round_tripped, payment);
+        writer.serialize(payment.to_raw())?;
+    }
+    let output = writer.into_inner()?;
+
+    let mut reader = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .from_reader(output.as_slice());
+    for (payment, expected) in reader.deserialize().zip(&payments) {
+        let payment: RawInputRecord = payment?;
+        let payment: Payment = payment.try_into()?;
+        assert_eq!(&payment, expected);
+    }
+    Ok(())
+}
+
+#[test]
+fn test_empty_amount_is_absent() -> anyhow::Result<()> {
+    let input = r#"type,client,tx,amount
+deposit,1,1,
+dispute,1,1,
+resolve,1,1,  
+"#;
+
+    let mut reader = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .from_reader(input.as_bytes());
+    let records = reader
+        .deserialize()
+        .collect::<std::result::Result<Vec<RawInputRecord>, _>>()?;
+    assert!(records.iter().all(|record| record.amount.is_none()));
+
+    let deposit: Result<Payment, _> = records[0].clone().try_into();
+    assert!(matches!(deposit, Err(DeserializationError::MissingAmount)));
+
+    let dispute: Payment = records[1].clone().try_into()?;
+    assert_eq!(
+        dispute,
+        Payment::Dispute(Dispute {
+            client: 1,
+            tx: 1,
+            amount: None
+        })
+    );
+
+    let resolve: Payment = records[2].clone().try_into()?;
+    assert_eq!(
+        resolve,
+        Payment::Resolve(Resolve {
+            client: 1,
+            tx: 1,
+            amount: None
+        })
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_input_amount_is_exact() -> anyhow::Result<()> {
+    let mut reader = input_reader(
+        "type,client,tx,amount
+        deposit,1,1,1.00005
+        deposit,1,2,0.1
+        deposit,1,3,1e3"
+            .as_bytes(),
+    )?;
+    let records = reader
+        .deserialize()
+        .collect::<std::result::Result<Vec<RawInputRecord>, _>>()?;
+    assert_eq!(records[0].amount.as_deref(), Some("1.00005"));
+
+    // excess precision isn't truncated away by a float
+    let payments: Vec<Result<Payment, _>> = records.into_iter().map(TryInto::try_into).collect();
+    assert_eq!(
+        payments[0],
+        Err(DeserializationError::ExcessPrecision("1.00005".to_string()))
+    );
+    assert_eq!(
+        payments[1],
+        Ok(Payment::Deposit(Deposit {
+            client: 1,
+            tx: 2,
+            amount: Amount(1000),
+        }))
+    );
+    assert_eq!(
+        payments[2],
+        Err(DeserializationError::InvalidAmount("1e3".to_string()))
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_strict_and_rounded_precision() -> anyhow::Result<()> {
+    let deposit = |amount| {
+        Payment::Deposit(Deposit {
+            client: 1,
+            tx: 1,
+            amount: Amount(amount),
+        })
+    };
+    let raw = || RawInputRecord {
+        amount: Some("1.00005".to_string()),
+        ..deposit(0).to_raw()
+    };
+
+    assert_eq!(
+        Payment::try_from(raw()),
+        Err(DeserializationError::ExcessPrecision("1.00005".to_string()))
+    );
+    assert_eq!(
+        raw().into_payment_rounded(RoundingMode::HalfUp),
+        Ok(deposit(1_0001))
+    );
+    assert_eq!(
+        raw().into_payment_rounded(RoundingMode::Truncate),
+        Ok(deposit(1_0000))
+    );
+
+    // exact amounts are the same either way
+    assert_eq!(
+        Amount::from_decimal_str_rounded("1.0001", 4, RoundingMode::HalfUp)?,
+        Amount(1_0001)
+    );
+    assert_eq!(
+        Amount::from_decimal_str_rounded("0.99995", 4, RoundingMode::HalfUp)?,
+        Amount(1_0000)
+    );
+    assert_eq!(
+        Amount::from_decimal_str_rounded("1.5", 0, RoundingMode::HalfEven)?,
+        Amount(2)
+    );
+    // rounding doesn't make anything else valid
+    assert_eq!(
+        Amount::from_decimal_str_rounded("1e3", 4, RoundingMode::HalfUp),
+        Err(DeserializationError::InvalidAmount("1e3".to_string()))
+    );
+    assert_eq!(
+        Amount::from_decimal_str_rounded("1844674407370955.16159", 4, RoundingMode::HalfUp),
+        Err(DeserializationError::InvalidAmount(
+            "1844674407370955.16159".to_string()
+        ))
+    );
+    Ok(())
+}
+
+#[test]
+fn test_amount_rounding_modes() -> anyhow::Result<()> {
+    use RoundingMode::*;
+
+    let cases = [
+        // value, truncate, half up, half even
+        ("1.00005", 10000, 10001, 10000),
+        ("1.00015", 10001, 10002, 10002),
+        ("1.000051", 10000, 10001, 10001),
+        ("1.00004", 10000, 10000, 10000),
+        ("1.0001", 10001, 10001, 10001),
+        ("0.99995", 9999, 10000, 10000),
+        ("2.0", 20000, 20000, 20000),
+    ];
+
+    let rounded =
+        |value, mode| Amount::from_decimal_str_rounded(value, AMOUNT_DECIMAL_PLACES, mode);
+    for &(value, truncate, half_up, half_even) in &cases {
+        assert_eq!(rounded(value, Truncate)?, Amount(truncate));
+        assert_eq!(rounded(value, HalfUp)?, Amount(half_up));
+        assert_eq!(rounded(value, HalfEven)?, Amount(half_even));
+    }
+
+    assert!(rounded("-1.0", HalfUp).is_err());
+    assert!(rounded("NaN", HalfUp).is_err());
+    assert!(rounded("1000000000000000000000000000000", HalfUp).is_err());
+    Ok(())
+}
+
+#[test]
+fn test_invalid_client_and_tx() -> anyhow::Result<()> {
+    let input = r#"type,client,tx,amount
+deposit,1,4294967296,1.0
+dispute,abc,1,
+deposit,-1,1,1.0
+withdrawal,1,1.5,1.0
+"#;
+
+    let mut reader = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .from_reader(input.as_bytes());
+    let errors = reader
+        .deserialize()
+        .map(|record| {
+            let record: RawInputRecord = record.unwrap();
+            Payment::try_from(record).unwrap_err().to_string()
+        })
+        .collect::<Vec<_>>();
+
+    assert_eq!(
+        errors,
+        vec![
+            "invalid transaction value: 4294967296",
+            "invalid client value: abc",
+            "invalid client value: -1",
+            "invalid transaction value: 1.5",
+        ]
+    );
+    Ok(())
+}
+
+#[test]
+fn test_bom_and_crlf() -> anyhow::Result<()> {
+    let input = "\u{feff}type,client,tx,amount\r\ndeposit,1,1,1.0\r\ndispute,1,1,\r\n";
+
+    let payments = input_reader(input.as_bytes())?
+        .deserialize()
+        .map(|record| -> anyhow::Result<Payment> {
+            let record: RawInputRecord = record?;
+            Ok(record.try_into()?)
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    assert_eq!(
+        payments,
+        vec![
+            Payment::Deposit(Deposit {
+                client: 1,
+                tx: 1,
+                amount: Amount(10000)
+            }),
+            Payment::Dispute(Dispute {
+                client: 1,
+                tx: 1,
+                amount: None
+            }),
+        ]
+    );
+    Ok(())
+}
+
+#[test]
+fn test_payment_from_line() -> anyhow::Result<()> {
+    assert_eq!(
+        Payment::try_from("deposit,1,2,1.5")?,
+        Payment::Deposit(Deposit {
+            client: 1,
+            tx: 2,
+            amount: Amount(15000)
+        })
+    );
+    assert_eq!(
+        Payment::try_from(" withdrawal, 1, 3, 0.5 ")?,
+        Payment::Withdrawal(Withdrawal {
+            client: 1,
+            tx: 3,
+            amount: Amount(5000)
+        })
+    );
+    assert_eq!(
+        Payment::try_from("dispute,1,2,")?,
+        Payment::Dispute(Dispute {
+            client: 1,
+            tx: 2,
+            amount: None
+        })
+    );
+    assert_eq!(
+        Payment::try_from("resolve,1,2,")?,
+        Payment::Resolve(Resolve {
+            client: 1,
+            tx: 2,
+            amount: None
+        })
+    );
+    assert_eq!(
+        Payment::try_from("chargeback,1,2,")?,
+        Payment::Chargeback(Chargeback {
+            client: 1,
+            tx: 2,
+            amount: None
+        })
+    );
+
+    assert!(matches!(
+        Payment::try_from("deposit,1,2,"),
+        Err(LineParseError::Invalid(DeserializationError::MissingAmount))
+    ));
+    assert!(matches!(
+        Payment::try_from("resolve,1,2"),
+        Err(LineParseError::Malformed(_))
+    ));
+    assert!(matches!(Payment::try_from(""), Err(LineParseError::Empty)));
+    Ok(())
+}
+
+#[test]
+fn test_amount_checked_arithmetic() {
+    let max = Amount(u64::MAX);
+
+    assert_eq!(Amount(2).checked_add(Amount(3)), Some(Amount(5)));
+    assert_eq!(max.checked_add(Amount(0)), Some(max));
+    assert_eq!(max.checked_add(Amount(1)), None);
+    assert_eq!(Amount(u64::MAX - 1).checked_add(Amount(1)), Some(max));
+
+    assert_eq!(Amount(3).checked_sub(Amount(2)), Some(Amount(1)));
+    assert_eq!(Amount(3).checked_sub(Amount(3)), Some(Amount(0)));
+    assert_eq!(Amount(0).checked_sub(Amount(1)), None);
+    assert_eq!(max.checked_sub(max), Some(Amount(0)));
+
+    assert_eq!(Amount(2) + Amount(3), Amount(5));
+    assert_eq!(Amount(3) - Amount(2), Amount(1));
+    assert!(std::panic::catch_unwind(|| max + Amount(1)).is_err());
+    assert!(std::panic::catch_unwind(|| Amount(0) - Amount(1)).is_err());
+}
+
+#[test]
+fn test_amount_display_extremes() {
+    assert_eq!(Amount(u64::MAX).to_string(), "1844674407370955.1615");
+    assert_eq!(Amount(0).to_string(), "0.0000");
+    assert_eq!(Amount(1).to_string(), "0.0001");
+    assert_eq!(Amount(1_0000).to_string(), "1.0000");
+    assert_eq!(
+        Amount(u64::MAX).to_decimal_string(20),
+        "0.18446744073709551615"
+    );
+    assert_eq!(
+        Amount(u64::MAX).to_decimal_string(0),
+        "18446744073709551615"
+    );
+}
+
+#[test]
+fn test_amount_output_string() {
+    assert_eq!(Amount(1_5000).to_output_string(4), "1.5");
+    assert_eq!(Amount(2_0000).to_output_string(4), "2.0");
+    assert_eq!(Amount(0).to_output_string(4), "0.0");
+    assert_eq!(Amount(1).to_output_string(8), "0.00000001");
+    assert_eq!(Amount(15).to_output_string(0), "15.0");
+    assert_eq!(
+        Amount(u64::MAX).to_output_string(4),
+        "1844674407370955.1615"
+    );
+    assert_eq!(
+        Amount(90_071_992_547_409_930).to_output_string(4),
+        "9007199254740.993"
+    );
+}
+
+#[test]
+fn test_located_payments() -> anyhow::Result<()> {
+    let mut reader = input_reader(
+        "type,client,tx,amount
+        deposit,1,1,1.0
+        withdrawal,1,2,0.5
+        deposit,1,x,1.0
+        dispute,1,1,"
+            .as_bytes(),
+    )?;
+
+    let payments = located_payments(&mut reader)?.collect::<csv::Result<Vec<_>>>()?;
+    assert_eq!(payments.len(), 4);
+    assert!(payments[0].is_ok());
+    assert_eq!(
+        payments[2],
+        Err(LocatedError {
+            line: 4,
+            source: DeserializationError::InvalidTransaction("x".into()),
+        })
+    );
+    assert_eq!(
+        payments[2].as_ref().unwrap_err().to_string(),
+        "line 4: invalid transaction value: x"
+    );
+    assert!(payments[3].is_ok());
+
+    Ok(())
+}
+
+#[cfg(feature = "json")]
+#[test]
+fn test_json_payments() -> anyhow::Result<()> {
+    use crate::processor::{InMemoryProcessor, Processor};
+
+    let json = r#"[
+        {"type": "deposit", "client": 1, "tx": 1, "amount": 5.0},
+        {"type": "deposit", "client": 2, "tx": 2, "amount": 2.0},
+        {"type": "withdrawal", "client": 1, "tx": 3, "amount": 1.5},
+        {"type": "deposit", "client": 1, "tx": 4, "amount": -1.0},
+        {"type": "dispute", "client": 2, "tx": 2}
+    ]"#;
+    let payments = json_payments(json.as_bytes(), None, None)?;
+    assert_eq!(
+        payments[3],
+        Err(DeserializationError::InvalidAmount("-1".to_string()))
+    );
+    assert_eq!(
+        payments[4],
+        Ok(Payment::Dispute(Dispute {
+            client: 2,
+            tx: 2,
+            amount: None
+        }))
+    );
+
+    let mut processor = InMemoryProcessor::default();
+    for payment in payments.into_iter().filter_map(Result::ok) {
+        processor.process(payment)?;
+    }
+    let client_1 = processor.get_account(1).unwrap();
+    assert_eq!(*client_1.total_funds, 3_5000);
+    let client_2 = processor.get_account(2).unwrap();
+    assert_eq!(*client_2.held_funds, 2_0000);
+
+    // not a payment at all
+    assert!(json_payments(
+        r#"[{"type": "transfer", "client": 1, "tx": 1}]"#.as_bytes(),
+        None,
+        None
+    )
+    .is_err());
+    assert!(json_payments(
+        r#"[{"type": "reversal", "client": 1, "tx": 1, "amount": 1.0}]"#.as_bytes(),
+        None,
+        None
+    )
+    .is_err());
+    assert!(json_payments(
+        r#"[{"type": "deposit", "client": 1, "tx": 1}]"#.as_bytes(),
+        None,
+        None
+    )
+    .is_err());
+
+    // amounts are exact as strings, and as numbers of up to 15 digits
+    let json = r#"[
+        {"type": "deposit", "client": 1, "tx": 1, "amount": "1844674407370955.1615"},
+        {"type": "deposit", "client": 1, "tx": 2, "amount": 0.1},
+        {"type": "deposit", "client": 1, "tx": 3, "amount": 1.00005},
+        {"type": "deposit", "client": 1, "tx": 4, "amount": true},
+        {"type": "deposit", "client": 1, "tx": 5, "amount": 12345678901.2345},
+        {"type": "dispute", "client": 1, "tx": 5, "amount": 0.5}
+    ]"#;
+    let payments = json_payments(json.as_bytes(), None, None)?;
+    assert_eq!(
+        payments[0].as_ref().unwrap().get_amount(),
+        Some(Amount(u64::MAX))
+    );
+    assert_eq!(
+        payments[4].as_ref().unwrap().get_amount(),
+        Some(Amount(123456789012345))
+    );
+    assert_eq!(
+        payments[5],
+        Ok(Payment::Dispute(Dispute {
+            client: 1,
+            tx: 5,
+            amount: Some(Amount(5000))
+        }))
+    );
+    assert_eq!(
+        payments[1].as_ref().unwrap().get_amount(),
+        Some(Amount(1000))
+    );
+    assert_eq!(
+        payments[2],
+        Err(DeserializationError::ExcessPrecision("1.00005".to_string()))
+    );
+    assert!(payments[3].is_err());
+    let payments = json_payments(json.as_bytes(), None, Some(RoundingMode::HalfUp))?;
+    assert_eq!(
+        payments[2].as_ref().unwrap().get_amount(),
+        Some(Amount(1_0001))
+    );
+
+    Ok(())
+}
+
+#[cfg(feature = "bincode")]
+#[test]
+fn test_bincode_payments() -> anyhow::Result<()> {
+    use crate::processor::{accounts_eq, InMemoryProcessor, Processor};
+
+    let csv = "type,client,tx,amount
+        deposit,1,1,5.0
+        deposit,2,2,2.0
+        withdrawal,1,3,1.5
+        dispute,2,2,
+        chargeback,2,2,";
+
+    let mut encoded = vec![];
+    let mut from_csv = InMemoryProcessor::default();
+    for raw in input_reader(csv.as_bytes())?.deserialize::<RawInputRecord>() {
+        let payment: Payment = raw?.try_into()?;
+        bincode::serialize_into(&mut encoded, &payment)?;
+        from_csv.process(payment)?;
+    }
+
+    let mut from_bincode = InMemoryProcessor::default();
+    for payment in bincode_payments(encoded.as_slice()) {
+        from_bincode.process(payment?)?;
+    }
+    assert!(accounts_eq(&from_csv, &from_bincode));
+    assert_eq!(from_bincode.get_all_clients().count(), 2);
+
+    // truncated record
+    let mut decoded = bincode_payments(&encoded[..encoded.len() - 1]);
+    assert!(decoded.by_ref().take(4).all(|p| p.is_ok()));
+    assert!(decoded.next().unwrap().is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_payment_validate() {
+    let deposit = |amount| {
+        Payment::Deposit(Deposit {
+            client: 1,
+            tx: 1,
+            amount: Amount(amount),
+        })
+    };
+    assert_eq!(
+        deposit(0).validate(),
+        Err(DeserializationError::NonPositiveAmount)
+    );
+    assert_eq!(deposit(1).validate(), Ok(()));
+    assert_eq!(
+        Payment::Withdrawal(Withdrawal {
+            client: 1,
+            tx: 2,
+            amount: Amount(0),
+        })
+        .validate(),
+        Err(DeserializationError::NonPositiveAmount)
+    );
+    assert_eq!(
+        Payment::Dispute(Dispute {
+            client: 1,
+            tx: 1,
+            amount: None
+        })
+        .validate(),
+        Ok(())
+    );
+}
+
+#[test]
+fn test_amount_signed_sub() {
+    assert_eq!(
+        Amount(5_0000).signed_sub(Amount(2_0000)),
+        SignedAmount(3_0000)
+    );
+    assert_eq!(Amount(2_0000).signed_sub(Amount(2_0000)), SignedAmount(0));
+    assert_eq!(
+        Amount(2_0000).signed_sub(Amount(5_0000)),
+        SignedAmount(-3_0000)
+    );
+    assert_eq!(
+        Amount(0).signed_sub(Amount(u64::MAX)),
+        SignedAmount(-i128::from(u64::MAX))
+    );
+}
+
+#[test]
+fn test_amount_display() {
+    assert_eq!(Amount(1_5000).to_string(), "1.5000");
+    assert_eq!(Amount(1).to_string(), "0.0001");
+    assert_eq!(Amount(0).to_string(), "0.0000");
+    assert_eq!(Amount(123_4567).to_string(), "123.4567");
+}
+
+#[test]
+fn test_thousands_separator() -> anyhow::Result<()> {
+    let mut reader = input_reader(
+        "type,client,tx,amount
+        deposit,1,1,\"1,234.5600\"
+        deposit,1,2,1.5"
+            .as_bytes(),
+    )?;
+    let headers = reader.headers()?.clone();
+    let records = reader.records().collect::<csv::Result<Vec<_>>>()?;
+
+    let raw = RawInputRecord::from_record(&records[0], &headers, Some(','))?;
+    let deposit: DepositDetails = raw.try_into()?;
+    assert_eq!(deposit.amount, Amount(1234_5600));
+    let raw = RawInputRecord::from_record(&records[1], &headers, Some(','))?;
+    assert_eq!(raw.amount.as_deref(), Some("1.5"));
+
+    let raw = RawInputRecord::from_record(&records[0], &headers, None)?;
+    assert_eq!(
+        DepositDetails::try_from(raw),
+        Err(DeserializationError::InvalidAmount(
+            "1,234.5600".to_string()
+        ))
+    );
+    let raw = RawInputRecord::from_record(&records[1], &headers, None)?;
+    assert_eq!(raw.amount.as_deref(), Some("1.5"));
+
+    assert_eq!(
+        strip_thousands_sep("1,234,567.5", ','),
+        Some("1234567.5".to_string())
+    );
+    assert_eq!(
+        strip_thousands_sep("1234.5", ','),
+        Some("1234.5".to_string())
+    );
+    // malformed groupings are left for the amount parsing to reject
+    for amount in &[
+        "1,,2", ",5", "12,34.5", "1234,567", "1,234,", "1.234,5", "1,2345",
+    ] {
+        assert_eq!(strip_thousands_sep(amount, ','), None, "{}", amount);
+    }
+    let mut reader = input_reader(
+        "type,client,tx,amount
+        deposit,1,1,\"12,34.5\""
+            .as_bytes(),
+    )?;
+    let headers = reader.headers()?.clone();
+    let record = reader.records().next().unwrap()?;
+    let raw = RawInputRecord::from_record(&record, &headers, Some(','))?;
+    assert_eq!(
+        DepositDetails::try_from(raw),
+        Err(DeserializationError::InvalidAmount("12,34.5".to_string()))
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_amount_from_decimal_str() -> anyhow::Result<()> {
+    for (input, expected) in &[
+        ("1.2345", 1_2345),
+        ("1", 1_0000),
+        ("0.5", 5000),
+        ("007.10", 7_1000),
+        ("1844674407370955.1615", u64::MAX),
+    ] {
+        assert_eq!(Amount::from_decimal_str(input)?, Amount(*expected));
+    }
+
+    // excess precision
+    for input in &["1.23456", "0.00001", "1.00000"] {
+        assert_eq!(
+            Amount::from_decimal_str(input),
+            Err(DeserializationError::ExcessPrecision(input.to_string()))
+        );
+    }
+
+    // garbage
+    for input in &[
+        "",
+        "abc",
+        "-1.0",
+        "+1.0",
+        "1e3",
+        "1.",
+        ".5",
+        "1.2.3",
+        " 1.0",
+        "1,0",
+        "1844674407370955.1616",
+    ] {
+        assert_eq!(
+            Amount::from_decimal_str(input),
+            Err(DeserializationError::InvalidAmount(input.to_string()))
+        );
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_signed_amount_display() {
+    assert_eq!(SignedAmount(0).to_string(), "0.0000");
+    assert_eq!(SignedAmount(1_5000).to_string(), "1.5000");
+    assert_eq!(SignedAmount(-5).to_string(), "-0.0005");
+    assert_eq!(
+        SignedAmount(-i128::from(u64::MAX) * 2).to_string(),
+        "-3689348814741910.3230"
+    );
+}
+
+#[test]
+fn test_amount_from_str() {
+    assert_eq!("1000.0000".parse(), Ok(Amount(1000_0000)));
+    assert_eq!(
+        "abc".parse::<Amount>(),
+        Err(DeserializationError::InvalidAmount("abc".to_string()))
+    );
+    assert_eq!(
+        "-1.0".parse::<Amount>(),
+        Err(DeserializationError::InvalidAmount("-1.0".to_string()))
+    );
+}
+
+#[test]
+fn test_amount_decimal_places() -> anyhow::Result<()> {
+    assert_eq!(Amount::from_decimal_str_with_places("12", 0)?, Amount(12));
+    assert!(Amount::from_decimal_str_with_places("12.5", 0).is_err());
+    assert_eq!(
+        Amount::from_decimal_str_with_places("0.12345678", 8)?,
+        Amount(12_345_678)
+    );
+    assert!(Amount::from_decimal_str_with_places("0.123456789", 8).is_err());
+
+    assert_eq!(Amount(12).to_decimal_string(0), "12");
+    assert_eq!(Amount(12_345_678).to_decimal_string(8), "0.12345678");
+    assert_eq!(Amount(1_5000).to_string(), "1.5000");
+    Ok(())
+}
+
+#[test]
+fn test_timestamp_from_str() -> anyhow::Result<()> {
+    assert_eq!("1970-01-01T00:00:00Z".parse(), Ok(Timestamp(0)));
+    assert_eq!(
+        "2021-03-01T12:30:15.25Z".parse(),
+        Ok(Timestamp(1_614_601_815_250_000_000))
+    );
+    // same instant in another offset
+    assert_eq!(
+        "2021-03-01T13:30:15.25+01:00".parse::<Timestamp>()?,
+        "2021-03-01T12:30:15.25Z".parse()?
+    );
+    assert_eq!(
+        "1969-12-31T23:59:59Z".parse(),
+        Ok(Timestamp(-1_000_000_000))
+    );
+    assert!("2020-02-29T00:00:00Z".parse::<Timestamp>()? < "2020-03-01T00:00:00Z".parse()?);
+
+    for invalid in [
+        "2021-02-29T00:00:00Z",
+        "2021-13-01T00:00:00Z",
+        "2021-03-01T24:00:00Z",
+        "2021-03-01T12:00:00",
+        "2021-03-01T12:00:00+0100",
+        "2021-03-01T12:00:00.Z",
+        "2021-03-01",
+        "1614601815",
+    ] {
+        assert_eq!(
+            invalid.parse::<Timestamp>(),
+            Err(DeserializationError::InvalidTimestamp(invalid.to_string()))
+        );
+    }
     Ok(())
 }
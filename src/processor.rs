@@ -1,46 +1,555 @@
 use crate::payment::{
-    Amount, Chargeback, ClientID, Deposit, Dispute, Payment, Resolve, TransactionID, Withdrawal,
+    Amount, Chargeback, ClientID, Deposit, DeserializationError, Dispute, Payment, RawInputRecord,
+    RawOutputRecord, Resolve, Reversal, RoundingMode, SignedAmount, Timestamp, TransactionID,
+    Withdrawal, AMOUNT_DECIMAL_PLACES,
 };
 use fnv::{FnvHashMap, FnvHashSet};
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::convert::TryInto;
+use std::hash::Hash;
 use thiserror::Error;
 
-type Result<T> = std::result::Result<T, Error>;
+pub type Result<T> = std::result::Result<T, Error>;
 
+/// Reasons for rejecting a payment
+///
+/// New variants may be added in the future, so matches on it
+/// need a catch-all arm. Client and transaction ids are widened to
+/// `u64`, so they fit processors with any (unsigned integer) id types.
 #[derive(Error, Debug, PartialEq, Eq)]
+#[non_exhaustive]
 pub enum Error {
-    #[error("balance overflow in account")]
-    Overflow,
-    #[error("balance underflow in account")]
-    Underflow,
-    #[error("transaction already existst")]
-    TransactionAlreadyExists,
-    #[error("transaction not found")]
-    TransactionNotFound,
-    #[error("transaction not under dispute")]
-    TransactionNotDisputed,
-    #[error("transaction already under dispute")]
-    TransactionAlreadyDisputed,
-    #[error("wrong transaction type")]
-    WrongTransactionType,
-    #[error("account locked")]
-    AccountLocked,
+    #[error("balance overflow in account of client {client}")]
+    Overflow { client: u64 },
+    #[error("balance underflow in account of client {client}")]
+    Underflow { client: u64 },
+    #[error("transaction {tx} of client {client} already exists")]
+    TransactionAlreadyExists { client: u64, tx: u64 },
+    #[error("transaction {tx} of client {client} not found")]
+    TransactionNotFound { client: u64, tx: u64 },
+    #[error("transaction {tx} of client {client} not under dispute")]
+    TransactionNotDisputed { client: u64, tx: u64 },
+    #[error("transaction {tx} of client {client} already under dispute")]
+    TransactionAlreadyDisputed { client: u64, tx: u64 },
+    #[error("transaction {tx} of client {client} has the wrong type")]
+    WrongTransactionType { client: u64, tx: u64 },
+    #[error("account of client {client} locked")]
+    AccountLocked { client: u64 },
+    #[error("too many clients for an account of client {client}")]
+    TooManyClients { client: u64 },
+    #[error("transaction {tx} of client {client} would use funds protected by a past dispute")]
+    HeldFundsProtected { client: u64, tx: u64 },
+    #[error("transaction {tx} of client {client} already charged back")]
+    TransactionChargedBack { client: u64, tx: u64 },
+    #[error("dispute of transaction {tx} of client {client} exceeds the held funds limit")]
+    HoldLimitExceeded { client: u64, tx: u64 },
+    #[error("invalid record: {0}")]
+    InvalidRecord(#[from] DeserializationError),
+    #[error("malformed record: {0}")]
+    MalformedRecord(String),
+    #[error("unknown client {client}")]
+    UnknownClient { client: u64 },
+    #[error("unfreezing the account of client {client} not allowed")]
+    UnfreezeNotAllowed { client: u64 },
+    #[error("failed to write event log: {0}")]
+    LogWriteFailed(String),
+    #[error("amount of transaction {tx} of client {client} too large")]
+    AmountTooLarge { client: u64, tx: u64 },
+    #[error("transaction {tx} of client {client} already reversed")]
+    TransactionAlreadyReversed { client: u64, tx: u64 },
+    #[error("transaction {tx} of client {client} conflicts with an existing one")]
+    TransactionConflict { client: u64, tx: u64 },
+    #[error("transaction {tx} of client {client} has no currency")]
+    MissingCurrency { client: u64, tx: u64 },
+    #[error(
+        "can't hold {} of the disputed transaction {tx} of client {client}, only {} available",
+        .needed.to_decimal_string(*.decimal_places),
+        .available.to_decimal_string(*.decimal_places)
+    )]
+    DisputedFundsSpent {
+        client: u64,
+        tx: u64,
+        needed: Amount,
+        available: Amount,
+        /// of the amounts, for the message
+        decimal_places: usize,
+    },
+    #[error("dispute of transaction {tx} of client {client} already resolved")]
+    TransactionDisputeClosed { client: u64, tx: u64 },
+    #[error("client {client} exceeded the rate limit")]
+    RateLimited { client: u64 },
+    #[error("disputes of transaction {tx} of client {client} would exceed its amount")]
+    DisputeExceedsTransaction { client: u64, tx: u64 },
+    #[error("transaction {tx} of client {client} has no outstanding dispute of that amount")]
+    DisputedAmountNotFound { client: u64, tx: u64 },
+    #[error("adjustment of client {client} larger than its available funds")]
+    AdjustmentExceedsFunds { client: u64 },
+}
+
+/// Processor configuration
+///
+/// `Default` gives the permissive, unlimited behavior.
+#[derive(Debug, Default, Clone)]
+pub struct Config {
+    /// Maximum number of distinct clients; payments that would create
+    /// an account beyond it are rejected
+    pub max_clients: Option<usize>,
+    /// Once a deposit was disputed, don't allow withdrawing
+    /// the funds it would need to hold if disputed again
+    pub deny_withdrawal_overdraft_after_dispute: bool,
+    /// Maximum funds that can be held (under dispute) at once per account
+    pub max_held_per_account: Option<Amount>,
+    /// Allow unfreezing accounts locked due to a chargeback
+    pub allow_unfreeze_after_chargeback: bool,
+    /// Maximum amount of a single deposit or withdrawal
+    pub max_amount: Option<Amount>,
+    /// What to do with a deposit reusing an existing transaction id
+    pub duplicate_tx_policy: DuplicateTxPolicy,
+    /// Accept deposits into locked accounts (eg. to settle a chargeback);
+    /// withdrawals are rejected either way
+    pub deposits_allowed_when_locked: bool,
+    /// Lift a chargeback lock once a resolve settles the last open dispute
+    /// of the account; administrative freezes are kept
+    pub auto_unlock_on_clear: bool,
+    /// What to do with a resolve of a transaction that is not disputed
+    pub duplicate_resolve_policy: DuplicateResolvePolicy,
+    /// Decimal places of amounts, `AMOUNT_DECIMAL_PLACES`
+    /// if not set; see `amount_rounding` for input amounts with more
+    pub amount_decimal_places: Option<usize>,
+    /// How to round input amounts with more decimal places than supported;
+    /// if not set (the default) they are rejected with
+    /// `DeserializationError::ExcessPrecision`
+    pub amount_rounding: Option<RoundingMode>,
+    /// What to do with a deposit that would overflow the total funds
+    pub overflow_policy: OverflowPolicy,
+    /// Reject disputes of deposits whose earlier dispute was resolved,
+    /// with `Error::TransactionDisputeClosed`; by default a resolved
+    /// deposit can be disputed again
+    pub deny_redispute: bool,
+    /// Which past transactions can be disputed
+    pub dispute_policy: DisputePolicy,
+    /// Maximum number of deposits and withdrawals kept per account; beyond
+    /// it the oldest settled ones, that can't be disputed or reversed
+    /// anymore, are dropped: charged back and reversed ones, and resolved
+    /// deposits with `deny_redispute`. Transactions that could still be
+    /// disputed or reversed are always kept, even if that exceeds the
+    /// maximum. Only the ids of dropped transactions are remembered: using
+    /// them again is still rejected with `Error::TransactionAlreadyExists`,
+    /// disputing them fails with `Error::TransactionNotFound`.
+    pub max_history_per_account: Option<usize>,
+    /// Limit on the payments of each client, only enforced by `process_at`
+    pub rate_limit: Option<RateLimit>,
+    /// Reject payments of clients not added with `register_client`, with
+    /// `Error::UnknownClient`; by default the first deposit or withdrawal
+    /// of a client creates its account
+    pub require_preregistration: bool,
+}
+
+/// At most `max` accepted payments per client within any `window`
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct RateLimit {
+    pub max: usize,
+    pub window: std::time::Duration,
+}
+
+/// Which past transactions can be disputed
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum DisputePolicy {
+    /// Only deposits; disputing a withdrawal fails with
+    /// `Error::WrongTransactionType`
+    #[default]
+    DepositsOnly,
+    /// Withdrawals as well, chargeback-only: as the funds already left,
+    /// a dispute holds nothing and a resolve changes no balances, while
+    /// a chargeback credits the withdrawn amount back (without fees)
+    /// and locks the account
+    WithdrawalChargebacks,
+}
+
+/// Handling of deposits that would take the total funds of an account
+/// beyond what an `Amount` can hold
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Reject with `Error::Overflow`
+    #[default]
+    Error,
+    /// Deposit only what fits (up to `u64::MAX` units) and set
+    /// `AccountState::saturated`; meant for simulations
+    Saturate,
+}
+
+/// Handling of resolves of existing transactions that are not disputed
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum DuplicateResolvePolicy {
+    /// Reject with `Error::TransactionNotDisputed`
+    #[default]
+    Reject,
+    /// Treat it as a successful no-op (eg. a resolve replayed twice);
+    /// resolving an unknown transaction is still an error
+    Ignore,
+}
+
+/// Handling of deposits with an already used transaction id
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum DuplicateTxPolicy {
+    /// Reject with `Error::TransactionAlreadyExists`
+    #[default]
+    Reject,
+    /// Treat an identical re-sent deposit as a successful no-op;
+    /// one with a different amount fails with `Error::TransactionConflict`
+    Ignore,
 }
 
 /// Payment processor
 ///
-/// The API that an implementation of a payment processor provides
-pub trait Processor {
+/// The API that an implementation of a payment processor provides.
+/// Generic over the client (`C`) and transaction (`T`) id types,
+/// which default to the ones of the CSV input.
+pub trait Processor<C = ClientID, T = TransactionID> {
     /// Process a payment
-    fn process(&mut self, payment: Payment) -> Result<()>;
-    fn get_all_accounts(&self) -> Box<dyn Iterator<Item = (&ClientID, &AccountState)> + '_>;
-    fn get_all_clients(&self) -> Box<dyn Iterator<Item = &ClientID> + '_>;
-    fn get_account(&self, client_id: ClientID) -> Option<&AccountState>;
+    fn process(&mut self, payment: Payment<C, T>) -> Result<()>;
+    fn get_all_accounts(&self) -> Box<dyn Iterator<Item = (&C, &AccountState)> + '_>;
+    fn get_all_clients(&self) -> Box<dyn Iterator<Item = &C> + '_>;
+    fn get_account(&self, client_id: C) -> Option<&AccountState>;
+    /// Number of transactions (deposits and withdrawals) of a client
+    fn get_transaction_count(&self, client_id: C) -> Option<usize>;
+    /// Is the transaction currently under dispute
+    fn is_disputed(&self, client_id: C, tx: T) -> bool;
+
+    /// Decimal places of amounts, ie. `Amount(1)` is `10^-places`
+    fn amount_decimal_places(&self) -> usize {
+        AMOUNT_DECIMAL_PLACES
+    }
+
+    /// Number of accounts
+    fn account_count(&self) -> usize {
+        self.get_all_clients().count()
+    }
+
+    /// Number of transactions (deposits and withdrawals) of all clients
+    fn transaction_count(&self) -> usize
+    where
+        C: Copy,
+    {
+        self.get_all_clients()
+            .filter_map(|client_id| self.get_transaction_count(*client_id))
+            .sum()
+    }
+
+    /// All accounts in the given order
+    fn sorted_accounts(&self, sort_by: SortBy) -> Vec<(&C, &AccountState)>
+    where
+        C: Copy + Ord,
+    {
+        let mut accounts: Vec<_> = self.get_all_accounts().collect();
+        // client ids are unique, so the order is fully deterministic
+        match sort_by {
+            SortBy::Client => accounts.sort_by_key(|(client_id, _)| **client_id),
+            SortBy::Total => accounts.sort_by_key(|(client_id, account)| {
+                (std::cmp::Reverse(account.total_funds), **client_id)
+            }),
+            SortBy::Available => accounts.sort_by_key(|(client_id, account)| {
+                (std::cmp::Reverse(account.available_funds()), **client_id)
+            }),
+        }
+        accounts
+    }
+
+    /// Clients with more than `percent` % of their total funds held, ascending
+    fn held_alerts(&self, percent: u32) -> Vec<C>
+    where
+        C: Copy + Ord,
+    {
+        let mut clients: Vec<_> = self
+            .get_all_accounts()
+            .filter(|(_, account)| account.held_exceeds_percent(percent))
+            .map(|(client_id, _)| *client_id)
+            .collect();
+        clients.sort_unstable();
+        clients
+    }
+
+    /// Output records of all accounts, sorted by client
+    fn export_records(&self) -> Vec<RawOutputRecord<C>>
+    where
+        C: Copy + Ord,
+    {
+        self.sorted_accounts(SortBy::Client)
+            .into_iter()
+            .map(|(client_id, account)| {
+                account.to_output_record_with_places(*client_id, self.amount_decimal_places())
+            })
+            .collect()
+    }
+
+    /// Write all accounts as CSV output records
+    ///
+    /// Rows are serialized one by one, straight from the accounts;
+    /// only references are collected for sorting.
+    fn write_accounts<W: std::io::Write>(&self, w: W, options: &OutputOptions) -> csv::Result<()>
+    where
+        Self: Sized,
+        C: Copy + Ord + Serialize,
+    {
+        let mut writer = csv::Writer::from_writer(w);
+        for (client_id, account) in self.sorted_accounts(options.sort_by) {
+            if options.skip_zero && account.is_empty() {
+                continue;
+            }
+            let mut record =
+                account.to_output_record_with_places(*client_id, self.amount_decimal_places());
+            if options.with_counts {
+                record.transactions = self.get_transaction_count(*client_id);
+            }
+            if options.with_lock_reason {
+                record.set_lock_reason(account.lock_reason());
+            }
+            if options.integer_amounts {
+                writer.serialize(account.with_unit_amounts(record))?;
+            } else {
+                writer.serialize(record)?;
+            }
+        }
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Order of accounts in the output
+///
+/// Amounts sort in descending order; ties are always broken
+/// by client id (ascending).
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum SortBy {
+    #[default]
+    Client,
+    Total,
+    Available,
+}
+
+impl std::str::FromStr for SortBy {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        Ok(match s {
+            "client" => SortBy::Client,
+            "total" => SortBy::Total,
+            "available" => SortBy::Available,
+            _ => return Err(format!("invalid sort key: {}", s)),
+        })
+    }
+}
+
+/// What and how to output
+#[derive(Debug, Default, Clone)]
+pub struct OutputOptions {
+    pub sort_by: SortBy,
+    /// Include the number of transactions of each client
+    pub with_counts: bool,
+    /// Include why each account is locked (empty if it's not)
+    pub with_lock_reason: bool,
+    /// Output amounts as raw `Amount` units (eg. `15000` for `1.5`)
+    /// instead of decimal numbers
+    pub integer_amounts: bool,
+    /// Leave out accounts without any funds that are not locked
+    pub skip_zero: bool,
+}
+
+/// Check if two processors have the same accounts in the same state
+///
+/// Useful for snapshot tests and comparing different implementations.
+pub fn accounts_eq<C, T>(a: &dyn Processor<C, T>, b: &dyn Processor<C, T>) -> bool
+where
+    C: Ord + Copy + std::fmt::Display,
+{
+    processors_equivalent(a, b).is_ok()
+}
+
+/// First difference found between the accounts of two processors
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+#[error("client {client}: {field} differs ({left} vs {right})")]
+pub struct ProcessorDiff {
+    /// Formatted, so any type of client id fits
+    pub client: String,
+    /// `account` if only one of the processors has it
+    pub field: &'static str,
+    pub left: String,
+    pub right: String,
+}
+
+/// Compare the accounts of two processors, reporting the first difference
+///
+/// Clients are compared in ascending order, so the result is deterministic.
+pub fn processors_equivalent<C, T>(
+    a: &dyn Processor<C, T>,
+    b: &dyn Processor<C, T>,
+) -> std::result::Result<(), ProcessorDiff>
+where
+    C: Ord + Copy + std::fmt::Display,
+{
+    let places = (a.amount_decimal_places(), b.amount_decimal_places());
+    let a: std::collections::BTreeMap<_, _> = a.get_all_accounts().collect();
+    let b: std::collections::BTreeMap<_, _> = b.get_all_accounts().collect();
+    let clients: std::collections::BTreeSet<C> = a.keys().chain(b.keys()).map(|c| **c).collect();
+
+    for client in clients {
+        let diff = |field, left: String, right: String| ProcessorDiff {
+            client: client.to_string(),
+            field,
+            left,
+            right,
+        };
+        let (left, right) = match (a.get(&client), b.get(&client)) {
+            (Some(left), Some(right)) => (left, right),
+            (left, _) => {
+                let presence = |present: bool| if present { "present" } else { "missing" };
+                return Err(diff(
+                    "account",
+                    presence(left.is_some()).to_string(),
+                    presence(left.is_none()).to_string(),
+                ));
+            }
+        };
+
+        if left.status != right.status {
+            return Err(diff(
+                "status",
+                format!("{:?}", left.status),
+                format!("{:?}", right.status),
+            ));
+        }
+        let amounts = [
+            ("total", left.total_funds, right.total_funds),
+            ("held", left.held_funds, right.held_funds),
+            ("available", left.available_funds(), right.available_funds()),
+        ];
+        for (field, left, right) in amounts.iter() {
+            if left != right {
+                return Err(diff(
+                    field,
+                    left.to_decimal_string(places.0),
+                    right.to_decimal_string(places.1),
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Fee policy
+///
+/// Lets the user model fees applied to held funds. The default
+/// implementations leave the amounts unchanged.
+pub trait FeePolicy {
+    /// Total amount to deduct from the account when `amount` held
+    /// under a dispute gets charged back
+    ///
+    /// Any difference from `amount` is effectively a fee (or a rebate).
+    fn on_chargeback(&self, amount: Amount) -> Amount {
+        amount
+    }
+}
+
+/// Fee policy that doesn't charge any fees
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NoFees;
+
+impl FeePolicy for NoFees {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TransactionKind {
+    Deposit,
+    Withdrawal,
+}
+
+/// Where a past transaction is in its lifecycle
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TransactionStatus {
+    Normal,
+    /// Under a dispute
+    Disputed,
+    /// Its (last) dispute was resolved
+    Resolved,
+    /// Charged back; final
+    ChargedBack,
+    /// Reversed withdrawal; final
+    Reversed,
+}
+
+/// Accepted deposit or withdrawal
+#[derive(Debug, Clone, Serialize)]
+pub struct PastTransaction {
+    pub kind: TransactionKind,
+    pub amount: Amount,
+    pub status: TransactionStatus,
+}
+
+impl PastTransaction {
+    fn new(kind: TransactionKind, amount: Amount) -> Self {
+        Self {
+            kind,
+            amount,
+            status: TransactionStatus::Normal,
+        }
+    }
+}
+
+/// Administrative balance correction, made outside of the payment flow
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Adjustment {
+    pub delta: SignedAmount,
+    pub reason: String,
 }
 
-#[derive(Debug, Clone)]
-pub enum PastTransaction {
-    Deposit(Amount),
-    Withdrawal(Amount),
+/// Entry of the history of an account, see `get_history_entries`
+#[derive(Debug, Clone, Copy)]
+pub enum HistoryEntry<'a, T = TransactionID> {
+    Transaction(T, &'a PastTransaction),
+    Adjustment(&'a Adjustment),
+}
+
+/// What `undo_last` took back
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Undone<C = ClientID, T = TransactionID> {
+    Payment(Payment<C, T>),
+    Adjustment { client: C, adjustment: Adjustment },
+}
+
+impl<C: Copy, T> Undone<C, T> {
+    fn get_client_id(&self) -> C {
+        match self {
+            Undone::Payment(payment) => payment.get_client_id(),
+            Undone::Adjustment { client, .. } => *client,
+        }
+    }
+}
+
+/// Why an account got frozen
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+pub enum FreezeReason {
+    Chargeback,
+    /// Manual intervention
+    Administrative,
+}
+
+/// Lowercase, as in the `lock_reason` output column
+impl std::fmt::Display for FreezeReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            FreezeReason::Chargeback => "chargeback",
+            FreezeReason::Administrative => "administrative",
+        })
+    }
+}
+
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Serialize)]
+pub enum AccountStatus {
+    #[default]
+    Active,
+    Frozen(FreezeReason),
 }
 
 // State of the account
@@ -49,260 +558,1450 @@ pub enum PastTransaction {
 // more natural to attempt a given operation
 // and only if it was successful, mutate
 // state and other parts of the `Account`
-#[derive(Debug, Default, Clone)]
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize)]
 pub struct AccountState {
     // TODO: it remains unclear to me what exactly should be dissallowed after
     // account has been locked
-    pub locked: bool,
-    // not storing `available_funds` since it's a straight `total - held` right now
-    // but it could be added later if necessary (more cases than just held)
+    pub status: AccountStatus,
     pub total_funds: Amount,
     pub held_funds: Amount,
+    // always equal to `total - held`, but stored since it's read
+    // much more often than modified; every operation below
+    // has to keep it up to date
+    available_funds: Amount,
+    /// A deposit was cut short under `OverflowPolicy::Saturate`
+    pub saturated: bool,
 }
 
 impl AccountState {
     pub fn available_funds(&self) -> Amount {
-        Amount(*self.total_funds - *self.held_funds)
+        self.available_funds
+    }
+
+    /// Is the account frozen for any reason
+    pub fn locked(&self) -> bool {
+        self.status != AccountStatus::Active
+    }
+
+    /// Why the account is frozen, `None` if it's active
+    pub fn lock_reason(&self) -> Option<FreezeReason> {
+        match self.status {
+            AccountStatus::Active => None,
+            AccountStatus::Frozen(reason) => Some(reason),
+        }
+    }
+
+    /// Are all balances zero, with the account not locked
+    pub fn is_empty(&self) -> bool {
+        *self.total_funds == 0
+            && *self.held_funds == 0
+            && *self.available_funds == 0
+            && !self.locked()
+    }
+
+    /// Are more than `percent` % of the total funds held
+    pub fn held_exceeds_percent(&self, percent: u32) -> bool {
+        // exact integer math; can't overflow in `u128`
+        u128::from(*self.held_funds) * 100 > u128::from(*self.total_funds) * u128::from(percent)
+    }
+
+    pub fn to_output_record<C>(&self, client: C) -> RawOutputRecord<C> {
+        self.to_output_record_with_places(client, AMOUNT_DECIMAL_PLACES)
+    }
+
+    /// `record` of this account, with its amounts as raw `Amount` units
+    pub fn with_unit_amounts<C, A>(
+        &self,
+        record: RawOutputRecord<C, A>,
+    ) -> RawOutputRecord<C, u64> {
+        record.with_amounts(
+            self.available_funds().0,
+            self.held_funds.0,
+            self.total_funds.0,
+        )
+    }
+
+    /// Same as `to_output_record`, for amounts with the given decimal places
+    pub fn to_output_record_with_places<C>(
+        &self,
+        client: C,
+        decimal_places: usize,
+    ) -> RawOutputRecord<C> {
+        RawOutputRecord {
+            client,
+            available: self.available_funds().to_output_string(decimal_places),
+            held: self.held_funds.to_output_string(decimal_places),
+            total: self.total_funds.to_output_string(decimal_places),
+            locked: self.locked(),
+            transactions: None,
+            lock_reason: None,
+            currency: None,
+        }
     }
 
     #[must_use]
-    fn deposit(&self, amount: Amount) -> Result<Self> {
+    fn deposit(&self, client: u64, amount: Amount) -> Result<Self> {
         let mut new = self.clone();
 
-        *new.total_funds = new
+        new.total_funds = new
             .total_funds
-            .checked_add(*amount)
-            .ok_or_else(|| Error::Overflow)?;
+            .checked_add(amount)
+            .ok_or_else(|| Error::Overflow { client })?;
+
+        new.available_funds = new
+            .available_funds
+            .checked_add(amount)
+            .ok_or_else(|| Error::Overflow { client })?;
 
         Ok(new)
     }
 
+    // deposits as much of `amount` as fits, returning the
+    // new state along with the amount actually deposited
+    #[must_use]
+    fn deposit_saturating(&self, amount: Amount) -> (Self, Amount) {
+        let mut new = self.clone();
+
+        let deposited = Amount(amount.0.min(u64::MAX - new.total_funds.0));
+        // available funds are at most the total, so neither can overflow
+        new.total_funds = new.total_funds + deposited;
+        new.available_funds = new.available_funds + deposited;
+        if deposited < amount {
+            new.saturated = true;
+        }
+
+        (new, deposited)
+    }
+
     #[must_use]
-    fn withdraw(&self, amount: Amount) -> Result<Self> {
+    fn withdraw(&self, client: u64, amount: Amount) -> Result<Self> {
         let mut new = self.clone();
 
         // can't withraw funds that are not available
-        new.available_funds()
-            .checked_sub(*amount)
-            .ok_or_else(|| Error::Underflow)?;
+        new.available_funds = new
+            .available_funds
+            .checked_sub(amount)
+            .ok_or_else(|| Error::Underflow { client })?;
 
-        *new.total_funds = new
+        new.total_funds = new
             .total_funds
-            .checked_sub(*amount)
-            .ok_or_else(|| Error::Underflow)?;
+            .checked_sub(amount)
+            .ok_or_else(|| Error::Underflow { client })?;
 
         Ok(new)
     }
 
     #[must_use]
-    fn hold(&self, amount: Amount) -> Result<Self> {
+    fn hold(&self, client: u64, amount: Amount) -> Result<Self> {
         let mut new = self.clone();
 
         // can't hold funds that are not available
-        new.available_funds()
-            .checked_sub(*amount)
-            .ok_or_else(|| Error::Underflow)?;
+        new.available_funds = new
+            .available_funds
+            .checked_sub(amount)
+            .ok_or_else(|| Error::Underflow { client })?;
 
-        *new.held_funds = new
+        new.held_funds = new
             .held_funds
-            .checked_add(*amount)
-            .ok_or_else(|| Error::Overflow)?;
+            .checked_add(amount)
+            .ok_or_else(|| Error::Overflow { client })?;
 
         Ok(new)
     }
 
     // TODO: is unhold a really bad name?
     #[must_use]
-    fn unhold(&self, amount: Amount) -> Result<Self> {
+    fn unhold(&self, client: u64, amount: Amount) -> Result<Self> {
         let mut new = self.clone();
 
-        *new.held_funds = new
+        new.held_funds = new
             .held_funds
-            .checked_sub(*amount)
-            .ok_or_else(|| Error::Underflow)?;
+            .checked_sub(amount)
+            .ok_or_else(|| Error::Underflow { client })?;
+
+        new.available_funds = new
+            .available_funds
+            .checked_add(amount)
+            .ok_or_else(|| Error::Overflow { client })?;
 
         Ok(new)
     }
 
+    // `held` is released from held funds, while `charged` (`held` plus
+    // any fees) is removed from total funds
     #[must_use]
-    fn chargeback(&self, amount: Amount) -> Result<Self> {
+    fn chargeback(&self, client: u64, held: Amount, charged: Amount) -> Result<Self> {
         let mut new = self.clone();
 
-        *new.total_funds = new
+        new.total_funds = new
             .total_funds
-            .checked_sub(*amount)
-            .ok_or_else(|| Error::Underflow)?;
+            .checked_sub(charged)
+            .ok_or_else(|| Error::Underflow { client })?;
 
-        *new.held_funds = new
+        new.held_funds = new
             .held_funds
-            .checked_sub(*amount)
-            .ok_or_else(|| Error::Underflow)?;
+            .checked_sub(held)
+            .ok_or_else(|| Error::Underflow { client })?;
+
+        // fees can't be paid from funds that are still held
+        new.available_funds = new
+            .total_funds
+            .checked_sub(new.held_funds)
+            .ok_or_else(|| Error::Underflow { client })?;
+
+        new.status = AccountStatus::Frozen(FreezeReason::Chargeback);
 
-        new.locked = true;
+        Ok(new)
+    }
 
+    // credits a charged back withdrawal to the account
+    #[must_use]
+    fn chargeback_withdrawal(&self, client: u64, amount: Amount) -> Result<Self> {
+        let mut new = self.deposit(client, amount)?;
+        new.status = AccountStatus::Frozen(FreezeReason::Chargeback);
         Ok(new)
     }
 }
 
-impl Account {
-    fn get_past_deposit(&self, tx: TransactionID) -> Result<Amount> {
-        Ok(
-            match self
-                .history
-                .get(&tx)
-                .ok_or_else(|| Error::TransactionNotFound)?
-            {
-                PastTransaction::Deposit(details) => *details,
-                // seems like disputing withrawals is not supported?
-                PastTransaction::Withdrawal(_) => return Err(Error::WrongTransactionType),
-            },
-        )
+impl<T: Eq + Hash + Copy + Into<u64>> Account<T> {
+    // `client` owns the account, it's only needed for errors
+    fn get_past<C: Into<u64>>(
+        &self,
+        client: C,
+        tx: T,
+        kind: TransactionKind,
+    ) -> Result<&PastTransaction> {
+        let (client, tx_id) = (client.into(), tx.into());
+        let past_tx = self
+            .history
+            .get(&tx)
+            .ok_or_else(|| Error::TransactionNotFound { client, tx: tx_id })?;
+        if past_tx.kind != kind {
+            return Err(Error::WrongTransactionType { client, tx: tx_id });
+        }
+        Ok(past_tx)
+    }
+
+    fn get_past_deposit<C: Into<u64>>(&self, client: C, tx: T) -> Result<Amount> {
+        Ok(self.get_past(client, tx, TransactionKind::Deposit)?.amount)
+    }
+
+    fn get_past_withdrawal<C: Into<u64>>(&self, client: C, tx: T) -> Result<Amount> {
+        Ok(self
+            .get_past(client, tx, TransactionKind::Withdrawal)?
+            .amount)
+    }
+
+    // past transaction the dispute policy allows disputing
+    fn get_past_disputable<C: Into<u64>>(
+        &self,
+        client: C,
+        tx: T,
+        config: &Config,
+    ) -> Result<(TransactionKind, Amount)> {
+        let client = client.into();
+        let past_tx = match config.dispute_policy {
+            DisputePolicy::DepositsOnly => self.get_past(client, tx, TransactionKind::Deposit)?,
+            DisputePolicy::WithdrawalChargebacks => {
+                self.history
+                    .get(&tx)
+                    .ok_or_else(|| Error::TransactionNotFound {
+                        client,
+                        tx: tx.into(),
+                    })?
+            }
+        };
+        Ok((past_tx.kind, past_tx.amount))
+    }
+
+    // `tx` must be in the history
+    fn set_status(&mut self, tx: T, status: TransactionStatus) {
+        if let Some(past_tx) = self.history.get_mut(&tx) {
+            past_tx.status = status;
+        }
     }
 
-    fn deposit(&mut self, details: Deposit) -> Result<()> {
-        if self.state.locked {
-            return Err(Error::AccountLocked);
+    fn deposit<C: Into<u64>>(&mut self, details: Deposit<C, T>, config: &Config) -> Result<()> {
+        let (client, tx) = (details.client.into(), details.tx.into());
+        if self.state.locked() && !config.deposits_allowed_when_locked {
+            return Err(Error::AccountLocked { client });
         }
 
-        if self.history.contains_key(&details.tx) {
-            return Err(Error::TransactionAlreadyExists);
+        if self.evicted.contains(&details.tx) {
+            // nothing left to compare it with, so even an identical one is rejected
+            return Err(Error::TransactionAlreadyExists { client, tx });
         }
-        let new_state = self.state.deposit(details.amount)?;
+        if let Some(past_tx) = self.history.get(&details.tx) {
+            return match config.duplicate_tx_policy {
+                DuplicateTxPolicy::Reject => Err(Error::TransactionAlreadyExists { client, tx }),
+                DuplicateTxPolicy::Ignore => match past_tx {
+                    PastTransaction {
+                        kind: TransactionKind::Deposit,
+                        amount,
+                        ..
+                    } if *amount == details.amount => Ok(()),
+                    _ => Err(Error::TransactionConflict { client, tx }),
+                },
+            };
+        }
+        let (new_state, amount) = match config.overflow_policy {
+            OverflowPolicy::Error => (self.state.deposit(client, details.amount)?, details.amount),
+            OverflowPolicy::Saturate => self.state.deposit_saturating(details.amount),
+        };
         self.state = new_state;
-        self.history
-            .insert(details.tx, PastTransaction::Deposit(details.amount));
+        // what was actually deposited, so a dispute holds no more than that
+        self.add_history(
+            details.tx,
+            PastTransaction::new(TransactionKind::Deposit, amount),
+            config,
+        );
         Ok(())
     }
 
-    fn withdraw(&mut self, details: Withdrawal) -> Result<()> {
-        if self.state.locked {
-            return Err(Error::AccountLocked);
+    fn withdraw<C: Into<u64>>(&mut self, details: Withdrawal<C, T>, config: &Config) -> Result<()> {
+        let (client, tx) = (details.client.into(), details.tx.into());
+        if self.state.locked() {
+            return Err(Error::AccountLocked { client });
+        }
+
+        if self.history.contains_key(&details.tx) || self.evicted.contains(&details.tx) {
+            return Err(Error::TransactionAlreadyExists { client, tx });
         }
 
-        if self.history.contains_key(&details.tx) {
-            return Err(Error::TransactionAlreadyExists);
+        if config.deny_withdrawal_overdraft_after_dispute {
+            let remaining = self
+                .state
+                .total_funds
+                .checked_sub(details.amount)
+                .ok_or_else(|| Error::Underflow { client })?;
+            if remaining < self.protected_funds {
+                return Err(Error::HeldFundsProtected { client, tx });
+            }
         }
-        self.state = self.state.withdraw(details.amount)?;
-        self.history
-            .insert(details.tx, PastTransaction::Withdrawal(details.amount));
+
+        self.state = self.state.withdraw(client, details.amount)?;
+        self.add_history(
+            details.tx,
+            PastTransaction::new(TransactionKind::Withdrawal, details.amount),
+            config,
+        );
         Ok(())
     }
 
-    fn dispute(&mut self, details: Dispute) -> Result<()> {
-        let past_tx = self.get_past_deposit(details.tx)?;
-        if self.in_dispute.contains(&details.tx) {
-            return Err(Error::TransactionAlreadyDisputed);
+    // `tx` must not be in the history yet
+    fn add_history(&mut self, tx: T, past_tx: PastTransaction, config: &Config) {
+        if let Some(max_history) = config.max_history_per_account {
+            while max_history <= self.history.len() && self.evict_oldest(config) {}
+        }
+        self.history.insert(tx, past_tx);
+        self.history_order.push(HistoryKey::Transaction(tx));
+    }
+
+    // drops the oldest transaction that can't be disputed or reversed
+    // anymore; `false` if there is none
+    fn evict_oldest(&mut self, config: &Config) -> bool {
+        let settled = |tx: &T| {
+            let past_tx = &self.history[tx];
+            // a partial chargeback may leave other disputes of it outstanding
+            if self.in_dispute.contains_key(tx) {
+                return false;
+            }
+            match past_tx.status {
+                TransactionStatus::ChargedBack | TransactionStatus::Reversed => true,
+                // withdrawals can be reversed until they are either of the above
+                TransactionStatus::Resolved => {
+                    config.deny_redispute && past_tx.kind == TransactionKind::Deposit
+                }
+                TransactionStatus::Normal | TransactionStatus::Disputed => false,
+            }
+        };
+        let position = self
+            .history_order
+            .iter()
+            .position(|key| matches!(key, HistoryKey::Transaction(tx) if settled(tx)));
+        let tx = match position.map(|position| self.history_order.remove(position)) {
+            Some(HistoryKey::Transaction(tx)) => tx,
+            _ => return false,
+        };
+        let past_tx = self
+            .history
+            .remove(&tx)
+            .expect("history and its order differ");
+        self.evicted.insert(tx);
+        // it can't be disputed again, so there's nothing left to protect
+        // (a chargeback released the protection already)
+        if self.disputed.remove(&tx) && past_tx.status != TransactionStatus::ChargedBack {
+            self.protected_funds = self
+                .protected_funds
+                .checked_sub(past_tx.amount)
+                .unwrap_or_default();
+        }
+        true
+    }
+
+    // sum of the outstanding disputes of `tx`
+    fn disputed_amount(&self, tx: T) -> Amount {
+        self.in_dispute.get(&tx).map_or(Amount(0), |disputes| {
+            disputes.iter().fold(Amount(0), |sum, amount| sum + *amount)
+        })
+    }
+
+    // sum of the outstanding disputes of `tx` that `amount` names,
+    // all of them if not set
+    fn settled_amount(&self, client: u64, tx: T, amount: Option<Amount>) -> Result<Amount> {
+        let disputes = self
+            .in_dispute
+            .get(&tx)
+            .ok_or(Error::TransactionNotDisputed {
+                client,
+                tx: tx.into(),
+            })?;
+        match amount {
+            Some(amount) if disputes.contains(&amount) => Ok(amount),
+            Some(_) => Err(Error::DisputedAmountNotFound {
+                client,
+                tx: tx.into(),
+            }),
+            None => Ok(self.disputed_amount(tx)),
+        }
+    }
+
+    // drops the outstanding disputes `settled_amount` summed up
+    fn settle_disputes(&mut self, tx: T, amount: Option<Amount>) {
+        if let (Some(disputes), Some(amount)) = (self.in_dispute.get_mut(&tx), amount) {
+            if let Some(position) = disputes.iter().position(|disputed| *disputed == amount) {
+                disputes.swap_remove(position);
+            }
+            if !disputes.is_empty() {
+                return;
+            }
+        }
+        self.in_dispute.remove(&tx);
+    }
+
+    fn dispute<C: Into<u64>>(&mut self, details: Dispute<C, T>, config: &Config) -> Result<()> {
+        let (client, tx) = (details.client.into(), details.tx.into());
+        let (kind, past_tx) = self.get_past_disputable(client, details.tx, config)?;
+        // charged back transactions are final and can't be disputed again
+        match self.history[&details.tx].status {
+            TransactionStatus::ChargedBack => {
+                return Err(Error::TransactionChargedBack { client, tx })
+            }
+            TransactionStatus::Resolved if config.deny_redispute => {
+                return Err(Error::TransactionDisputeClosed { client, tx })
+            }
+            TransactionStatus::Reversed => {
+                return Err(Error::TransactionAlreadyReversed { client, tx })
+            }
+            _ => {}
+        }
+        // concurrent disputes can cover at most the whole transaction
+        let undisputed = past_tx
+            .checked_sub(self.disputed_amount(details.tx))
+            .unwrap_or_default();
+        if undisputed == Amount(0) && self.in_dispute.contains_key(&details.tx) {
+            return Err(Error::TransactionAlreadyDisputed { client, tx });
+        }
+        let amount = details.amount.unwrap_or(undisputed);
+        if undisputed < amount {
+            return Err(Error::DisputeExceedsTransaction { client, tx });
+        }
+        // the withdrawn funds already left, there's nothing to hold
+        if kind == TransactionKind::Withdrawal {
+            self.in_dispute.entry(details.tx).or_default().push(amount);
+            self.set_status(details.tx, TransactionStatus::Disputed);
+            return Ok(());
+        }
+        if let Some(max_held) = config.max_held_per_account {
+            match self.state.held_funds.checked_add(amount) {
+                Some(held) if held <= max_held => {}
+                _ => return Err(Error::HoldLimitExceeded { client, tx }),
+            }
+        }
+
+        let available = self.state.available_funds();
+        if available < amount {
+            return Err(Error::DisputedFundsSpent {
+                client,
+                tx,
+                needed: amount,
+                available,
+                decimal_places: config
+                    .amount_decimal_places
+                    .unwrap_or(AMOUNT_DECIMAL_PLACES),
+            });
         }
 
-        self.state = self.state.hold(past_tx)?;
-        self.in_dispute.insert(details.tx);
+        self.state = self.state.hold(client, amount)?;
+        self.in_dispute.entry(details.tx).or_default().push(amount);
+        self.set_status(details.tx, TransactionStatus::Disputed);
+        // protects the whole deposit, as it may be disputed as a whole again
+        if self.disputed.insert(details.tx) {
+            self.protected_funds = self
+                .protected_funds
+                .checked_add(past_tx)
+                .ok_or_else(|| Error::Overflow { client })?;
+        }
         Ok(())
     }
 
-    fn resolve(&mut self, details: Resolve) -> Result<()> {
-        let past_tx = self.get_past_deposit(details.tx)?;
-        if !self.in_dispute.contains(&details.tx) {
-            return Err(Error::TransactionNotDisputed);
+    fn resolve<C: Into<u64>>(&mut self, details: Resolve<C, T>, config: &Config) -> Result<()> {
+        let (client, tx) = (details.client.into(), details.tx.into());
+        let (kind, _) = self.get_past_disputable(client, details.tx, config)?;
+        if !self.in_dispute.contains_key(&details.tx) {
+            return match config.duplicate_resolve_policy {
+                DuplicateResolvePolicy::Reject => Err(Error::TransactionNotDisputed { client, tx }),
+                DuplicateResolvePolicy::Ignore => Ok(()),
+            };
+        }
+
+        let settled = self.settled_amount(client, details.tx, details.amount)?;
+        if kind == TransactionKind::Deposit {
+            self.state = self.state.unhold(client, settled)?;
         }
+        self.settle_disputes(details.tx, details.amount);
+        // a partial chargeback before keeps it charged back
+        if !self.in_dispute.contains_key(&details.tx)
+            && self.history[&details.tx].status == TransactionStatus::Disputed
+        {
+            self.set_status(details.tx, TransactionStatus::Resolved);
+        }
+        if config.auto_unlock_on_clear
+            && self.in_dispute.is_empty()
+            && self.state.status == AccountStatus::Frozen(FreezeReason::Chargeback)
+        {
+            self.state.status = AccountStatus::Active;
+        }
+        Ok(())
+    }
 
-        self.state = self.state.unhold(past_tx)?;
-        self.in_dispute.remove(&details.tx);
+    fn chargeback<C: Into<u64>>(
+        &mut self,
+        details: Chargeback<C, T>,
+        fee_policy: &dyn FeePolicy,
+        config: &Config,
+    ) -> Result<()> {
+        let client = details.client.into();
+        let (kind, past_tx) = self.get_past_disputable(client, details.tx, config)?;
+        let settled = self.settled_amount(client, details.tx, details.amount)?;
+
+        self.state = match kind {
+            TransactionKind::Deposit => {
+                self.state
+                    .chargeback(client, settled, fee_policy.on_chargeback(settled))?
+            }
+            TransactionKind::Withdrawal => self.state.chargeback_withdrawal(client, settled)?,
+        };
+        self.settle_disputes(details.tx, details.amount);
+        let charged_back_before =
+            self.history[&details.tx].status == TransactionStatus::ChargedBack;
+        self.set_status(details.tx, TransactionStatus::ChargedBack);
+        if kind == TransactionKind::Deposit && !charged_back_before {
+            // funds are gone, nothing left to protect
+            self.protected_funds = self
+                .protected_funds
+                .checked_sub(past_tx)
+                .unwrap_or_default();
+        }
         Ok(())
     }
 
-    fn chargeback(&mut self, details: Chargeback) -> Result<()> {
-        let past_tx = self.get_past_deposit(details.tx)?;
-        if !self.in_dispute.contains(&details.tx) {
-            return Err(Error::TransactionNotDisputed);
+    fn reverse<C: Into<u64>>(&mut self, details: Reversal<C, T>) -> Result<()> {
+        let (client, tx) = (details.client.into(), details.tx.into());
+        if self.state.locked() {
+            return Err(Error::AccountLocked { client });
+        }
+
+        let past_tx = self.get_past_withdrawal(client, details.tx)?;
+        // a disputed withdrawal may still be credited back by a chargeback
+        match self.history[&details.tx].status {
+            TransactionStatus::Reversed => {
+                return Err(Error::TransactionAlreadyReversed { client, tx })
+            }
+            TransactionStatus::Disputed => {
+                return Err(Error::TransactionAlreadyDisputed { client, tx })
+            }
+            TransactionStatus::ChargedBack => {
+                return Err(Error::TransactionChargedBack { client, tx })
+            }
+            _ => {}
         }
 
-        self.state = self.state.chargeback(past_tx)?;
-        self.in_dispute.remove(&details.tx);
+        self.state = self.state.deposit(client, past_tx)?;
+        self.set_status(details.tx, TransactionStatus::Reversed);
         Ok(())
     }
 }
 
-#[derive(Debug, Default, Clone)]
-struct Account {
+// hash sets and maps serialize sorted, so dumps don't depend on their order
+fn serialize_sorted_set<T, S>(
+    set: &FnvHashSet<T>,
+    serializer: S,
+) -> std::result::Result<S::Ok, S::Error>
+where
+    T: Serialize + Ord,
+    S: serde::Serializer,
+{
+    serializer.collect_seq(set.iter().collect::<std::collections::BTreeSet<_>>())
+}
+
+fn serialize_sorted_map<K, V, S>(
+    map: &FnvHashMap<K, V>,
+    serializer: S,
+) -> std::result::Result<S::Ok, S::Error>
+where
+    K: Serialize + Ord,
+    V: Serialize,
+    S: serde::Serializer,
+{
+    serializer.collect_map(map.iter().collect::<std::collections::BTreeMap<_, _>>())
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(bound(serialize = "T: Serialize + Ord"))]
+struct Account<T = TransactionID> {
     state: AccountState,
-    history: FnvHashMap<TransactionID, PastTransaction>,
-    in_dispute: FnvHashSet<TransactionID>,
+    #[serde(serialize_with = "serialize_sorted_map")]
+    history: FnvHashMap<T, PastTransaction>,
+    // keys of `history` and `adjustments`, in the order they were added
+    history_order: Vec<HistoryKey<T>>,
+    // settled transactions dropped from `history`, see `max_history_per_account`
+    #[serde(
+        serialize_with = "serialize_sorted_set",
+        skip_serializing_if = "FnvHashSet::is_empty"
+    )]
+    evicted: FnvHashSet<T>,
+    // tx -> amounts of its outstanding disputes, which sum up to at most
+    // the transaction; only transactions with some are in it
+    #[serde(serialize_with = "serialize_sorted_map")]
+    in_dispute: FnvHashMap<T, Vec<Amount>>,
+    // deposits that were ever disputed and the sum of their
+    // amounts (minus the charged back ones)
+    #[serde(serialize_with = "serialize_sorted_set")]
+    disputed: FnvHashSet<T>,
+    protected_funds: Amount,
+    // adjustments have no transaction id, so they are kept apart from
+    // the `history` (only ordered along with it) and can't be disputed
+    adjustments: Vec<Adjustment>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+enum HistoryKey<T> {
+    Transaction(T),
+    // index into `adjustments`, which are never dropped
+    Adjustment(usize),
+}
+
+// not derived, as that would require `T: Default`
+impl<T> Default for Account<T> {
+    fn default() -> Self {
+        Self {
+            state: Default::default(),
+            history: Default::default(),
+            history_order: Default::default(),
+            evicted: Default::default(),
+            in_dispute: Default::default(),
+            disputed: Default::default(),
+            protected_funds: Default::default(),
+            adjustments: Default::default(),
+        }
+    }
+}
+
+// accepted payment or adjustment along with what it changed, as it was before it
+struct UndoEntry<C, T> {
+    change: Undone<C, T>,
+    // `None` if the payment created the account
+    account: Option<Account<T>>,
+    funds_flow: FundsFlow,
+    // set by `process_at`, to take the payment out of the rate limit again
+    timestamp: Option<Timestamp>,
 }
 
 /**
  * Simple processor implementation that keeps track of everything in the memory.
+ *
+ * `new` creates one with the default id types; use `with_config`
+ * for others (eg. `InMemoryProcessor::<u64, u64>::with_config`).
  */
-#[derive(Default)]
-pub struct InMemoryProcessor {
-    accounts: FnvHashMap<ClientID, Account>,
+pub struct InMemoryProcessor<C = ClientID, T = TransactionID> {
+    accounts: FnvHashMap<C, Account<T>>,
+    config: Config,
+    fee_policy: Box<dyn FeePolicy + Send>,
+    undo_log: VecDeque<UndoEntry<C, T>>,
+    undo_depth: usize,
+    stats: ProcessorStats,
+    funds_flow: FundsFlow,
+    // times of the payments of each client within the `rate_limit` window
+    recent: FnvHashMap<C, VecDeque<Timestamp>>,
 }
 
-impl Processor for InMemoryProcessor {
-    fn process(&mut self, payment: Payment) -> Result<()> {
-        let account = self.accounts.entry(payment.get_client_id()).or_default();
-        match payment {
-            Payment::Deposit(details) => {
-                account.deposit(details)?;
-            }
-            Payment::Withdrawal(details) => {
-                account.withdraw(details)?;
-            }
-            Payment::Dispute(details) => {
-                account.dispute(details)?;
-            }
-            Payment::Resolve(details) => {
-                account.resolve(details)?;
-            }
-            Payment::Chargeback(details) => {
-                account.chargeback(details)?;
-            }
-        }
-        Ok(())
-    }
+/// Number of payments processed, by type
+///
+/// Counts all calls to `process`; undoing a payment doesn't change them.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ProcessorStats {
+    pub deposits: usize,
+    pub withdrawals: usize,
+    pub disputes: usize,
+    pub resolves: usize,
+    pub chargebacks: usize,
+    pub reversals: usize,
+    /// Payments that failed, by type
+    pub rejected: RejectedStats,
+}
 
-    fn get_all_accounts(&self) -> Box<dyn Iterator<Item = (&ClientID, &AccountState)> + '_> {
-        Box::new(
-            self.accounts
-                .iter()
-                .map(|(id, account)| (id, &account.state)),
-        )
+impl ProcessorStats {
+    fn accepted<C, T>(&mut self, payment: &Payment<C, T>) {
+        let counter = match payment {
+            Payment::Deposit(_) => &mut self.deposits,
+            Payment::Withdrawal(_) => &mut self.withdrawals,
+            Payment::Dispute(_) => &mut self.disputes,
+            Payment::Resolve(_) => &mut self.resolves,
+            Payment::Chargeback(_) => &mut self.chargebacks,
+            Payment::Reversal(_) => &mut self.reversals,
+        };
+        *counter += 1;
     }
+}
 
-    fn get_all_clients(&self) -> Box<dyn Iterator<Item = &ClientID> + '_> {
-        Box::new(self.accounts.keys())
+/// Number of payments that failed, by type
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct RejectedStats {
+    pub deposits: usize,
+    pub withdrawals: usize,
+    pub disputes: usize,
+    pub resolves: usize,
+    pub chargebacks: usize,
+    pub reversals: usize,
+}
+
+impl RejectedStats {
+    /// Payments of any type that failed
+    pub fn total(&self) -> usize {
+        self.deposits
+            + self.withdrawals
+            + self.disputes
+            + self.resolves
+            + self.chargebacks
+            + self.reversals
     }
 
-    fn get_account(&self, client_id: ClientID) -> Option<&AccountState> {
-        self.accounts.get(&client_id).map(|account| &account.state)
+    fn rejected<C, T>(&mut self, payment: &Payment<C, T>) {
+        let counter = match payment {
+            Payment::Deposit(_) => &mut self.deposits,
+            Payment::Withdrawal(_) => &mut self.withdrawals,
+            Payment::Dispute(_) => &mut self.disputes,
+            Payment::Resolve(_) => &mut self.resolves,
+            Payment::Chargeback(_) => &mut self.chargebacks,
+            Payment::Reversal(_) => &mut self.reversals,
+        };
+        *counter += 1;
     }
 }
 
-#[test]
-fn basic_happy_case() -> Result<()> {
-    let mut processor = InMemoryProcessor::default();
-    let client = 1;
+/// Funds moved in or out of the accounts, by what moved them
+///
+/// Tallied independently of the accounts, from the amounts of accepted
+/// payments, so `reconcile` can check the two agree.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct FundsFlow {
+    pub deposited: SignedAmount,
+    pub withdrawn: SignedAmount,
+    /// Including any chargeback fees; charged back withdrawals,
+    /// credited to the accounts, count negative
+    pub charged_back: SignedAmount,
+    pub reversed: SignedAmount,
+    /// Sum of `adjust_balance` deltas
+    pub adjusted: SignedAmount,
+}
 
-    processor.process(Payment::Deposit(Deposit {
-        client,
-        tx: 3,
-        amount: Amount(1),
-    }))?;
+impl FundsFlow {
+    /// Total funds the accounts should hold altogether
+    pub fn expected_total(&self) -> SignedAmount {
+        SignedAmount(
+            self.deposited.0 - self.withdrawn.0 - self.charged_back.0
+                + self.reversed.0
+                + self.adjusted.0,
+        )
+    }
+}
 
-    processor.process(Payment::Withdrawal(Withdrawal {
-        client,
-        tx: 4,
-        amount: Amount(1),
-    }))?;
+/// Expected vs actual total funds, see `InMemoryProcessor::reconcile`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Reconciliation {
+    pub expected: SignedAmount,
+    pub actual: SignedAmount,
+}
 
-    assert_eq!(*processor.get_account(client).unwrap().total_funds, 0);
-    assert_eq!(*processor.get_account(client).unwrap().available_funds(), 0);
+impl Reconciliation {
+    /// Funds created (positive) or destroyed (negative) unexpectedly;
+    /// always zero, unless there is a bug
+    pub fn discrepancy(&self) -> SignedAmount {
+        SignedAmount(self.actual.0 - self.expected.0)
+    }
+}
 
-    Ok(())
+impl Default for InMemoryProcessor {
+    fn default() -> Self {
+        Self::new(Config::default())
+    }
 }
 
-#[test]
-fn basic_multi_payment_math_checks_out() -> Result<()> {
-    let mut processor = InMemoryProcessor::default();
+impl InMemoryProcessor {
+    pub fn new(config: Config) -> Self {
+        Self::with_config(config)
+    }
+
+    /// Dump all accounts, with their dispute sets and history, as pretty JSON
+    ///
+    /// Meant for debugging; accounts are ordered by client id so dumps diff well.
+    #[cfg(feature = "json")]
+    pub fn to_json(&self) -> String {
+        struct Accounts<'a>(Vec<(&'a ClientID, &'a Account)>);
+
+        impl Serialize for Accounts<'_> {
+            fn serialize<S: serde::Serializer>(
+                &self,
+                serializer: S,
+            ) -> std::result::Result<S::Ok, S::Error> {
+                serializer.collect_map(self.0.iter().copied())
+            }
+        }
+
+        serde_json::to_string_pretty(&Accounts(self.accounts_by_client()))
+            .expect("accounts always serialize")
+    }
+
+    /// Process payments from CSV text (with a header)
+    ///
+    /// Convenient for tests and examples. Returns the index of
+    /// every rejected row along with its error.
+    pub fn process_str(&mut self, csv: &str) -> Vec<(usize, Error)> {
+        self.process_reader(csv.as_bytes())
+            .expect("reading from memory can't fail")
+    }
+
+    /// Same as `process_str`, for CSV from any reader
+    ///
+    /// Fails only if the start of the input can't be read; later read
+    /// errors are reported for their row, as `Error::MalformedRecord`.
+    pub fn process_reader<R: std::io::Read>(
+        &mut self,
+        input: R,
+    ) -> std::io::Result<Vec<(usize, Error)>> {
+        let mut reader = crate::payment::input_reader(input)?;
+
+        let mut errors = vec![];
+        for (i, payment) in reader.deserialize::<RawInputRecord>().enumerate() {
+            let result = payment
+                .map_err(|e| Error::MalformedRecord(e.to_string()))
+                .and_then(|payment| {
+                    Ok(payment.into_payment(
+                        self.config.amount_decimal_places,
+                        self.config.amount_rounding,
+                    )?)
+                })
+                .and_then(|payment| self.process(payment));
+            if let Err(e) = result {
+                errors.push((i, e));
+            }
+        }
+        Ok(errors)
+    }
+}
+
+impl<C, T> InMemoryProcessor<C, T>
+where
+    C: Eq + Hash + Copy + Into<u64>,
+    T: Eq + Hash + Copy + Into<u64>,
+{
+    /// Same as `new`, for any client and transaction id types
+    pub fn with_config(config: Config) -> Self {
+        Self {
+            accounts: Default::default(),
+            config,
+            fee_policy: Box::new(NoFees),
+            undo_log: Default::default(),
+            undo_depth: 0,
+            stats: Default::default(),
+            funds_flow: Default::default(),
+            recent: Default::default(),
+        }
+    }
+
+    pub fn with_fee_policy(mut self, fee_policy: impl FeePolicy + Send + 'static) -> Self {
+        self.fee_policy = Box::new(fee_policy);
+        self
+    }
+
+    /// Reserve room for the given number of clients up front
+    ///
+    /// Saves rehashing the accounts map while it grows when the number of
+    /// clients in the input is known in advance.
+    pub fn with_capacity(mut self, clients: usize) -> Self {
+        self.accounts.reserve(clients);
+        self
+    }
+
+    /// Create an empty account for a client, so its payments are accepted
+    /// with `Config::require_preregistration`
+    ///
+    /// Registering an existing client leaves its account as it is.
+    pub fn register_client(&mut self, client_id: C) -> Result<()> {
+        if let Some(max_clients) = self.config.max_clients {
+            if max_clients <= self.accounts.len() && !self.accounts.contains_key(&client_id) {
+                return Err(Error::TooManyClients {
+                    client: client_id.into(),
+                });
+            }
+        }
+        self.accounts.entry(client_id).or_default();
+        Ok(())
+    }
+
+    /// Forget all accounts (with their histories and disputes), the undo
+    /// log, stats and funds flow, as if freshly created
+    ///
+    /// The config and fee policy stay, as does the memory allocated for
+    /// the accounts, so it can be reused for another input.
+    pub fn clear(&mut self) {
+        self.accounts.clear();
+        self.undo_log.clear();
+        self.stats = Default::default();
+        self.funds_flow = Default::default();
+        self.recent.clear();
+    }
+
+    /// Process a payment made at `timestamp`, enforcing `Config::rate_limit`
+    ///
+    /// Payments beyond the limit fail with `Error::RateLimited`; only
+    /// accepted ones count towards it. Timestamps should be (roughly)
+    /// increasing, as the ones out of the window are dropped from the front.
+    pub fn process_at(&mut self, payment: Payment<C, T>, timestamp: Timestamp) -> Result<()> {
+        let limit = match self.config.rate_limit {
+            Some(limit) => limit,
+            None => return self.process(payment),
+        };
+        let client_id = payment.get_client_id();
+        let window: i128 = limit.window.as_nanos().try_into().unwrap_or(i128::MAX);
+        let start = timestamp.0.saturating_sub(window);
+        if let Some(recent) = self.recent.get_mut(&client_id) {
+            while recent.front().is_some_and(|time| time.0 <= start) {
+                recent.pop_front();
+            }
+            if limit.max <= recent.len() {
+                self.stats.rejected.rejected(&payment);
+                return Err(Error::RateLimited {
+                    client: client_id.into(),
+                });
+            }
+        }
+
+        self.process(payment)?;
+        self.recent
+            .entry(client_id)
+            .or_default()
+            .push_back(timestamp);
+        // the entry of the payment just accepted, if undo is enabled
+        if let Some(entry) = self.undo_log.back_mut() {
+            entry.timestamp = Some(timestamp);
+        }
+        Ok(())
+    }
+
+    /// Apply an administrative correction to the balance of a client
+    ///
+    /// Works on locked accounts as well. Only available funds can be
+    /// taken away, held ones stay for their disputes; a negative `delta`
+    /// larger than them fails with `Error::AdjustmentExceedsFunds`.
+    /// The adjustment goes into the history of the account, and can be
+    /// taken back with `undo_last` like a payment.
+    pub fn adjust_balance(
+        &mut self,
+        client_id: C,
+        delta: SignedAmount,
+        reason: String,
+    ) -> Result<()> {
+        let account = self
+            .accounts
+            .get_mut(&client_id)
+            .ok_or_else(|| Error::UnknownClient {
+                client: client_id.into(),
+            })?;
+        let client = client_id.into();
+        let amount = delta.0.unsigned_abs().try_into().map(Amount);
+        let state = if delta.0 < 0 {
+            amount
+                .ok()
+                .and_then(|amount| account.state.withdraw(client, amount).ok())
+                .ok_or(Error::AdjustmentExceedsFunds { client })?
+        } else {
+            account
+                .state
+                .deposit(client, amount.map_err(|_| Error::Overflow { client })?)?
+        };
+
+        let adjustment = Adjustment { delta, reason };
+        if 0 < self.undo_depth {
+            if self.undo_log.len() == self.undo_depth {
+                self.undo_log.pop_front();
+            }
+            self.undo_log.push_back(UndoEntry {
+                change: Undone::Adjustment {
+                    client: client_id,
+                    adjustment: adjustment.clone(),
+                },
+                account: Some(account.clone()),
+                funds_flow: self.funds_flow.clone(),
+                timestamp: None,
+            });
+        }
+        account.state = state;
+        account
+            .history_order
+            .push(HistoryKey::Adjustment(account.adjustments.len()));
+        account.adjustments.push(adjustment);
+        self.funds_flow.adjusted.0 += delta.0;
+        Ok(())
+    }
+
+    /// Adjustments made to the account of a client, oldest first
+    pub fn get_adjustments(&self, client_id: C) -> Option<&[Adjustment]> {
+        self.accounts
+            .get(&client_id)
+            .map(|account| account.adjustments.as_slice())
+    }
+
+    /// Deposits and withdrawals of a client, in the order they were accepted
+    pub fn get_history_ordered(&self, client_id: C) -> Option<Vec<(T, &PastTransaction)>> {
+        Some(
+            self.get_history_entries(client_id)?
+                .into_iter()
+                .filter_map(|entry| match entry {
+                    HistoryEntry::Transaction(tx, past_tx) => Some((tx, past_tx)),
+                    HistoryEntry::Adjustment(_) => None,
+                })
+                .collect(),
+        )
+    }
+
+    /// Deposits, withdrawals and adjustments of a client, in the order
+    /// they were made
+    pub fn get_history_entries(&self, client_id: C) -> Option<Vec<HistoryEntry<'_, T>>> {
+        let account = self.accounts.get(&client_id)?;
+        Some(
+            account
+                .history_order
+                .iter()
+                .map(|key| match key {
+                    HistoryKey::Transaction(tx) => {
+                        HistoryEntry::Transaction(*tx, &account.history[tx])
+                    }
+                    HistoryKey::Adjustment(index) => {
+                        HistoryEntry::Adjustment(&account.adjustments[*index])
+                    }
+                })
+                .collect(),
+        )
+    }
+
+    /// All accounts, ordered by client id
+    ///
+    /// Whatever emits whole accounts goes through this, so its
+    /// output never depends on the order of the hash map.
+    fn accounts_by_client(&self) -> Vec<(&C, &Account<T>)>
+    where
+        C: Ord,
+    {
+        let mut accounts: Vec<_> = self.accounts.iter().collect();
+        accounts.sort_unstable_by_key(|(client_id, _)| *client_id);
+        accounts
+    }
+
+    /// Sum of the funds held in all accounts
+    ///
+    /// On overflow, the error names the first client (by id)
+    /// whose held funds no longer fit in the sum.
+    pub fn total_held_funds(&self) -> Result<Amount>
+    where
+        C: Ord,
+    {
+        self.accounts_by_client()
+            .into_iter()
+            .try_fold(Amount(0), |sum, (client_id, account)| {
+                sum.checked_add(account.state.held_funds)
+                    .ok_or_else(|| Error::Overflow {
+                        client: (*client_id).into(),
+                    })
+            })
+    }
+
+    /// Sum of the total funds of all accounts
+    pub fn total_system_funds(&self) -> SignedAmount {
+        SignedAmount(
+            self.accounts
+                .values()
+                .map(|account| i128::from(account.state.total_funds.0))
+                .sum(),
+        )
+    }
+
+    /// Counters of accepted (by type) and rejected payments so far
+    pub fn stats(&self) -> ProcessorStats {
+        self.stats.clone()
+    }
+
+    /// Funds moved by accepted payments and adjustments so far
+    pub fn funds_flow(&self) -> FundsFlow {
+        self.funds_flow.clone()
+    }
+
+    /// Funds moved in or out of the account of a client, derived
+    /// from its history and adjustments
+    ///
+    /// Its `expected_total` is the total funds of the account, unless
+    /// part of the history was dropped (see `max_history_per_account`).
+    pub fn account_funds_flow(&self, client_id: C) -> Option<FundsFlow> {
+        let account = self.accounts.get(&client_id)?;
+        let mut flow = FundsFlow::default();
+        for past_tx in account.history.values() {
+            let amount = i128::from(past_tx.amount.0);
+            match (past_tx.kind, past_tx.status) {
+                (TransactionKind::Deposit, status) => {
+                    flow.deposited.0 += amount;
+                    if status == TransactionStatus::ChargedBack {
+                        flow.charged_back.0 +=
+                            i128::from(self.fee_policy.on_chargeback(past_tx.amount).0);
+                    }
+                }
+                (TransactionKind::Withdrawal, status) => {
+                    flow.withdrawn.0 += amount;
+                    match status {
+                        TransactionStatus::Reversed => flow.reversed.0 += amount,
+                        TransactionStatus::ChargedBack => flow.charged_back.0 -= amount,
+                        _ => {}
+                    }
+                }
+            }
+        }
+        for adjustment in &account.adjustments {
+            flow.adjusted.0 += adjustment.delta.0;
+        }
+        Some(flow)
+    }
+
+    /// Compare the total funds expected from the funds flow
+    /// with the actual total of all accounts
+    pub fn reconcile(&self) -> Reconciliation {
+        Reconciliation {
+            expected: self.funds_flow.expected_total(),
+            actual: self.total_system_funds(),
+        }
+    }
+
+    /// How input amounts with excess decimal places are rounded,
+    /// see `Config::amount_rounding`
+    pub fn amount_rounding(&self) -> Option<RoundingMode> {
+        self.config.amount_rounding
+    }
+
+    /// Enable `undo_last` for up to `depth` most recent payments
+    ///
+    /// Every accepted payment keeps a copy of the whole account
+    /// it touched, so this is meant for interactive use only.
+    pub fn with_undo(mut self, depth: usize) -> Self {
+        self.undo_depth = depth;
+        self
+    }
+}
+
+impl<C, T> InMemoryProcessor<C, T>
+where
+    C: Eq + Hash + Copy + Into<u64>,
+    T: Eq + Hash + Copy + Into<u64>,
+{
+    fn apply(&mut self, payment: Payment<C, T>) -> Result<()> {
+        let client_id = payment.get_client_id();
+        if let (Some(max_amount), Some(amount)) = (self.config.max_amount, payment.get_amount()) {
+            if max_amount < amount {
+                return Err(Error::AmountTooLarge {
+                    client: client_id.into(),
+                    tx: payment.get_tx().into(),
+                });
+            }
+        }
+        if self.config.require_preregistration && !self.accounts.contains_key(&client_id) {
+            return Err(Error::UnknownClient {
+                client: client_id.into(),
+            });
+        }
+        if let Some(max_clients) = self.config.max_clients {
+            if max_clients <= self.accounts.len() && !self.accounts.contains_key(&client_id) {
+                return Err(Error::TooManyClients {
+                    client: client_id.into(),
+                });
+            }
+        }
+
+        let undo_entry = if 0 < self.undo_depth {
+            Some(UndoEntry {
+                change: Undone::Payment(payment.clone()),
+                account: self.accounts.get(&client_id).cloned(),
+                funds_flow: self.funds_flow.clone(),
+                timestamp: None,
+            })
+        } else {
+            None
+        };
+
+        let account = match &payment {
+            Payment::Deposit(_) | Payment::Withdrawal(_) => {
+                self.accounts.entry(client_id).or_default()
+            }
+            // the other types refer to a past deposit or withdrawal,
+            // there's none without an account
+            Payment::Dispute(details)
+            | Payment::Resolve(details)
+            | Payment::Chargeback(details)
+            | Payment::Reversal(details) => {
+                self.accounts
+                    .get_mut(&client_id)
+                    .ok_or_else(|| Error::TransactionNotFound {
+                        client: client_id.into(),
+                        tx: details.tx.into(),
+                    })?
+            }
+        };
+        let flow = &mut self.funds_flow;
+        match payment {
+            Payment::Deposit(details) => {
+                // ignored duplicates don't move any funds
+                let duplicate = account.history.contains_key(&details.tx);
+                let tx = details.tx;
+                account.deposit(details, &self.config)?;
+                if !duplicate {
+                    // may be less than the deposit, if saturated
+                    flow.deposited.0 += i128::from(account.get_past_deposit(client_id, tx)?.0);
+                }
+            }
+            Payment::Withdrawal(details) => {
+                let amount = details.amount;
+                account.withdraw(details, &self.config)?;
+                flow.withdrawn.0 += i128::from(amount.0);
+            }
+            Payment::Dispute(details) => {
+                account.dispute(details, &self.config)?;
+            }
+            Payment::Resolve(details) => {
+                account.resolve(details, &self.config)?;
+            }
+            Payment::Chargeback(details) => {
+                let tx = details.tx;
+                account.chargeback(details, &*self.fee_policy, &self.config)?;
+                let past_tx = &account.history[&tx];
+                match past_tx.kind {
+                    TransactionKind::Deposit => {
+                        let charged = self.fee_policy.on_chargeback(past_tx.amount);
+                        flow.charged_back.0 += i128::from(charged.0);
+                    }
+                    TransactionKind::Withdrawal => {
+                        flow.charged_back.0 -= i128::from(past_tx.amount.0)
+                    }
+                }
+            }
+            Payment::Reversal(details) => {
+                let tx = details.tx;
+                account.reverse(details)?;
+                flow.reversed.0 += i128::from(account.get_past_withdrawal(client_id, tx)?.0);
+            }
+        }
+
+        if let Some(undo_entry) = undo_entry {
+            if self.undo_log.len() == self.undo_depth {
+                self.undo_log.pop_front();
+            }
+            self.undo_log.push_back(undo_entry);
+        }
+        Ok(())
+    }
+
+    /// Freeze an account, rejecting any further deposits and withdrawals
+    ///
+    /// Freezing an already frozen account keeps the original reason.
+    pub fn freeze_account(&mut self, client_id: C, reason: FreezeReason) -> Result<()> {
+        let account = self
+            .accounts
+            .get_mut(&client_id)
+            .ok_or_else(|| Error::UnknownClient {
+                client: client_id.into(),
+            })?;
+        if !account.state.locked() {
+            account.state.status = AccountStatus::Frozen(reason);
+        }
+        self.undo_log.clear();
+        Ok(())
+    }
+
+    /// Make a frozen account active again
+    pub fn unfreeze_account(&mut self, client_id: C) -> Result<()> {
+        let account = self
+            .accounts
+            .get_mut(&client_id)
+            .ok_or_else(|| Error::UnknownClient {
+                client: client_id.into(),
+            })?;
+        // chargeback locks are permanent unless the policy says otherwise
+        if account.state.status == AccountStatus::Frozen(FreezeReason::Chargeback)
+            && !self.config.allow_unfreeze_after_chargeback
+        {
+            return Err(Error::UnfreezeNotAllowed {
+                client: client_id.into(),
+            });
+        }
+        account.state.status = AccountStatus::Active;
+        self.undo_log.clear();
+        Ok(())
+    }
+
+    /// Undo the most recently accepted payment or balance adjustment
+    ///
+    /// Returns what was undone, or `None` if there is nothing
+    /// to undo (or undoing is not enabled, see `with_undo`).
+    ///
+    /// Freezing and unfreezing (`freeze_account`, `unfreeze_account`)
+    /// can't be undone, so they clear the undo log: undoing a payment
+    /// from before one of them would revert it too.
+    pub fn undo_last(&mut self) -> Option<Undone<C, T>> {
+        let entry = self.undo_log.pop_back()?;
+        self.funds_flow = entry.funds_flow;
+        let client_id = entry.change.get_client_id();
+        match entry.account {
+            Some(account) => {
+                self.accounts.insert(client_id, account);
+            }
+            None => {
+                self.accounts.remove(&client_id);
+            }
+        }
+        if let (Some(timestamp), Some(recent)) = (entry.timestamp, self.recent.get_mut(&client_id))
+        {
+            if recent.back() == Some(&timestamp) {
+                recent.pop_back();
+            }
+        }
+        Some(entry.change)
+    }
+}
+
+impl<C, T> Processor<C, T> for InMemoryProcessor<C, T>
+where
+    C: Eq + Hash + Copy + Into<u64>,
+    T: Eq + Hash + Copy + Into<u64>,
+{
+    fn process(&mut self, payment: Payment<C, T>) -> Result<()> {
+        let result = self.apply(payment.clone());
+        match result {
+            Ok(()) => self.stats.accepted(&payment),
+            Err(_) => self.stats.rejected.rejected(&payment),
+        }
+        result
+    }
+
+    fn get_all_accounts(&self) -> Box<dyn Iterator<Item = (&C, &AccountState)> + '_> {
+        Box::new(
+            self.accounts
+                .iter()
+                .map(|(id, account)| (id, &account.state)),
+        )
+    }
+
+    fn get_all_clients(&self) -> Box<dyn Iterator<Item = &C> + '_> {
+        Box::new(self.accounts.keys())
+    }
+
+    fn get_account(&self, client_id: C) -> Option<&AccountState> {
+        self.accounts.get(&client_id).map(|account| &account.state)
+    }
+
+    fn get_transaction_count(&self, client_id: C) -> Option<usize> {
+        self.accounts
+            .get(&client_id)
+            .map(|account| account.history.len())
+    }
+
+    fn sorted_accounts(&self, sort_by: SortBy) -> Vec<(&C, &AccountState)>
+    where
+        C: Copy + Ord,
+    {
+        let mut accounts: Vec<_> = self
+            .accounts_by_client()
+            .into_iter()
+            .map(|(client_id, account)| (client_id, &account.state))
+            .collect();
+        // stable, so ties stay ordered by client id
+        match sort_by {
+            SortBy::Client => {}
+            SortBy::Total => {
+                accounts.sort_by_key(|(_, account)| std::cmp::Reverse(account.total_funds))
+            }
+            SortBy::Available => {
+                accounts.sort_by_key(|(_, account)| std::cmp::Reverse(account.available_funds()))
+            }
+        }
+        accounts
+    }
+
+    fn amount_decimal_places(&self) -> usize {
+        self.config
+            .amount_decimal_places
+            .unwrap_or(AMOUNT_DECIMAL_PLACES)
+    }
+
+    fn account_count(&self) -> usize {
+        self.accounts.len()
+    }
+
+    fn transaction_count(&self) -> usize {
+        self.accounts
+            .values()
+            .map(|account| account.history.len())
+            .sum()
+    }
+
+    fn is_disputed(&self, client_id: C, tx: T) -> bool {
+        self.accounts
+            .get(&client_id)
+            .is_some_and(|account| account.in_dispute.contains_key(&tx))
+    }
+}
+
+#[test]
+fn basic_happy_case() -> Result<()> {
+    let mut processor = InMemoryProcessor::default();
+    let client = 1;
+
+    processor.process(Payment::Deposit(Deposit {
+        client,
+        tx: 3,
+        amount: Amount(1),
+    }))?;
+
+    processor.process(Payment::Withdrawal(Withdrawal {
+        client,
+        tx: 4,
+        amount: Amount(1),
+    }))?;
+
+    assert_eq!(*processor.get_account(client).unwrap().total_funds, 0);
+    assert_eq!(*processor.get_account(client).unwrap().available_funds(), 0);
+
+    Ok(())
+}
+
+#[test]
+fn basic_multi_payment_math_checks_out() -> Result<()> {
+    let mut processor = InMemoryProcessor::default();
     let client = 3;
 
     processor
@@ -371,7 +2070,11 @@ fn funds_on_hold_math_and_basic_flow() -> Result<()> {
     assert_eq!(*processor.get_account(client).unwrap().held_funds, 0);
     assert_eq!(*processor.get_account(client).unwrap().available_funds(), 8);
 
-    processor.process(Payment::Dispute(Dispute { client, tx: 3 }))?;
+    processor.process(Payment::Dispute(Dispute {
+        client,
+        tx: 3,
+        amount: None,
+    }))?;
 
     assert_eq!(*processor.get_account(client).unwrap().total_funds, 8);
     assert_eq!(*processor.get_account(client).unwrap().held_funds, 7);
@@ -392,32 +2095,65 @@ fn funds_on_hold_math_and_basic_flow() -> Result<()> {
 
     // can't dispute same tx twice
     assert_eq!(
-        processor.process(Payment::Dispute(Dispute { client, tx: 3 })),
-        Err(Error::TransactionAlreadyDisputed)
+        processor.process(Payment::Dispute(Dispute {
+            client,
+            tx: 3,
+            amount: None
+        })),
+        Err(Error::TransactionAlreadyDisputed {
+            client: client.into(),
+            tx: 3
+        })
     );
 
     // can't resolve wrong tx
     assert_eq!(
-        processor.process(Payment::Resolve(Resolve { client, tx: 500 })),
-        Err(Error::TransactionNotFound)
+        processor.process(Payment::Resolve(Resolve {
+            client,
+            tx: 500,
+            amount: None
+        })),
+        Err(Error::TransactionNotFound {
+            client: client.into(),
+            tx: 500
+        })
     );
 
     // can't resolve tx not under dispute
     assert_eq!(
-        processor.process(Payment::Resolve(Resolve { client, tx: 4 })),
-        Err(Error::TransactionNotDisputed)
+        processor.process(Payment::Resolve(Resolve {
+            client,
+            tx: 4,
+            amount: None
+        })),
+        Err(Error::TransactionNotDisputed {
+            client: client.into(),
+            tx: 4
+        })
     );
 
     // resolve dispute now
-    processor.process(Payment::Resolve(Resolve { client, tx: 3 }))?;
+    processor.process(Payment::Resolve(Resolve {
+        client,
+        tx: 3,
+        amount: None,
+    }))?;
 
     assert_eq!(*processor.get_account(client).unwrap().total_funds, 7);
     assert_eq!(*processor.get_account(client).unwrap().available_funds(), 7);
     assert_eq!(*processor.get_account(client).unwrap().held_funds, 0);
 
-    // can dispute this tx again (?)
-    processor.process(Payment::Dispute(Dispute { client, tx: 3 }))?;
-    processor.process(Payment::Resolve(Resolve { client, tx: 3 }))?;
+    // can dispute this tx again, unless `deny_redispute` is set
+    processor.process(Payment::Dispute(Dispute {
+        client,
+        tx: 3,
+        amount: None,
+    }))?;
+    processor.process(Payment::Resolve(Resolve {
+        client,
+        tx: 3,
+        amount: None,
+    }))?;
 
     processor
         .process(Payment::Withdrawal(Withdrawal {
@@ -433,8 +2169,18 @@ fn funds_on_hold_math_and_basic_flow() -> Result<()> {
 
     // trying to dispute this tx again would cause a negative balance
     assert_eq!(
-        processor.process(Payment::Dispute(Dispute { client, tx: 3 })),
-        Err(Error::Underflow)
+        processor.process(Payment::Dispute(Dispute {
+            client,
+            tx: 3,
+            amount: None
+        })),
+        Err(Error::DisputedFundsSpent {
+            client: client.into(),
+            tx: 3,
+            needed: Amount(7),
+            available: Amount(0),
+            decimal_places: AMOUNT_DECIMAL_PLACES,
+        })
     );
 
     assert_eq!(*processor.get_account(client).unwrap().total_funds, 0);
@@ -463,7 +2209,9 @@ fn withdrawal_underflow() -> Result<()> {
             tx: 4,
             amount: Amount(2),
         })),
-        Err(Error::Underflow)
+        Err(Error::Underflow {
+            client: client.into()
+        })
     );
 
     Ok(())
@@ -475,69 +2223,2309 @@ fn dispute_unknown_tx() -> Result<()> {
     let client = 3;
 
     assert_eq!(
-        processor.process(Payment::Dispute(Dispute { client, tx: 4 })),
-        Err(Error::TransactionNotFound)
+        processor.process(Payment::Dispute(Dispute {
+            client,
+            tx: 4,
+            amount: None
+        })),
+        Err(Error::TransactionNotFound {
+            client: client.into(),
+            tx: 4
+        })
     );
 
     Ok(())
 }
 
+#[test]
+fn error_messages_name_client_and_tx() {
+    assert_eq!(
+        Error::TransactionNotFound { client: 3, tx: 500 }.to_string(),
+        "transaction 500 of client 3 not found"
+    );
+    assert_eq!(
+        Error::AccountLocked { client: 2 }.to_string(),
+        "account of client 2 locked"
+    );
+
+    let mut processor = InMemoryProcessor::default();
+    let errors = processor.process_str(
+        "type,client,tx,amount
+        deposit,1,17,1.0
+        dispute,1,17,
+        dispute,1,17,",
+    );
+    assert_eq!(
+        errors
+            .iter()
+            .map(|(line, error)| (*line, error.to_string()))
+            .collect::<Vec<_>>(),
+        vec![(2, "transaction 17 of client 1 already under dispute".into())]
+    );
+}
+
 #[test]
 fn chargeback_unknown_tx() -> Result<()> {
     let mut processor = InMemoryProcessor::default();
     let client = 3;
 
     assert_eq!(
-        processor.process(Payment::Chargeback(Dispute { client, tx: 4 })),
-        Err(Error::TransactionNotFound)
+        processor.process(Payment::Chargeback(Dispute {
+            client,
+            tx: 4,
+            amount: None
+        })),
+        Err(Error::TransactionNotFound {
+            client: client.into(),
+            tx: 4
+        })
     );
 
     Ok(())
 }
 
 #[test]
-fn basic_chargeback_flow() -> Result<()> {
-    let mut processor = InMemoryProcessor::default();
-    let client = 3;
+fn with_capacity_processes_the_same() {
+    let input = "type,client,tx,amount
+        deposit,1,1,5.0
+        deposit,2,2,3.0
+        withdrawal,1,3,1.5
+        dispute,2,2,
+        chargeback,2,2,
+        deposit,3,4,1.0";
 
-    processor.process(Payment::Deposit(Deposit {
-        client,
-        tx: 0,
-        amount: Amount(2),
-    }))?;
+    let mut presized = InMemoryProcessor::default().with_capacity(1000);
+    let mut plain = InMemoryProcessor::default();
+    assert_eq!(presized.process_str(input), vec![]);
+    assert_eq!(plain.process_str(input), vec![]);
+    assert!(accounts_eq(&presized, &plain));
+}
 
-    processor.process(Payment::Deposit(Deposit {
-        client,
-        tx: 1,
-        amount: Amount(1),
-    }))?;
+#[test]
+fn clear_behaves_like_fresh() {
+    let input = "type,client,tx,amount
+        deposit,1,1,5.0
+        deposit,2,2,3.0
+        dispute,2,2,
+        chargeback,2,2,";
 
-    processor.process(Payment::Dispute(Dispute { client, tx: 0 }))?;
-    assert_eq!(*processor.get_account(client).unwrap().total_funds, 3);
-    assert_eq!(*processor.get_account(client).unwrap().available_funds(), 1);
-    assert_eq!(*processor.get_account(client).unwrap().held_funds, 2);
-    assert_eq!(processor.get_account(client).unwrap().locked, false);
+    let mut processor = InMemoryProcessor::default().with_undo(4);
+    assert_eq!(processor.process_str(input), vec![]);
+    processor.clear();
+    assert_eq!(processor.account_count(), 0);
+    assert_eq!(processor.stats(), ProcessorStats::default());
+    assert_eq!(processor.funds_flow(), FundsFlow::default());
+    assert_eq!(processor.undo_last(), None);
 
+    // the same transaction ids are new again
+    let mut fresh = InMemoryProcessor::default();
+    assert_eq!(processor.process_str(input), vec![]);
+    assert_eq!(fresh.process_str(input), vec![]);
+    assert!(accounts_eq(&processor, &fresh));
+    assert_eq!(processor.stats(), fresh.stats());
+}
+
+#[test]
+fn dispute_of_unknown_client() -> anyhow::Result<()> {
+    let mut processor = InMemoryProcessor::default();
+    let errors = processor.process_str(
+        "type,client,tx,amount
+        deposit,1,1,1.0
+        dispute,2,1,
+        resolve,2,1,
+        chargeback,2,1,
+        reversal,2,1,",
+    );
     assert_eq!(
-        processor.process(Payment::Chargeback(Resolve { client, tx: 1 })),
-        Err(Error::TransactionNotDisputed)
+        errors,
+        vec![
+            (1, Error::TransactionNotFound { client: 2, tx: 1 }),
+            (2, Error::TransactionNotFound { client: 2, tx: 1 }),
+            (3, Error::TransactionNotFound { client: 2, tx: 1 }),
+            (4, Error::TransactionNotFound { client: 2, tx: 1 }),
+        ]
     );
-    assert_eq!(processor.get_account(client).unwrap().locked, false);
+    assert!(processor.get_account(2).is_none());
 
-    processor.process(Payment::Chargeback(Dispute { client, tx: 0 }))?;
+    let mut output = vec![];
+    processor.write_accounts(&mut output, &OutputOptions::default())?;
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        "client,available,held,total,locked\n1,1.0,0.0,1.0,false\n"
+    );
 
-    assert_eq!(*processor.get_account(client).unwrap().total_funds, 1);
-    assert_eq!(*processor.get_account(client).unwrap().available_funds(), 1);
-    assert_eq!(*processor.get_account(client).unwrap().held_funds, 0);
-    assert_eq!(processor.get_account(client).unwrap().locked, true);
+    Ok(())
+}
 
+#[test]
+fn concurrent_partial_disputes() {
+    let mut processor = InMemoryProcessor::default();
+    let errors = processor.process_str(
+        "type,client,tx,amount
+        deposit,1,1,5.0
+        dispute,1,1,2.0
+        dispute,1,1,3.5
+        dispute,1,1,3.0
+        dispute,1,1,
+        dispute,1,1,1.0",
+    );
     assert_eq!(
-        processor.process(Payment::Withdrawal(Withdrawal {
+        errors,
+        vec![
+            (2, Error::DisputeExceedsTransaction { client: 1, tx: 1 }),
+            (4, Error::TransactionAlreadyDisputed { client: 1, tx: 1 }),
+            (5, Error::TransactionAlreadyDisputed { client: 1, tx: 1 }),
+        ]
+    );
+    // the two disputes sum up to the whole deposit
+    let account = processor.get_account(1).unwrap();
+    assert_eq!(account.held_funds, Amount(5_0000));
+    assert_eq!(account.available_funds(), Amount(0));
+
+    // each one is settled on its own, by its amount
+    let errors = processor.process_str(
+        "type,client,tx,amount
+        resolve,1,1,1.0
+        resolve,1,1,2.0",
+    );
+    assert_eq!(
+        errors,
+        vec![(0, Error::DisputedAmountNotFound { client: 1, tx: 1 })]
+    );
+    assert!(processor.is_disputed(1, 1));
+    let account = processor.get_account(1).unwrap();
+    assert_eq!(account.held_funds, Amount(3_0000));
+    assert_eq!(account.available_funds(), Amount(2_0000));
+
+    assert_eq!(
+        processor.process_str(
+            "type,client,tx,amount
+            chargeback,1,1,3.0"
+        ),
+        vec![]
+    );
+    assert!(!processor.is_disputed(1, 1));
+    let account = processor.get_account(1).unwrap();
+    assert_eq!(account.held_funds, Amount(0));
+    assert_eq!(account.total_funds, Amount(2_0000));
+    assert!(account.locked());
+
+    // without an amount, a resolve settles all outstanding disputes
+    let mut processor = InMemoryProcessor::default();
+    assert_eq!(
+        processor.process_str(
+            "type,client,tx,amount
+            deposit,1,1,5.0
+            dispute,1,1,2.0
+            dispute,1,1,
+            resolve,1,1,"
+        ),
+        vec![]
+    );
+    assert!(!processor.is_disputed(1, 1));
+    let account = processor.get_account(1).unwrap();
+    assert_eq!(account.held_funds, Amount(0));
+    assert_eq!(account.available_funds(), Amount(5_0000));
+}
+
+#[test]
+fn deposit_overflow_policy() -> Result<()> {
+    let deposit = |tx, amount| {
+        Payment::Deposit(Deposit {
+            client: 1,
+            tx,
+            amount: Amount(amount),
+        })
+    };
+
+    let mut processor = InMemoryProcessor::default();
+    processor.process(deposit(1, u64::MAX - 10))?;
+    assert_eq!(
+        processor.process(deposit(2, 11)),
+        Err(Error::Overflow { client: 1 })
+    );
+    let account = processor.get_account(1).unwrap();
+    assert_eq!(account.total_funds, Amount(u64::MAX - 10));
+    assert!(!account.saturated);
+
+    let mut processor = InMemoryProcessor::new(Config {
+        overflow_policy: OverflowPolicy::Saturate,
+        ..Default::default()
+    });
+    processor.process(deposit(1, u64::MAX - 10))?;
+    processor.process(deposit(2, 10))?;
+    assert!(!processor.get_account(1).unwrap().saturated);
+    processor.process(deposit(3, 5))?;
+    let account = processor.get_account(1).unwrap();
+    assert_eq!(account.total_funds, Amount(u64::MAX));
+    assert_eq!(account.available_funds(), Amount(u64::MAX));
+    assert!(account.saturated);
+
+    // the dispute holds only what was deposited
+    processor.process(Payment::Dispute(Dispute {
+        client: 1,
+        tx: 3,
+        amount: None,
+    }))?;
+    assert_eq!(processor.get_account(1).unwrap().held_funds, Amount(0));
+    assert_eq!(processor.reconcile().discrepancy(), SignedAmount(0));
+
+    Ok(())
+}
+
+#[test]
+fn redispute_policy() {
+    let input = "type,client,tx,amount
+        deposit,1,1,1.0
+        dispute,1,1,
+        resolve,1,1,
+        dispute,1,1,";
+
+    let mut processor = InMemoryProcessor::default();
+    assert_eq!(processor.process_str(input), vec![]);
+    assert!(processor.is_disputed(1, 1));
+
+    let mut processor = InMemoryProcessor::new(Config {
+        deny_redispute: true,
+        ..Default::default()
+    });
+    assert_eq!(
+        processor.process_str(input),
+        vec![(3, Error::TransactionDisputeClosed { client: 1, tx: 1 })]
+    );
+    assert!(!processor.is_disputed(1, 1));
+    assert_eq!(processor.get_account(1).unwrap().held_funds, Amount(0));
+}
+
+#[test]
+fn basic_chargeback_flow() -> Result<()> {
+    let mut processor = InMemoryProcessor::default();
+    let client = 3;
+
+    processor.process(Payment::Deposit(Deposit {
+        client,
+        tx: 0,
+        amount: Amount(2),
+    }))?;
+
+    processor.process(Payment::Deposit(Deposit {
+        client,
+        tx: 1,
+        amount: Amount(1),
+    }))?;
+
+    processor.process(Payment::Dispute(Dispute {
+        client,
+        tx: 0,
+        amount: None,
+    }))?;
+    assert_eq!(*processor.get_account(client).unwrap().total_funds, 3);
+    assert_eq!(*processor.get_account(client).unwrap().available_funds(), 1);
+    assert_eq!(*processor.get_account(client).unwrap().held_funds, 2);
+    assert_eq!(processor.get_account(client).unwrap().locked(), false);
+
+    assert_eq!(
+        processor.process(Payment::Chargeback(Resolve {
+            client,
+            tx: 1,
+            amount: None
+        })),
+        Err(Error::TransactionNotDisputed { client: 3, tx: 1 })
+    );
+    assert_eq!(processor.get_account(client).unwrap().locked(), false);
+
+    processor.process(Payment::Chargeback(Dispute {
+        client,
+        tx: 0,
+        amount: None,
+    }))?;
+
+    assert_eq!(*processor.get_account(client).unwrap().total_funds, 1);
+    assert_eq!(*processor.get_account(client).unwrap().available_funds(), 1);
+    assert_eq!(*processor.get_account(client).unwrap().held_funds, 0);
+    assert_eq!(processor.get_account(client).unwrap().locked(), true);
+
+    assert_eq!(
+        processor.process(Payment::Withdrawal(Withdrawal {
+            client,
+            tx: 2,
+            amount: Amount(1),
+        })),
+        Err(Error::AccountLocked { client: 3 })
+    );
+    Ok(())
+}
+
+#[test]
+fn chargeback_with_flat_fee() -> Result<()> {
+    struct FlatFee(Amount);
+
+    impl FeePolicy for FlatFee {
+        fn on_chargeback(&self, amount: Amount) -> Amount {
+            Amount(*amount + *self.0)
+        }
+    }
+
+    let mut processor = InMemoryProcessor::default().with_fee_policy(FlatFee(Amount(1)));
+    let client = 3;
+
+    processor.process(Payment::Deposit(Deposit {
+        client,
+        tx: 0,
+        amount: Amount(5),
+    }))?;
+
+    processor.process(Payment::Deposit(Deposit {
+        client,
+        tx: 1,
+        amount: Amount(2),
+    }))?;
+
+    processor.process(Payment::Dispute(Dispute {
+        client,
+        tx: 0,
+        amount: None,
+    }))?;
+    processor.process(Payment::Chargeback(Chargeback {
+        client,
+        tx: 0,
+        amount: None,
+    }))?;
+
+    assert_eq!(*processor.get_account(client).unwrap().total_funds, 1);
+    assert_eq!(*processor.get_account(client).unwrap().available_funds(), 1);
+    assert_eq!(*processor.get_account(client).unwrap().held_funds, 0);
+    assert!(processor.get_account(client).unwrap().locked());
+
+    Ok(())
+}
+
+#[test]
+fn max_clients_limit() -> Result<()> {
+    let mut processor = InMemoryProcessor::new(Config {
+        max_clients: Some(2),
+        ..Default::default()
+    });
+
+    for (tx, client) in [1, 2, 1].iter().enumerate() {
+        processor.process(Payment::Deposit(Deposit {
+            client: *client,
+            tx: tx as TransactionID,
+            amount: Amount(1),
+        }))?;
+    }
+
+    assert_eq!(
+        processor.process(Payment::Deposit(Deposit {
+            client: 3,
+            tx: 4,
+            amount: Amount(1),
+        })),
+        Err(Error::TooManyClients { client: 3 })
+    );
+    assert!(processor.get_account(3).is_none());
+
+    // existing clients still process
+    processor.process(Payment::Deposit(Deposit {
+        client: 2,
+        tx: 5,
+        amount: Amount(1),
+    }))?;
+    assert_eq!(*processor.get_account(2).unwrap().total_funds, 2);
+
+    Ok(())
+}
+
+#[test]
+fn available_funds_invariant() -> Result<()> {
+    let mut processor = InMemoryProcessor::default();
+    let client = 3;
+
+    let payments = vec![
+        Payment::Deposit(Deposit {
+            client,
+            tx: 0,
+            amount: Amount(7),
+        }),
+        Payment::Deposit(Deposit {
+            client,
+            tx: 1,
+            amount: Amount(3),
+        }),
+        Payment::Withdrawal(Withdrawal {
+            client,
+            tx: 2,
+            amount: Amount(2),
+        }),
+        Payment::Dispute(Dispute {
+            client,
+            tx: 0,
+            amount: None,
+        }),
+        // fails: only 1 available
+        Payment::Withdrawal(Withdrawal {
+            client,
+            tx: 3,
+            amount: Amount(2),
+        }),
+        Payment::Resolve(Resolve {
+            client,
+            tx: 0,
+            amount: None,
+        }),
+        Payment::Dispute(Dispute {
+            client,
+            tx: 1,
+            amount: None,
+        }),
+        Payment::Chargeback(Chargeback {
             client,
+            tx: 1,
+            amount: None,
+        }),
+    ];
+
+    for payment in payments {
+        let _ = processor.process(payment);
+        let state = processor.get_account(client).unwrap();
+        assert_eq!(
+            *state.available_funds(),
+            *state.total_funds - *state.held_funds
+        );
+    }
+
+    assert_eq!(*processor.get_account(client).unwrap().available_funds(), 5);
+
+    Ok(())
+}
+
+#[test]
+fn deny_withdrawal_overdraft_after_dispute() -> Result<()> {
+    let payments = [
+        Payment::Deposit(Deposit {
+            client: 3,
+            tx: 3,
+            amount: Amount(7),
+        }),
+        Payment::Dispute(Dispute {
+            client: 3,
+            tx: 3,
+            amount: None,
+        }),
+        Payment::Resolve(Resolve {
+            client: 3,
+            tx: 3,
+            amount: None,
+        }),
+        Payment::Withdrawal(Withdrawal {
+            client: 3,
+            tx: 13,
+            amount: Amount(7),
+        }),
+        Payment::Dispute(Dispute {
+            client: 3,
+            tx: 3,
+            amount: None,
+        }),
+    ];
+
+    // permissive: withdrawal passes, re-dispute can't hold funds anymore
+    let mut processor = InMemoryProcessor::default();
+    let results: Vec<_> = payments
+        .iter()
+        .map(|payment| processor.process(payment.clone()))
+        .collect();
+    assert_eq!(results[3], Ok(()));
+    assert!(matches!(results[4], Err(Error::DisputedFundsSpent { .. })));
+
+    // strict: withdrawal is blocked and the re-dispute succeeds
+    let mut processor = InMemoryProcessor::new(Config {
+        deny_withdrawal_overdraft_after_dispute: true,
+        ..Default::default()
+    });
+    let results: Vec<_> = payments
+        .iter()
+        .map(|payment| processor.process(payment.clone()))
+        .collect();
+    assert_eq!(
+        results[3],
+        Err(Error::HeldFundsProtected { client: 3, tx: 13 })
+    );
+    assert_eq!(results[4], Ok(()));
+    assert_eq!(*processor.get_account(3).unwrap().held_funds, 7);
+
+    // but the rest can still be withdrawn
+    processor.process(Payment::Deposit(Deposit {
+        client: 3,
+        tx: 14,
+        amount: Amount(2),
+    }))?;
+    processor.process(Payment::Withdrawal(Withdrawal {
+        client: 3,
+        tx: 15,
+        amount: Amount(2),
+    }))?;
+
+    Ok(())
+}
+
+#[test]
+fn equal_account_states() -> Result<()> {
+    let payments = [
+        Payment::Deposit(Deposit {
+            client: 1,
+            tx: 1,
+            amount: Amount(5),
+        }),
+        Payment::Deposit(Deposit {
+            client: 2,
             tx: 2,
+            amount: Amount(3),
+        }),
+        Payment::Dispute(Dispute {
+            client: 1,
+            tx: 1,
+            amount: None,
+        }),
+    ];
+
+    let mut a = InMemoryProcessor::default();
+    let mut b = InMemoryProcessor::default();
+    for payment in &payments {
+        a.process(payment.clone())?;
+        b.process(payment.clone())?;
+    }
+
+    assert_eq!(a.get_account(1), b.get_account(1));
+    assert!(accounts_eq(&a, &b));
+
+    b.process(Payment::Resolve(Resolve {
+        client: 1,
+        tx: 1,
+        amount: None,
+    }))?;
+    assert_ne!(a.get_account(1), b.get_account(1));
+    assert!(!accounts_eq(&a, &b));
+
+    Ok(())
+}
+
+#[test]
+fn undo_last_payment() -> Result<()> {
+    let mut processor = InMemoryProcessor::default().with_undo(2);
+    let client = 3;
+
+    let deposit = Payment::Deposit(Deposit {
+        client,
+        tx: 1,
+        amount: Amount(5),
+    });
+    processor.process(deposit.clone())?;
+    assert_eq!(
+        processor.undo_last(),
+        Some(Undone::Payment(deposit.clone()))
+    );
+    assert!(processor.get_account(client).is_none());
+    assert_eq!(processor.undo_last(), None);
+
+    // the same tx can be applied again after undoing it
+    processor.process(deposit)?;
+    processor.process(Payment::Dispute(Dispute {
+        client,
+        tx: 1,
+        amount: None,
+    }))?;
+    // rejected payments are not recorded
+    assert!(processor
+        .process(Payment::Dispute(Dispute {
+            client,
+            tx: 1,
+            amount: None
+        }))
+        .is_err());
+    assert_eq!(
+        processor.undo_last(),
+        Some(Undone::Payment(Payment::Dispute(Dispute {
+            client,
+            tx: 1,
+            amount: None
+        })))
+    );
+    assert_eq!(*processor.get_account(client).unwrap().total_funds, 5);
+    assert_eq!(*processor.get_account(client).unwrap().held_funds, 0);
+
+    // only the last `depth` payments are kept
+    for tx in 2..5 {
+        processor.process(Payment::Deposit(Deposit {
+            client,
+            tx,
             amount: Amount(1),
+        }))?;
+    }
+    assert!(processor.undo_last().is_some());
+    assert!(processor.undo_last().is_some());
+    assert_eq!(processor.undo_last(), None);
+    assert_eq!(*processor.get_account(client).unwrap().total_funds, 6);
+
+    // adjustments are undone like payments, while a freeze is kept,
+    // along with the payments before it
+    processor.process(Payment::Deposit(Deposit {
+        client,
+        tx: 5,
+        amount: Amount(1),
+    }))?;
+    processor.freeze_account(client, FreezeReason::Administrative)?;
+    processor.adjust_balance(client, SignedAmount(2), "correction".into())?;
+    assert_eq!(
+        processor.undo_last(),
+        Some(Undone::Adjustment {
+            client,
+            adjustment: Adjustment {
+                delta: SignedAmount(2),
+                reason: "correction".into(),
+            },
+        })
+    );
+    assert_eq!(processor.undo_last(), None);
+    let account = processor.get_account(client).unwrap();
+    assert_eq!(
+        account.status,
+        AccountStatus::Frozen(FreezeReason::Administrative)
+    );
+    assert_eq!(*account.total_funds, 7);
+    assert_eq!(processor.get_adjustments(client).unwrap(), []);
+    assert_eq!(processor.funds_flow().adjusted, SignedAmount(0));
+
+    // disabled by default
+    let mut processor = InMemoryProcessor::default();
+    processor.process(Payment::Deposit(Deposit {
+        client,
+        tx: 1,
+        amount: Amount(5),
+    }))?;
+    assert_eq!(processor.undo_last(), None);
+
+    Ok(())
+}
+
+#[test]
+fn dispute_charged_back_tx() -> Result<()> {
+    let mut processor = InMemoryProcessor::default();
+    let client = 3;
+
+    processor.process(Payment::Deposit(Deposit {
+        client,
+        tx: 0,
+        amount: Amount(2),
+    }))?;
+    processor.process(Payment::Deposit(Deposit {
+        client,
+        tx: 1,
+        amount: Amount(2),
+    }))?;
+    processor.process(Payment::Dispute(Dispute {
+        client,
+        tx: 0,
+        amount: None,
+    }))?;
+    processor.process(Payment::Chargeback(Chargeback {
+        client,
+        tx: 0,
+        amount: None,
+    }))?;
+
+    assert_eq!(
+        processor.process(Payment::Dispute(Dispute {
+            client,
+            tx: 0,
+            amount: None
         })),
-        Err(Error::AccountLocked)
+        Err(Error::TransactionChargedBack { client: 3, tx: 0 })
     );
+    assert_eq!(*processor.get_account(client).unwrap().total_funds, 2);
+    assert_eq!(*processor.get_account(client).unwrap().held_funds, 0);
+
+    Ok(())
+}
+
+#[test]
+fn write_accounts_matches_record_loop() -> anyhow::Result<()> {
+    let mut processor = InMemoryProcessor::default();
+    for client in 1..5 {
+        processor.process(Payment::Deposit(Deposit {
+            client,
+            tx: TransactionID::from(client),
+            amount: Amount(15000 * u64::from(client)),
+        }))?;
+    }
+    processor.process(Payment::Dispute(Dispute {
+        client: 2,
+        tx: 2,
+        amount: None,
+    }))?;
+
+    let mut expected = csv::Writer::from_writer(vec![]);
+    for (client_id, account) in processor.sorted_accounts(SortBy::Client) {
+        expected.serialize(account.to_output_record(*client_id))?;
+    }
+    let expected = expected.into_inner()?;
+
+    let mut output = vec![];
+    processor.write_accounts(&mut output, &OutputOptions::default())?;
+
+    assert_eq!(output, expected);
+    assert_eq!(output.iter().filter(|&&b| b == b'\n').count(), 5);
+
+    Ok(())
+}
+
+#[test]
+fn hold_limit_per_account() -> Result<()> {
+    let mut processor = InMemoryProcessor::new(Config {
+        max_held_per_account: Some(Amount(10)),
+        ..Default::default()
+    });
+    let client = 3;
+
+    processor.process(Payment::Deposit(Deposit {
+        client,
+        tx: 0,
+        amount: Amount(6),
+    }))?;
+    processor.process(Payment::Deposit(Deposit {
+        client,
+        tx: 1,
+        amount: Amount(5),
+    }))?;
+
+    processor.process(Payment::Dispute(Dispute {
+        client,
+        tx: 0,
+        amount: None,
+    }))?;
+    assert_eq!(
+        processor.process(Payment::Dispute(Dispute {
+            client,
+            tx: 1,
+            amount: None
+        })),
+        Err(Error::HoldLimitExceeded {
+            client: client.into(),
+            tx: 1
+        })
+    );
+    assert_eq!(*processor.get_account(client).unwrap().held_funds, 6);
+
+    // room again after the first one is resolved
+    processor.process(Payment::Resolve(Resolve {
+        client,
+        tx: 0,
+        amount: None,
+    }))?;
+    processor.process(Payment::Dispute(Dispute {
+        client,
+        tx: 1,
+        amount: None,
+    }))?;
+    assert_eq!(*processor.get_account(client).unwrap().held_funds, 5);
+
+    Ok(())
+}
+
+#[test]
+fn sorted_accounts_by_key() -> Result<()> {
+    let mut processor = InMemoryProcessor::default();
+    // client, total, held
+    for (tx, (client, total, held)) in [(4, 5, 0), (1, 5, 4), (3, 9, 0), (2, 1, 0)]
+        .iter()
+        .enumerate()
+    {
+        let tx = tx as TransactionID * 2;
+        processor.process(Payment::Deposit(Deposit {
+            client: *client,
+            tx,
+            amount: Amount(*held),
+        }))?;
+        processor.process(Payment::Deposit(Deposit {
+            client: *client,
+            tx: tx + 1,
+            amount: Amount(total - held),
+        }))?;
+        if 0 < *held {
+            processor.process(Payment::Dispute(Dispute {
+                client: *client,
+                tx,
+                amount: None,
+            }))?;
+        }
+    }
+
+    let order = |sort_by| -> Vec<ClientID> {
+        processor
+            .sorted_accounts(sort_by)
+            .into_iter()
+            .map(|(client_id, _)| *client_id)
+            .collect()
+    };
+    assert_eq!(order(SortBy::Client), vec![1, 2, 3, 4]);
+    // ties broken by client id
+    assert_eq!(order(SortBy::Total), vec![3, 1, 4, 2]);
+    assert_eq!(order(SortBy::Available), vec![3, 4, 1, 2]);
+
+    Ok(())
+}
+
+#[test]
+fn process_str_sample() -> Result<()> {
+    let mut processor = InMemoryProcessor::default();
+
+    let errors = processor.process_str(
+        "type, client, tx, amount
+        deposit, 1, 1, 1.0
+        deposit, 1, 2, 2.0
+        withdrawal, 1, 3, 1.5
+        dispute, 1, 1,
+        chargeback, 1, 1,",
+    );
+    assert_eq!(errors, vec![]);
+    assert_eq!(*processor.get_account(1).unwrap().total_funds, 5000);
+    assert!(processor.get_account(1).unwrap().locked());
+
+    let errors = processor.process_str(
+        "type,client,tx,amount
+        deposit,2,1,1.0
+        transfer,2,2,1.0
+        deposit,2,1,1.0",
+    );
+    assert_eq!(
+        errors,
+        vec![
+            (
+                1,
+                Error::InvalidRecord(DeserializationError::InvalidType("transfer".into()))
+            ),
+            (2, Error::TransactionAlreadyExists { client: 2, tx: 1 }),
+        ]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn account_status_transitions() -> anyhow::Result<()> {
+    let mut processor = InMemoryProcessor::default();
+
+    let errors = processor.process_str(
+        "type,client,tx,amount
+        deposit,1,1,1.0
+        dispute,1,1,",
+    );
+    assert_eq!(errors, vec![]);
+    assert_eq!(
+        processor.get_account(1).unwrap().status,
+        AccountStatus::Active
+    );
+
+    processor.process(Payment::Chargeback(Chargeback {
+        client: 1,
+        tx: 1,
+        amount: None,
+    }))?;
+    assert_eq!(
+        processor.get_account(1).unwrap().status,
+        AccountStatus::Frozen(FreezeReason::Chargeback)
+    );
+
+    let mut output = vec![];
+    processor.write_accounts(&mut output, &OutputOptions::default())?;
+    assert_eq!(
+        String::from_utf8(output)?,
+        "client,available,held,total,locked\n1,0.0,0.0,0.0,true\n"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn freeze_and_unfreeze_account() -> Result<()> {
+    let mut processor = InMemoryProcessor::default();
+    let client = 3;
+
+    processor.process(Payment::Deposit(Deposit {
+        client,
+        tx: 1,
+        amount: Amount(1),
+    }))?;
+
+    processor.freeze_account(client, FreezeReason::Administrative)?;
+    assert!(processor.get_account(client).unwrap().locked());
+    assert_eq!(
+        processor.process(Payment::Deposit(Deposit {
+            client,
+            tx: 2,
+            amount: Amount(1),
+        })),
+        Err(Error::AccountLocked { client: 3 })
+    );
+
+    processor.unfreeze_account(client)?;
+    processor.process(Payment::Deposit(Deposit {
+        client,
+        tx: 2,
+        amount: Amount(1),
+    }))?;
+    assert_eq!(*processor.get_account(client).unwrap().total_funds, 2);
+
+    assert_eq!(
+        processor.freeze_account(7, FreezeReason::Administrative),
+        Err(Error::UnknownClient { client: 7 })
+    );
+
+    Ok(())
+}
+
+#[test]
+fn unfreeze_after_chargeback() -> Result<()> {
+    let input = "type,client,tx,amount
+    deposit,1,1,1.0
+    deposit,1,2,1.0
+    dispute,1,1,
+    chargeback,1,1,";
+
+    let mut processor = InMemoryProcessor::default();
+    assert_eq!(processor.process_str(input), vec![]);
+    // admin freeze doesn't override the chargeback reason
+    processor.freeze_account(1, FreezeReason::Administrative)?;
+    assert_eq!(
+        processor.unfreeze_account(1),
+        Err(Error::UnfreezeNotAllowed { client: 1 })
+    );
+    assert!(processor.get_account(1).unwrap().locked());
+
+    let mut processor = InMemoryProcessor::new(Config {
+        allow_unfreeze_after_chargeback: true,
+        ..Default::default()
+    });
+    assert_eq!(processor.process_str(input), vec![]);
+    processor.unfreeze_account(1)?;
+    assert!(!processor.get_account(1).unwrap().locked());
+
+    Ok(())
+}
+
+#[test]
+fn max_amount_limit() -> Result<()> {
+    let mut processor = InMemoryProcessor::new(Config {
+        max_amount: Some(Amount(1000_0000)),
+        ..Default::default()
+    });
+
+    let errors = processor.process_str(
+        "type,client,tx,amount
+        deposit,1,1,2000.0
+        deposit,1,2,1000.0
+        withdrawal,1,3,1000.0001",
+    );
+    assert_eq!(
+        errors,
+        vec![
+            (0, Error::AmountTooLarge { client: 1, tx: 1 }),
+            (2, Error::AmountTooLarge { client: 1, tx: 3 })
+        ]
+    );
+    assert_eq!(*processor.get_account(1).unwrap().total_funds, 1000_0000);
+
+    Ok(())
+}
+
+#[test]
+fn output_with_transaction_counts() -> anyhow::Result<()> {
+    let mut processor = InMemoryProcessor::default();
+    let errors = processor.process_str(
+        "type,client,tx,amount
+        deposit,1,1,1.0
+        deposit,1,2,1.0
+        deposit,2,3,1.0
+        deposit,1,4,1.0
+        withdrawal,1,5,2.0
+        dispute,1,1,",
+    );
+    assert_eq!(errors, vec![]);
+    assert_eq!(processor.get_transaction_count(1), Some(4));
+    assert_eq!(processor.get_transaction_count(3), None);
+
+    let mut output = vec![];
+    processor.write_accounts(
+        &mut output,
+        &OutputOptions {
+            with_counts: true,
+            ..Default::default()
+        },
+    )?;
+    assert_eq!(
+        String::from_utf8(output)?,
+        "client,available,held,total,locked,transactions
+1,0.0,1.0,1.0,false,4
+2,1.0,0.0,1.0,false,1
+"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn output_with_lock_reason() -> anyhow::Result<()> {
+    let mut processor = InMemoryProcessor::default();
+    let errors = processor.process_str(
+        "type,client,tx,amount
+        deposit,1,1,1.0
+        deposit,2,2,2.0
+        dispute,2,2,
+        chargeback,2,2,
+        deposit,3,3,3.0",
+    );
+    assert_eq!(errors, vec![]);
+    processor.freeze_account(3, FreezeReason::Administrative)?;
+
+    let mut output = vec![];
+    processor.write_accounts(
+        &mut output,
+        &OutputOptions {
+            with_lock_reason: true,
+            ..Default::default()
+        },
+    )?;
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        "client,available,held,total,locked,lock_reason
+1,1.0,0.0,1.0,false,
+2,0.0,0.0,0.0,true,chargeback
+3,3.0,0.0,3.0,true,administrative
+"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn history_cap_evicts_settled_only() -> Result<()> {
+    let mut processor = InMemoryProcessor::new(Config {
+        max_history_per_account: Some(3),
+        deny_redispute: true,
+        ..Default::default()
+    });
+    let errors = processor.process_str(
+        "type,client,tx,amount
+        deposit,1,1,1.0
+        deposit,1,2,1.0
+        dispute,1,2,
+        resolve,1,2,
+        deposit,1,3,1.0
+        withdrawal,1,4,0.5
+        reversal,1,4,
+        deposit,1,5,1.0
+        deposit,1,6,1.0
+        deposit,1,2,1.0
+        withdrawal,1,4,0.5
+        dispute,1,2,
+        dispute,1,1,",
+    );
+    // the resolved tx 2 and then the reversed withdrawal can't be disputed
+    // anymore, so they go; the deposits that still can are kept beyond
+    // the maximum
+    assert_eq!(
+        errors,
+        vec![
+            (9, Error::TransactionAlreadyExists { client: 1, tx: 2 }),
+            (10, Error::TransactionAlreadyExists { client: 1, tx: 4 }),
+            (11, Error::TransactionNotFound { client: 1, tx: 2 }),
+        ]
+    );
+    let history: Vec<_> = processor
+        .get_history_ordered(1)
+        .unwrap()
+        .into_iter()
+        .map(|(tx, _)| tx)
+        .collect();
+    assert_eq!(history, vec![1, 3, 5, 6]);
+    let account = processor.get_account(1).unwrap();
+    assert_eq!(*account.total_funds, 5_0000);
+    assert_eq!(*account.held_funds, 1_0000);
+
+    Ok(())
+}
+
+#[test]
+fn history_cap_keeps_reversible_withdrawals() -> Result<()> {
+    let input = "type,client,tx,amount
+        deposit,1,1,5.0
+        withdrawal,1,2,1.0
+        deposit,1,3,1.0
+        deposit,1,4,1.0
+        reversal,1,2,";
+    let mut capped = InMemoryProcessor::new(Config {
+        max_history_per_account: Some(2),
+        ..Default::default()
+    });
+    let mut uncapped = InMemoryProcessor::default();
+
+    // the withdrawal can still be reversed, so it isn't dropped
+    assert_eq!(capped.process_str(input), vec![]);
+    assert_eq!(uncapped.process_str(input), vec![]);
+    assert!(accounts_eq(&capped, &uncapped));
+    assert_eq!(*capped.get_account(1).unwrap().total_funds, 7_0000);
+
+    Ok(())
+}
+
+#[test]
+fn reverse_withdrawal() -> Result<()> {
+    let mut processor = InMemoryProcessor::default();
+    let errors = processor.process_str(
+        "type,client,tx,amount
+        deposit,1,1,5.0
+        withdrawal,1,2,3.0
+        reversal,1,2,",
+    );
+    assert_eq!(errors, vec![]);
+    assert_eq!(*processor.get_account(1).unwrap().total_funds, 5_0000);
+    assert_eq!(*processor.get_account(1).unwrap().available_funds(), 5_0000);
+
+    assert_eq!(
+        processor.process(Payment::Reversal(Reversal {
+            client: 1,
+            tx: 2,
+            amount: None
+        })),
+        Err(Error::TransactionAlreadyReversed { client: 1, tx: 2 })
+    );
+    assert_eq!(
+        processor.process(Payment::Reversal(Reversal {
+            client: 1,
+            tx: 1,
+            amount: None
+        })),
+        Err(Error::WrongTransactionType { client: 1, tx: 1 })
+    );
+    assert_eq!(
+        processor.process(Payment::Reversal(Reversal {
+            client: 1,
+            tx: 7,
+            amount: None
+        })),
+        Err(Error::TransactionNotFound { client: 1, tx: 7 })
+    );
+    assert_eq!(*processor.get_account(1).unwrap().total_funds, 5_0000);
+
+    Ok(())
+}
+
+#[test]
+fn withdrawal_dispute_chargeback() -> Result<()> {
+    let input = "type,client,tx,amount
+        deposit,1,1,5.0
+        withdrawal,1,2,3.0
+        dispute,1,2,";
+
+    let mut processor = InMemoryProcessor::default();
+    assert_eq!(
+        processor.process_str(input),
+        vec![(2, Error::WrongTransactionType { client: 1, tx: 2 })]
+    );
+
+    let mut processor = InMemoryProcessor::new(Config {
+        dispute_policy: DisputePolicy::WithdrawalChargebacks,
+        ..Default::default()
+    });
+    assert_eq!(processor.process_str(input), vec![]);
+    // nothing is held, the funds already left
+    let account = processor.get_account(1).unwrap();
+    assert_eq!(*account.total_funds, 2_0000);
+    assert_eq!(*account.held_funds, 0);
+    assert_eq!(*account.available_funds(), 2_0000);
+    assert!(processor.is_disputed(1, 2));
+
+    assert_eq!(
+        processor.process(Payment::Reversal(Reversal {
+            client: 1,
+            tx: 2,
+            amount: None
+        })),
+        Err(Error::TransactionAlreadyDisputed { client: 1, tx: 2 })
+    );
+    processor.process(Payment::Chargeback(Chargeback {
+        client: 1,
+        tx: 2,
+        amount: None,
+    }))?;
+    let account = processor.get_account(1).unwrap();
+    assert_eq!(*account.total_funds, 5_0000);
+    assert_eq!(*account.held_funds, 0);
+    assert_eq!(*account.available_funds(), 5_0000);
+    assert!(account.locked());
+    assert_eq!(processor.reconcile().discrepancy(), SignedAmount(0));
+
+    Ok(())
+}
+
+#[test]
+fn withdrawal_dispute_resolve() -> Result<()> {
+    let mut processor = InMemoryProcessor::new(Config {
+        dispute_policy: DisputePolicy::WithdrawalChargebacks,
+        ..Default::default()
+    });
+    let errors = processor.process_str(
+        "type,client,tx,amount
+        deposit,1,1,5.0
+        withdrawal,1,2,3.0
+        dispute,1,2,
+        resolve,1,2,
+        chargeback,1,2,",
+    );
+    assert_eq!(
+        errors,
+        vec![(4, Error::TransactionNotDisputed { client: 1, tx: 2 })]
+    );
+    let account = processor.get_account(1).unwrap();
+    assert_eq!(*account.total_funds, 2_0000);
+    assert_eq!(*account.held_funds, 0);
+    assert_eq!(*account.available_funds(), 2_0000);
+    assert!(!account.locked());
+
+    Ok(())
+}
+
+#[test]
+fn duplicate_deposit_policy() -> Result<()> {
+    let input = "type,client,tx,amount
+        deposit,1,1,5.0
+        deposit,1,1,5.0";
+
+    let mut processor = InMemoryProcessor::default();
+    let errors = processor.process_str(input);
+    assert_eq!(
+        errors,
+        vec![(1, Error::TransactionAlreadyExists { client: 1, tx: 1 })]
+    );
+
+    let mut processor = InMemoryProcessor::new(Config {
+        duplicate_tx_policy: DuplicateTxPolicy::Ignore,
+        ..Default::default()
+    });
+    let errors = processor.process_str(input);
+    assert_eq!(errors, vec![]);
+    assert_eq!(*processor.get_account(1).unwrap().total_funds, 5_0000);
+
+    assert_eq!(
+        processor.process(Payment::Deposit(Deposit {
+            client: 1,
+            tx: 1,
+            amount: Amount(6_0000),
+        })),
+        Err(Error::TransactionConflict { client: 1, tx: 1 })
+    );
+    assert_eq!(*processor.get_account(1).unwrap().total_funds, 5_0000);
+
+    Ok(())
+}
+
+#[cfg(feature = "json")]
+#[test]
+fn to_json_dump() -> Result<()> {
+    let mut processor = InMemoryProcessor::default();
+    let errors = processor.process_str(
+        "type,client,tx,amount
+        deposit,7,1,2.5
+        dispute,7,1,",
+    );
+    assert_eq!(errors, vec![]);
+
+    let json = processor.to_json();
+    assert!(json.contains("\"7\""));
+    assert!(json.contains("\"total_funds\": 25000"));
+    assert!(json.contains("\"held_funds\": 25000"));
+    assert!(json.contains("\"in_dispute\""));
+
+    Ok(())
+}
+
+#[test]
+fn snapshots_deterministic() {
+    let snapshot = |input: &str| {
+        let mut processor = InMemoryProcessor::default();
+        assert_eq!(processor.process_str(input), vec![]);
+        let mut output = vec![];
+        for sort_by in [SortBy::Client, SortBy::Total, SortBy::Available] {
+            let options = OutputOptions {
+                sort_by,
+                ..Default::default()
+            };
+            processor.write_accounts(&mut output, &options).unwrap();
+        }
+        #[cfg(feature = "json")]
+        output.extend(processor.to_json().into_bytes());
+        output
+    };
+
+    let input = "type,client,tx,amount
+        deposit,3,1,1.0
+        deposit,1,2,1.0
+        deposit,2,3,1.0
+        deposit,1,4,2.0
+        deposit,1,5,3.0
+        dispute,1,5,
+        dispute,1,2,
+        dispute,1,4,";
+    assert_eq!(snapshot(input), snapshot(input));
+
+    // same state, with accounts created and disputes made in another order
+    let reordered = "type,client,tx,amount
+        deposit,1,2,1.0
+        deposit,2,3,1.0
+        deposit,1,4,2.0
+        deposit,1,5,3.0
+        deposit,3,1,1.0
+        dispute,1,4,
+        dispute,1,2,
+        dispute,1,5,";
+    assert_eq!(snapshot(input), snapshot(reordered));
+}
+
+#[test]
+fn transaction_status_lifecycle() -> Result<()> {
+    let mut processor = InMemoryProcessor::default();
+    let status = |processor: &InMemoryProcessor, tx| {
+        processor
+            .get_history_ordered(1)
+            .unwrap()
+            .into_iter()
+            .find(|(past_tx, _)| *past_tx == tx)
+            .map(|(_, past_tx)| past_tx.status)
+    };
+
+    processor.process_str(
+        "type,client,tx,amount
+        deposit,1,1,5.0
+        withdrawal,1,2,1.0",
+    );
+    assert_eq!(status(&processor, 1), Some(TransactionStatus::Normal));
+    assert_eq!(status(&processor, 2), Some(TransactionStatus::Normal));
+
+    processor.process(Payment::Reversal(Reversal {
+        client: 1,
+        tx: 2,
+        amount: None,
+    }))?;
+    assert_eq!(status(&processor, 2), Some(TransactionStatus::Reversed));
+
+    processor.process(Payment::Dispute(Dispute {
+        client: 1,
+        tx: 1,
+        amount: None,
+    }))?;
+    assert_eq!(status(&processor, 1), Some(TransactionStatus::Disputed));
+    processor.process(Payment::Resolve(Resolve {
+        client: 1,
+        tx: 1,
+        amount: None,
+    }))?;
+    assert_eq!(status(&processor, 1), Some(TransactionStatus::Resolved));
+    processor.process(Payment::Dispute(Dispute {
+        client: 1,
+        tx: 1,
+        amount: None,
+    }))?;
+    processor.process(Payment::Chargeback(Chargeback {
+        client: 1,
+        tx: 1,
+        amount: None,
+    }))?;
+    assert_eq!(status(&processor, 1), Some(TransactionStatus::ChargedBack));
+
+    Ok(())
+}
+
+#[test]
+fn reconcile_funds() -> Result<()> {
+    struct FlatFee(Amount);
+
+    impl FeePolicy for FlatFee {
+        fn on_chargeback(&self, amount: Amount) -> Amount {
+            amount + self.0
+        }
+    }
+
+    let mut processor = InMemoryProcessor::default()
+        .with_fee_policy(FlatFee(Amount(1)))
+        .with_undo(1);
+    processor.process_str(
+        "type,client,tx,amount
+        deposit,1,1,5.0
+        deposit,2,2,2.0
+        deposit,2,5,1.0
+        dispute,1,1,
+        resolve,1,1,
+        withdrawal,1,3,1.0
+        withdrawal,2,4,0.5
+        dispute,2,2,
+        chargeback,2,2,
+        reversal,1,3,",
+    );
+    processor.adjust_balance(1, SignedAmount(-5000), "correction".to_string())?;
+    processor.process(Payment::Deposit(Deposit {
+        client: 3,
+        tx: 6,
+        amount: Amount(1_0000),
+    }))?;
+    processor.undo_last();
+
+    assert_eq!(
+        processor.funds_flow(),
+        FundsFlow {
+            deposited: SignedAmount(8_0000),
+            withdrawn: SignedAmount(1_5000),
+            charged_back: SignedAmount(2_0001),
+            reversed: SignedAmount(1_0000),
+            adjusted: SignedAmount(-5000),
+        }
+    );
+    assert_eq!(
+        processor.reconcile(),
+        Reconciliation {
+            expected: SignedAmount(4_9999),
+            actual: SignedAmount(4_9999),
+        }
+    );
+    assert_eq!(processor.reconcile().discrepancy(), SignedAmount(0));
+
+    // the same, account by account
+    for client in [1, 2] {
+        let flow = processor.account_funds_flow(client).unwrap();
+        let total = processor.get_account(client).unwrap().total_funds;
+        assert_eq!(flow.expected_total(), SignedAmount(i128::from(total.0)));
+    }
+    assert_eq!(
+        processor.account_funds_flow(2).unwrap().charged_back,
+        SignedAmount(2_0001)
+    );
+    assert_eq!(processor.account_funds_flow(3), None);
+
+    Ok(())
+}
+
+#[test]
+fn processor_stats() -> Result<()> {
+    let mut processor = InMemoryProcessor::default();
+    let errors = processor.process_str(
+        "type,client,tx,amount
+        deposit,1,1,5.0
+        deposit,2,2,2.0
+        dispute,1,1,
+        resolve,1,1,
+        withdrawal,1,3,1.0
+        withdrawal,2,4,3.0
+        dispute,2,2,
+        chargeback,2,2,
+        reversal,1,3,
+        chargeback,1,1,
+        deposit,1,1,1.0
+        deposit,1,5,1.0
+        withdrawal,1,6,1.0
+        dispute,1,7,
+        dispute,1,8,
+        resolve,1,1,
+        reversal,1,3,",
+    );
+    assert_eq!(
+        errors,
+        vec![
+            (5, Error::Underflow { client: 2 }),
+            (9, Error::TransactionNotDisputed { client: 1, tx: 1 }),
+            (10, Error::TransactionAlreadyExists { client: 1, tx: 1 }),
+            (13, Error::TransactionNotFound { client: 1, tx: 7 }),
+            (14, Error::TransactionNotFound { client: 1, tx: 8 }),
+            (15, Error::TransactionNotDisputed { client: 1, tx: 1 }),
+            (16, Error::TransactionAlreadyReversed { client: 1, tx: 3 }),
+        ]
+    );
+    let stats = processor.stats();
+    assert_eq!(
+        stats,
+        ProcessorStats {
+            deposits: 3,
+            withdrawals: 2,
+            disputes: 2,
+            resolves: 1,
+            chargebacks: 1,
+            reversals: 1,
+            rejected: RejectedStats {
+                deposits: 1,
+                withdrawals: 1,
+                disputes: 2,
+                resolves: 1,
+                chargebacks: 1,
+                reversals: 1,
+            },
+        }
+    );
+    assert_eq!(stats.rejected.total(), 7);
+
+    Ok(())
+}
+
+#[test]
+fn deposit_into_locked_account() -> Result<()> {
+    let input = "type,client,tx,amount
+        deposit,1,1,5.0
+        deposit,1,2,1.0
+        dispute,1,1,
+        chargeback,1,1,
+        deposit,1,3,2.0
+        withdrawal,1,4,1.0";
+
+    let mut processor = InMemoryProcessor::default();
+    let errors = processor.process_str(input);
+    assert_eq!(
+        errors,
+        vec![
+            (4, Error::AccountLocked { client: 1 }),
+            (5, Error::AccountLocked { client: 1 })
+        ]
+    );
+    assert_eq!(*processor.get_account(1).unwrap().total_funds, 1_0000);
+
+    let mut processor = InMemoryProcessor::new(Config {
+        deposits_allowed_when_locked: true,
+        ..Default::default()
+    });
+    let errors = processor.process_str(input);
+    assert_eq!(errors, vec![(5, Error::AccountLocked { client: 1 })]);
+    let account = processor.get_account(1).unwrap();
+    assert!(account.locked());
+    assert_eq!(*account.total_funds, 3_0000);
+    assert_eq!(*account.available_funds(), 3_0000);
+
+    Ok(())
+}
+
+#[test]
+fn auto_unlock_on_clear() -> Result<()> {
+    let input = "type,client,tx,amount
+        deposit,1,1,5.0
+        deposit,1,2,1.0
+        dispute,1,1,
+        dispute,1,2,
+        chargeback,1,1,
+        resolve,1,2,";
+
+    let mut processor = InMemoryProcessor::default();
+    assert_eq!(processor.process_str(input), vec![]);
+    assert!(processor.get_account(1).unwrap().locked());
+
+    let mut processor = InMemoryProcessor::new(Config {
+        auto_unlock_on_clear: true,
+        ..Default::default()
+    });
+    assert_eq!(processor.process_str(input), vec![]);
+    let account = processor.get_account(1).unwrap();
+    assert!(!account.locked());
+    assert_eq!(*account.total_funds, 1_0000);
+    assert_eq!(*account.available_funds(), 1_0000);
+
+    // administrative freezes stay
+    processor.process(Payment::Deposit(Deposit {
+        client: 1,
+        tx: 3,
+        amount: Amount(1_0000),
+    }))?;
+    processor.process(Payment::Dispute(Dispute {
+        client: 1,
+        tx: 3,
+        amount: None,
+    }))?;
+    processor.freeze_account(1, FreezeReason::Administrative)?;
+    processor.process(Payment::Resolve(Resolve {
+        client: 1,
+        tx: 3,
+        amount: None,
+    }))?;
+    assert!(processor.get_account(1).unwrap().locked());
+
+    Ok(())
+}
+
+#[test]
+fn near_max_amounts_are_rejected() -> Result<()> {
+    // not a crash the `payments` fuzz target found (it hasn't found any),
+    // just the kind of sequence it generates, at the limits of the amounts
+    let client = 1;
+    let big = Amount(u64::MAX - 1);
+    let payments = [
+        Payment::Deposit(Deposit {
+            client,
+            tx: 0,
+            amount: big,
+        }),
+        Payment::Deposit(Deposit {
+            client,
+            tx: 1,
+            amount: big,
+        }),
+        Payment::Dispute(Dispute {
+            client,
+            tx: 1,
+            amount: None,
+        }),
+        Payment::Withdrawal(Withdrawal {
+            client,
+            tx: 2,
+            amount: Amount(u64::MAX),
+        }),
+        Payment::Reversal(Reversal {
+            client,
+            tx: 2,
+            amount: None,
+        }),
+        Payment::Dispute(Dispute {
+            client,
+            tx: 0,
+            amount: None,
+        }),
+        Payment::Chargeback(Chargeback {
+            client,
+            tx: 0,
+            amount: None,
+        }),
+        Payment::Resolve(Resolve {
+            client,
+            tx: 0,
+            amount: None,
+        }),
+    ];
+    let mut processor = InMemoryProcessor::default();
+    let results: Vec<_> = payments
+        .iter()
+        .map(|payment| processor.process(payment.clone()))
+        .collect();
+    assert_eq!(
+        results,
+        [
+            Ok(()),
+            Err(Error::Overflow {
+                client: client.into()
+            }),
+            Err(Error::TransactionNotFound {
+                client: client.into(),
+                tx: 1
+            }),
+            Err(Error::Underflow {
+                client: client.into()
+            }),
+            Err(Error::TransactionNotFound {
+                client: client.into(),
+                tx: 2
+            }),
+            Ok(()),
+            Ok(()),
+            Err(Error::TransactionNotDisputed {
+                client: client.into(),
+                tx: 0
+            }),
+        ]
+    );
+
+    let account = processor.get_account(client).unwrap();
+    assert!(account.held_funds <= account.total_funds);
+    assert_eq!(*account.total_funds, 0);
+
+    Ok(())
+}
+
+#[test]
+fn adjust_balance() -> Result<()> {
+    let mut processor = InMemoryProcessor::default();
+    assert_eq!(
+        processor.adjust_balance(1, SignedAmount(1_0000), "bonus".into()),
+        Err(Error::UnknownClient { client: 1 })
+    );
+
+    processor.process(Payment::Deposit(Deposit {
+        client: 1,
+        tx: 1,
+        amount: Amount(5_0000),
+    }))?;
+    processor.adjust_balance(1, SignedAmount(1_0000), "interest".into())?;
+    processor.adjust_balance(1, SignedAmount(-2_5000), "writedown".into())?;
+    let account = processor.get_account(1).unwrap();
+    assert_eq!(*account.total_funds, 3_5000);
+    assert_eq!(*account.available_funds(), 3_5000);
+
+    assert_eq!(
+        processor.adjust_balance(1, SignedAmount(-3_5001), "too much".into()),
+        Err(Error::AdjustmentExceedsFunds { client: 1 })
+    );
+    assert_eq!(
+        processor.adjust_balance(1, SignedAmount(-i128::MAX), "way too much".into()),
+        Err(Error::AdjustmentExceedsFunds { client: 1 })
+    );
+    assert_eq!(*processor.get_account(1).unwrap().total_funds, 3_5000);
+
+    // held funds are not available to take away either
+    processor.process(Payment::Deposit(Deposit {
+        client: 1,
+        tx: 2,
+        amount: Amount(1_0000),
+    }))?;
+    processor.process(Payment::Dispute(Dispute {
+        client: 1,
+        tx: 2,
+        amount: None,
+    }))?;
+    assert_eq!(
+        processor.adjust_balance(1, SignedAmount(-4_0000), "held".into()),
+        Err(Error::AdjustmentExceedsFunds { client: 1 })
+    );
+    assert_eq!(
+        processor
+            .adjust_balance(1, SignedAmount(-4_0000), "held".into())
+            .unwrap_err()
+            .to_string(),
+        "adjustment of client 1 larger than its available funds"
+    );
+    let account = processor.get_account(1).unwrap();
+    assert_eq!(*account.total_funds, 4_5000);
+    assert_eq!(*account.held_funds, 1_0000);
+
+    assert_eq!(
+        processor.get_adjustments(1).unwrap(),
+        [
+            Adjustment {
+                delta: SignedAmount(1_0000),
+                reason: "interest".into(),
+            },
+            Adjustment {
+                delta: SignedAmount(-2_5000),
+                reason: "writedown".into(),
+            },
+        ]
+    );
+
+    // in the history, in order with the transactions
+    let history: Vec<_> = processor
+        .get_history_entries(1)
+        .unwrap()
+        .into_iter()
+        .map(|entry| match entry {
+            HistoryEntry::Transaction(tx, _) => format!("tx {}", tx),
+            HistoryEntry::Adjustment(adjustment) => adjustment.reason.clone(),
+        })
+        .collect();
+    assert_eq!(history, ["tx 1", "interest", "writedown", "tx 2"]);
+    assert_eq!(processor.get_history_ordered(1).unwrap().len(), 2);
+
+    Ok(())
+}
+
+#[test]
+fn processors_equivalent_diff() -> Result<()> {
+    let input = "type,client,tx,amount
+        deposit,1,1,5.0
+        deposit,2,2,1.0
+        dispute,2,2,";
+    let mut a = InMemoryProcessor::default();
+    let mut b = InMemoryProcessor::default();
+    assert_eq!(a.process_str(input), vec![]);
+    assert_eq!(b.process_str(input), vec![]);
+    assert_eq!(processors_equivalent(&a, &b), Ok(()));
+
+    b.process(Payment::Resolve(Resolve {
+        client: 2,
+        tx: 2,
+        amount: None,
+    }))?;
+    let diff = processors_equivalent(&a, &b).unwrap_err();
+    assert_eq!(
+        diff,
+        ProcessorDiff {
+            client: "2".into(),
+            field: "held",
+            left: "1.0000".into(),
+            right: "0.0000".into(),
+        }
+    );
+    assert_eq!(
+        diff.to_string(),
+        "client 2: held differs (1.0000 vs 0.0000)"
+    );
+
+    a.process(Payment::Deposit(Deposit {
+        client: 0,
+        tx: 3,
+        amount: Amount(1),
+    }))?;
+    assert_eq!(
+        processors_equivalent(&a, &b).unwrap_err(),
+        ProcessorDiff {
+            client: "0".into(),
+            field: "account",
+            left: "present".into(),
+            right: "missing".into(),
+        }
+    );
+
+    // any id types
+    let mut a = InMemoryProcessor::<u64, u64>::with_config(Config::default());
+    let b = InMemoryProcessor::<u64, u64>::with_config(Config::default());
+    a.process(Payment::Deposit(Deposit {
+        client: u64::MAX,
+        tx: u64::MAX,
+        amount: Amount(1),
+    }))?;
+    assert_eq!(
+        processors_equivalent(&a, &b).unwrap_err().to_string(),
+        "client 18446744073709551615: account differs (present vs missing)"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn held_ratio_alerts() -> Result<()> {
+    let mut processor = InMemoryProcessor::default();
+    let errors = processor.process_str(
+        "type,client,tx,amount
+        deposit,1,1,8.0
+        deposit,1,2,2.0
+        dispute,1,1,
+        deposit,2,3,5.0
+        deposit,2,4,5.0
+        dispute,2,3,
+        deposit,3,5,1.0",
+    );
+    assert_eq!(errors, vec![]);
+
+    // 80 % and 50 % held
+    assert_eq!(processor.held_alerts(50), vec![1]);
+    assert_eq!(processor.held_alerts(49), vec![1, 2]);
+    assert_eq!(processor.held_alerts(80), vec![]);
+    assert!(!processor.get_account(3).unwrap().held_exceeds_percent(0));
+
+    Ok(())
+}
+
+#[test]
+fn is_disputed() -> Result<()> {
+    let mut processor = InMemoryProcessor::default();
+    let errors = processor.process_str(
+        "type,client,tx,amount
+        deposit,1,1,5.0
+        deposit,1,2,1.0
+        dispute,1,1,",
+    );
+    assert_eq!(errors, vec![]);
+    assert!(processor.is_disputed(1, 1));
+    assert!(!processor.is_disputed(1, 2));
+    assert!(!processor.is_disputed(1, 3));
+    assert!(!processor.is_disputed(2, 1));
+
+    processor.process(Payment::Resolve(Resolve {
+        client: 1,
+        tx: 1,
+        amount: None,
+    }))?;
+    assert!(!processor.is_disputed(1, 1));
+
+    Ok(())
+}
+
+#[test]
+fn total_held_funds() -> Result<()> {
+    let mut processor = InMemoryProcessor::default();
+    assert_eq!(processor.total_held_funds(), Ok(Amount(0)));
+
+    let errors = processor.process_str(
+        "type,client,tx,amount
+        deposit,1,1,5.0
+        deposit,1,2,1.0
+        deposit,2,3,2.5
+        dispute,1,1,
+        dispute,2,3,",
+    );
+    assert_eq!(errors, vec![]);
+    assert_eq!(processor.total_held_funds(), Ok(Amount(7_5000)));
+
+    processor.process(Payment::Deposit(Deposit {
+        client: 3,
+        tx: 4,
+        amount: Amount(u64::MAX),
+    }))?;
+    processor.process(Payment::Dispute(Dispute {
+        client: 3,
+        tx: 4,
+        amount: None,
+    }))?;
+    assert_eq!(
+        processor.total_held_funds(),
+        Err(Error::Overflow { client: 3 })
+    );
+
+    Ok(())
+}
+
+#[test]
+fn duplicate_resolve_policy() -> Result<()> {
+    let input = "type,client,tx,amount
+        deposit,1,1,5.0
+        dispute,1,1,
+        resolve,1,1,
+        resolve,1,1,
+        resolve,1,2,";
+
+    let mut processor = InMemoryProcessor::default();
+    assert_eq!(
+        processor.process_str(input),
+        vec![
+            (3, Error::TransactionNotDisputed { client: 1, tx: 1 }),
+            (4, Error::TransactionNotFound { client: 1, tx: 2 })
+        ]
+    );
+
+    let mut processor = InMemoryProcessor::new(Config {
+        duplicate_resolve_policy: DuplicateResolvePolicy::Ignore,
+        ..Default::default()
+    });
+    assert_eq!(
+        processor.process_str(input),
+        vec![(4, Error::TransactionNotFound { client: 1, tx: 2 })]
+    );
+    let account = processor.get_account(1).unwrap();
+    assert_eq!(*account.available_funds(), 5_0000);
+    assert_eq!(*account.held_funds, 0);
+
+    Ok(())
+}
+
+#[test]
+fn account_and_transaction_counts() -> Result<()> {
+    let mut processor = InMemoryProcessor::default();
+    assert_eq!(processor.account_count(), 0);
+    assert_eq!(processor.transaction_count(), 0);
+
+    let errors = processor.process_str(
+        "type,client,tx,amount
+        deposit,1,1,5.0
+        withdrawal,1,2,1.0
+        deposit,2,3,2.5
+        dispute,2,3,
+        deposit,3,4,1.0",
+    );
+    assert_eq!(errors, vec![]);
+    assert_eq!(processor.account_count(), 3);
+    assert_eq!(processor.transaction_count(), 4);
+
+    Ok(())
+}
+
+#[test]
+fn wide_client_ids() -> Result<()> {
+    let mut processor = InMemoryProcessor::<u64, u32>::with_config(Config::default());
+    let client = u64::from(u32::MAX) + 1;
+    processor.process(Payment::Deposit(Deposit {
+        client,
+        tx: 1,
+        amount: Amount(2_0000),
+    }))?;
+    processor.process(Payment::Deposit(Deposit {
+        client: 1,
+        tx: 1,
+        amount: Amount(1_0000),
+    }))?;
+    processor.process(Payment::Dispute(Dispute {
+        client,
+        tx: 1,
+        amount: None,
+    }))?;
+
+    assert_eq!(*processor.get_account(client).unwrap().held_funds, 2_0000);
+    assert!(processor.is_disputed(client, 1));
+    assert!(!processor.is_disputed(1, 1));
+    assert_eq!(processor.held_alerts(50), [client]);
+
+    let mut output = vec![];
+    processor
+        .write_accounts(&mut output, &OutputOptions::default())
+        .unwrap();
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        "client,available,held,total,locked\n\
+         1,1.0,0.0,1.0,false\n\
+         4294967296,0.0,2.0,2.0,false\n"
+    );
+    Ok(())
+}
+
+#[test]
+fn history_in_insertion_order() -> Result<()> {
+    let mut processor = InMemoryProcessor::default();
+    for (tx, amount) in [(5, 1_0000), (2, 2_0000), (9, 3_0000)] {
+        processor.process(Payment::Deposit(Deposit {
+            client: 1,
+            tx,
+            amount: Amount(amount),
+        }))?;
+    }
+    processor.process(Payment::Withdrawal(Withdrawal {
+        client: 1,
+        tx: 3,
+        amount: Amount(5000),
+    }))?;
+    // rejected ones are not recorded
+    assert!(processor
+        .process(Payment::Deposit(Deposit {
+            client: 1,
+            tx: 2,
+            amount: Amount(1),
+        }))
+        .is_err());
+
+    let history: Vec<_> = processor
+        .get_history_ordered(1)
+        .unwrap()
+        .into_iter()
+        .map(|(tx, past_tx)| (tx, past_tx.kind, *past_tx.amount))
+        .collect();
+    assert_eq!(
+        history,
+        [
+            (5, TransactionKind::Deposit, 1_0000),
+            (2, TransactionKind::Deposit, 2_0000),
+            (9, TransactionKind::Deposit, 3_0000),
+            (3, TransactionKind::Withdrawal, 5000),
+        ]
+    );
+    assert!(processor.get_history_ordered(2).is_none());
+    Ok(())
+}
+
+#[test]
+fn configurable_decimal_places() -> anyhow::Result<()> {
+    let mut processor = InMemoryProcessor::new(Config {
+        amount_decimal_places: Some(0),
+        ..Default::default()
+    });
+    let errors = processor.process_str(
+        "type,client,tx,amount
+        deposit,1,1,5
+        deposit,1,2,1.5
+        withdrawal,1,3,2",
+    );
+    assert_eq!(
+        errors,
+        [(
+            1,
+            Error::InvalidRecord(DeserializationError::ExcessPrecision("1.5".to_string()))
+        )]
+    );
+    assert_eq!(*processor.get_account(1).unwrap().total_funds, 3);
+    let mut output = vec![];
+    processor.write_accounts(&mut output, &OutputOptions::default())?;
+    assert_eq!(
+        String::from_utf8(output)?,
+        "client,available,held,total,locked\n1,3.0,0.0,3.0,false\n"
+    );
+
+    let mut processor = InMemoryProcessor::new(Config {
+        amount_decimal_places: Some(8),
+        ..Default::default()
+    });
+    let errors = processor.process_str(
+        "type,client,tx,amount
+        deposit,1,1,1.12345678
+        deposit,1,2,0.00000001
+        deposit,1,3,0.000000001",
+    );
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].0, 2);
+    assert_eq!(*processor.get_account(1).unwrap().total_funds, 1_12345679);
+    let mut output = vec![];
+    processor.write_accounts(&mut output, &OutputOptions::default())?;
+    assert_eq!(
+        String::from_utf8(output)?,
+        "client,available,held,total,locked\n1,1.12345679,0.0,1.12345679,false\n"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn amount_rounding() {
+    let mut processor = InMemoryProcessor::new(Config {
+        amount_decimal_places: Some(0),
+        amount_rounding: Some(RoundingMode::HalfEven),
+        ..Default::default()
+    });
+    let errors = processor.process_str(
+        "type,client,tx,amount
+        deposit,1,1,2.5
+        deposit,1,2,3.5",
+    );
+    assert_eq!(errors, []);
+    assert_eq!(*processor.get_account(1).unwrap().total_funds, 6);
+}
+
+#[test]
+fn dispute_of_spent_deposit() -> Result<()> {
+    let mut processor = InMemoryProcessor::default();
+    let errors = processor.process_str(
+        "type,client,tx,amount
+        deposit,1,1,5.0
+        deposit,1,2,1.0
+        withdrawal,1,3,4.0
+        dispute,1,1,
+        dispute,1,2,",
+    );
+    assert_eq!(
+        errors,
+        [(
+            3,
+            Error::DisputedFundsSpent {
+                client: 1,
+                tx: 1,
+                needed: Amount(5_0000),
+                available: Amount(2_0000),
+                decimal_places: AMOUNT_DECIMAL_PLACES,
+            }
+        )]
+    );
+    assert_eq!(
+        errors[0].1.to_string(),
+        "can't hold 5.0000 of the disputed transaction 1 of client 1, only 2.0000 available"
+    );
+    // the one still covered is held as usual
+    assert_eq!(*processor.get_account(1).unwrap().held_funds, 1_0000);
+    Ok(())
+}
+
+#[test]
+fn rate_limit_rejects_quick_payments() -> Result<()> {
+    let mut processor = InMemoryProcessor::<u16>::with_config(Config {
+        rate_limit: Some(RateLimit {
+            max: 2,
+            window: std::time::Duration::from_secs(60),
+        }),
+        ..Default::default()
+    });
+    let at = |time: &str| time.parse::<Timestamp>().unwrap();
+    let deposit = |tx| {
+        Payment::Deposit(Deposit {
+            client: 1,
+            tx,
+            amount: Amount(1_0000),
+        })
+    };
+
+    processor.process_at(deposit(1), at("2021-03-01T12:00:00Z"))?;
+    processor.process_at(deposit(2), at("2021-03-01T12:00:01Z"))?;
+    assert_eq!(
+        processor.process_at(deposit(3), at("2021-03-01T12:00:02Z")),
+        Err(Error::RateLimited { client: 1 })
+    );
+    // the first one has left the window
+    processor.process_at(deposit(4), at("2021-03-01T12:01:00Z"))?;
+
+    assert_eq!(*processor.get_account(1).unwrap().total_funds, 3_0000);
+    Ok(())
+}
+
+#[test]
+fn undo_leaves_the_rate_limit() -> Result<()> {
+    let mut processor = InMemoryProcessor::<u16>::with_config(Config {
+        rate_limit: Some(RateLimit {
+            max: 1,
+            window: std::time::Duration::from_secs(60),
+        }),
+        ..Default::default()
+    })
+    .with_undo(1);
+    let at = |time: &str| time.parse::<Timestamp>().unwrap();
+    let deposit = |tx| {
+        Payment::Deposit(Deposit {
+            client: 1,
+            tx,
+            amount: Amount(1_0000),
+        })
+    };
+
+    processor.process_at(deposit(1), at("2021-03-01T12:00:00Z"))?;
+    assert!(processor.undo_last().is_some());
+    // the undone payment doesn't count anymore
+    processor.process_at(deposit(2), at("2021-03-01T12:00:01Z"))?;
+
+    assert_eq!(*processor.get_account(1).unwrap().total_funds, 1_0000);
+    Ok(())
+}
+
+#[test]
+fn export_records_sorted_by_client() -> Result<()> {
+    let mut processor = InMemoryProcessor::default();
+    let errors = processor.process_str(
+        "type,client,tx,amount
+        deposit,3,1,2.0
+        deposit,1,2,1.5
+        deposit,2,3,4.0
+        withdrawal,3,4,0.5
+        dispute,2,3,
+        chargeback,2,3,",
+    );
+    assert_eq!(errors, []);
+
+    let record = |client, available: &str, total: &str, locked| RawOutputRecord {
+        client,
+        currency: None,
+        available: available.to_string(),
+        held: "0.0".to_string(),
+        total: total.to_string(),
+        locked,
+        transactions: None,
+        lock_reason: None,
+    };
+    assert_eq!(
+        processor.export_records(),
+        [
+            record(1, "1.5", "1.5", false),
+            record(2, "0.0", "0.0", true),
+            record(3, "1.5", "1.5", false),
+        ]
+    );
+    Ok(())
+}
+
+#[test]
+fn require_preregistration() -> Result<()> {
+    let input = "type,client,tx,amount
+        deposit,1,1,1.0
+        deposit,2,2,2.0
+        withdrawal,2,3,0.5";
+
+    // by default any client gets an account
+    let mut processor = InMemoryProcessor::default();
+    assert_eq!(processor.process_str(input), []);
+    assert_eq!(processor.account_count(), 2);
+
+    let mut processor = InMemoryProcessor::new(Config {
+        require_preregistration: true,
+        ..Default::default()
+    });
+    processor.register_client(2)?;
+    assert_eq!(
+        processor.process_str(input),
+        [(0, Error::UnknownClient { client: 1 })]
+    );
+    assert!(processor.get_account(1).is_none());
+    assert_eq!(*processor.get_account(2).unwrap().total_funds, 1_5000);
+
+    // registering again keeps the account
+    processor.register_client(2)?;
+    assert_eq!(*processor.get_account(2).unwrap().total_funds, 1_5000);
     Ok(())
 }
@@ -0,0 +1,75 @@
+//! HTTP interface to a processor
+//!
+//! - `POST /payments` processes a payment given as a JSON object, in the
+//!   format of the JSON input (`{"type": "deposit", "client": 1, "tx": 1, "amount": 1.5}`)
+//! - `GET /accounts/{client}` returns the `AccountState` of a client as JSON
+//!
+//! Rejected payments get a 4xx status (see `status_code`) with the error
+//! message as the body.
+use crate::async_processor::{AsyncProcessor, ProcessorHandle};
+use crate::payment::{ClientID, JsonPayment};
+use crate::processor::{AccountState, Error};
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::routing::{get, post};
+use axum::{Json, Router};
+
+pub fn router(processor: ProcessorHandle) -> Router {
+    Router::new()
+        .route("/payments", post(submit_payment))
+        .route("/accounts/:client", get(get_account))
+        .with_state(processor)
+}
+
+/// Serve the `router` of `processor` on `listener`, forever
+pub async fn serve(
+    listener: tokio::net::TcpListener,
+    processor: ProcessorHandle,
+) -> std::io::Result<()> {
+    axum::serve(listener, router(processor)).await
+}
+
+async fn submit_payment(
+    State(processor): State<ProcessorHandle>,
+    Json(payment): Json<JsonPayment>,
+) -> (StatusCode, String) {
+    let payment = match payment.into_payment(
+        Some(processor.amount_decimal_places()),
+        processor.amount_rounding(),
+    ) {
+        Ok(payment) => payment,
+        Err(e) => return (StatusCode::BAD_REQUEST, e.to_string()),
+    };
+    match processor.process(payment).await {
+        Ok(()) => (StatusCode::OK, String::new()),
+        Err(e) => (status_code(&e), e.to_string()),
+    }
+}
+
+async fn get_account(
+    State(processor): State<ProcessorHandle>,
+    Path(client): Path<ClientID>,
+) -> Result<Json<AccountState>, StatusCode> {
+    processor
+        .get_account(client)
+        .await
+        .map(Json)
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+/// HTTP status of a rejected payment
+pub fn status_code(error: &Error) -> StatusCode {
+    match error {
+        Error::TransactionAlreadyExists { .. }
+        | Error::TransactionConflict { .. }
+        | Error::TransactionAlreadyDisputed { .. }
+        | Error::TransactionChargedBack { .. }
+        | Error::TransactionDisputeClosed { .. }
+        | Error::TransactionAlreadyReversed { .. } => StatusCode::CONFLICT,
+        Error::AccountLocked { .. } => StatusCode::LOCKED,
+        Error::TransactionNotFound { .. } | Error::UnknownClient { .. } => StatusCode::NOT_FOUND,
+        Error::InvalidRecord(_) | Error::MalformedRecord(_) => StatusCode::BAD_REQUEST,
+        // valid, but not acceptable in the current state of the account
+        _ => StatusCode::UNPROCESSABLE_ENTITY,
+    }
+}
@@ -0,0 +1,87 @@
+//! Synthetic CSV input, eg. for benchmarks
+//!
+//! The rows are generated by a fixed pseudo-random sequence, so the same
+//! size always gives the same input. Every row parses; as in real inputs,
+//! some are still rejected by a processor (eg. withdrawals beyond the
+//! available funds, or disputes of already disputed deposits).
+use crate::payment::{Amount, ClientID, TransactionID};
+use std::fmt::Write;
+
+/// xorshift64, plenty for spreading rows over clients and types
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+}
+
+/// CSV (with a header) of `rows` payments of clients `1..=clients`
+///
+/// About 70% deposits, 20% withdrawals and 5% each disputes and
+/// resolves, which refer to earlier deposits of the same client.
+pub fn synthetic_csv(rows: usize, clients: ClientID) -> String {
+    assert!(0 < clients, "need at least one client");
+    let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+    let mut csv = String::with_capacity(32 * (rows + 1));
+    csv.push_str("type,client,tx,amount\n");
+
+    let mut deposits: Vec<(ClientID, TransactionID)> = vec![];
+    let mut disputed: Vec<(ClientID, TransactionID)> = vec![];
+    let mut next_tx: TransactionID = 1;
+    for _ in 0..rows {
+        let roll = rng.below(100);
+        // `writeln!` into a `String` can't fail
+        let _ = match roll {
+            90..=94 if !deposits.is_empty() => {
+                let (client, tx) = deposits[rng.below(deposits.len() as u64) as usize];
+                disputed.push((client, tx));
+                writeln!(csv, "dispute,{},{},", client, tx)
+            }
+            95..=99 if !disputed.is_empty() => {
+                let (client, tx) = disputed.swap_remove(rng.below(disputed.len() as u64) as usize);
+                writeln!(csv, "resolve,{},{},", client, tx)
+            }
+            _ => {
+                let client = rng.below(u64::from(clients)) as ClientID + 1;
+                let tx = next_tx;
+                next_tx = next_tx.wrapping_add(1);
+                // up to 1000.0000
+                let amount = Amount(rng.below(1000_0000) + 1);
+                if roll < 70 {
+                    deposits.push((client, tx));
+                    writeln!(csv, "deposit,{},{},{}", client, tx, amount)
+                } else {
+                    writeln!(csv, "withdrawal,{},{},{}", client, tx, amount)
+                }
+            }
+        };
+    }
+    csv
+}
+
+#[test]
+fn synthetic_rows_parse() -> anyhow::Result<()> {
+    use crate::payment::{input_reader, Payment, RawInputRecord};
+    use std::convert::TryInto;
+
+    let csv = synthetic_csv(1000, 10);
+    assert_eq!(csv, synthetic_csv(1000, 10));
+
+    let mut reader = input_reader(csv.as_bytes())?;
+    let mut count = 0;
+    for record in reader.deserialize::<RawInputRecord>() {
+        let payment: Payment = record?.try_into()?;
+        assert!((1..=10).contains(&payment.get_client_id()));
+        count += 1;
+    }
+    assert_eq!(count, 1000);
+    Ok(())
+}
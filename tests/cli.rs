@@ -0,0 +1,1127 @@
+use std::path::PathBuf;
+use std::process::{Command, Output, Stdio};
+
+const SAMPLE: &str = "type,client,tx,amount
+deposit,1,1,1.0
+deposit,2,2,2.0
+deposit,1,3,2.0
+withdrawal,1,4,1.5
+withdrawal,2,5,3.0
+";
+
+/// Directory of the files of a single test, removed along with them on drop
+struct TempDir(PathBuf);
+
+impl TempDir {
+    /// Empty directory in the temp dir, unique to the test `name` and run
+    fn new(name: &str) -> Self {
+        let path =
+            std::env::temp_dir().join(format!("payengine-test-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+        TempDir(path)
+    }
+
+    /// Path of the file `name` in the directory
+    fn path(&self, name: &str) -> PathBuf {
+        self.0.join(name)
+    }
+
+    /// Write `contents` into the CSV file `name` in the directory
+    fn write_input(&self, name: &str, contents: &str) -> PathBuf {
+        let path = self.path(&format!("{}.csv", name));
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+fn payengine(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_payengine"))
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn query_existing_client() {
+    let dir = TempDir::new("query_existing_client");
+    let input = dir.write_input("input", SAMPLE);
+    let output = payengine(&[input.to_str().unwrap(), "query", "--client", "1"]);
+
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "client,available,held,total,locked\n1,1.5,0.0,1.5,false\n"
+    );
+}
+
+#[test]
+fn query_missing_client() {
+    let dir = TempDir::new("query_missing_client");
+    let input = dir.write_input("input", SAMPLE);
+    let output = payengine(&[input.to_str().unwrap(), "query", "--client", "7"]);
+
+    assert!(!output.status.success());
+    assert!(String::from_utf8(output.stdout)
+        .unwrap()
+        .contains("client 7 not found"));
+}
+
+#[test]
+fn expected_clients() {
+    let dir = TempDir::new("expected_clients");
+    let input = dir.write_input("input", SAMPLE);
+    let output = payengine(&[input.to_str().unwrap(), "--expected-clients", "100000"]);
+
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "client,available,held,total,locked\n1,1.5,0.0,1.5,false\n2,2.0,0.0,2.0,false\n"
+    );
+}
+
+#[test]
+fn as_of_cutoff() {
+    let dir = TempDir::new("as_of_cutoff");
+    let input = dir.write_input(
+        "input",
+        "type,client,tx,amount,timestamp
+deposit,1,1,1.0,2021-03-01T10:00:00Z
+deposit,2,2,2.0,2021-03-01T12:00:00+01:00
+withdrawal,1,3,0.5,2021-03-01T11:00:00Z
+deposit,2,4,4.0,2021-03-01T12:00:00Z
+withdrawal,2,5,1.0,2021-03-02T00:00:00Z
+",
+    );
+    let output = payengine(&[input.to_str().unwrap(), "--as-of", "2021-03-01T11:00:00Z"]);
+
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "client,available,held,total,locked\n1,0.5,0.0,0.5,false\n2,2.0,0.0,2.0,false\n"
+    );
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .contains("Skipped 2 records after the cutoff"));
+}
+
+#[test]
+fn per_file() {
+    let dir = TempDir::new("per_file");
+    let day1 = dir.write_input("day1", SAMPLE);
+    let day2 = dir.write_input(
+        "day2",
+        "type,client,tx,amount
+deposit,1,1,3.0
+withdrawal,1,2,1.0
+deposit,3,3,1.0
+",
+    );
+    let output = payengine(&[day1.to_str().unwrap(), day2.to_str().unwrap(), "--per-file"]);
+
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "");
+    assert_eq!(
+        std::fs::read_to_string(day1.with_extension("out.csv")).unwrap(),
+        "client,available,held,total,locked\n1,1.5,0.0,1.5,false\n2,2.0,0.0,2.0,false\n"
+    );
+    // tx 1 isn't a duplicate of the one in the first file
+    assert_eq!(
+        std::fs::read_to_string(day2.with_extension("out.csv")).unwrap(),
+        "client,available,held,total,locked\n1,2.0,0.0,2.0,false\n3,1.0,0.0,1.0,false\n"
+    );
+}
+
+#[test]
+fn per_file_output_options() {
+    let dir = TempDir::new("per_file_output_options");
+    let input = dir.write_input(
+        "input",
+        "type,client,tx,amount
+deposit,1,1,1.0
+deposit,2,2,3.0
+dispute,2,2,
+",
+    );
+    let output = payengine(&[
+        input.to_str().unwrap(),
+        "--per-file",
+        "--sort-by",
+        "total",
+        "--with-counts",
+        "--alerts",
+        "50",
+    ]);
+
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stderr).unwrap(),
+        "Alert: client 2 has over 50% of funds held\n"
+    );
+    assert_eq!(
+        std::fs::read_to_string(input.with_extension("out.csv")).unwrap(),
+        "client,available,held,total,locked,transactions\n2,0.0,3.0,3.0,false,1\n1,1.0,0.0,1.0,false,1\n"
+    );
+}
+
+#[test]
+fn explain() {
+    let dir = TempDir::new("explain");
+    let input = dir.write_input(
+        "input",
+        "type,client,tx,amount
+deposit,1,1,5.0
+withdrawal,1,2,1.5
+deposit,1,3,2.0
+dispute,1,3,
+resolve,1,3,
+deposit,2,4,1.0
+",
+    );
+    let output = payengine(&[input.to_str().unwrap(), "--explain"]);
+
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stderr).unwrap(),
+        "Client 1: deposited 7.0000, withdrawn 1.5000, charged back 0.0000, reversed 0.0000, \
+         adjusted 0.0000 => total 5.5000, held 0.0000, available 5.5000
+Client 2: deposited 1.0000, withdrawn 0.0000, charged back 0.0000, reversed 0.0000, \
+         adjusted 0.0000 => total 1.0000, held 0.0000, available 1.0000
+"
+    );
+}
+
+#[test]
+fn manifest_skips_processed_files() {
+    let dir = TempDir::new("manifest_skips_processed_files");
+    let input = dir.write_input("input", SAMPLE);
+    let manifest = dir.path("manifest.txt");
+    let args = [
+        input.to_str().unwrap(),
+        "--manifest",
+        manifest.to_str().unwrap(),
+    ];
+
+    let output = payengine(&args);
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "client,available,held,total,locked\n1,1.5,0.0,1.5,false\n2,2.0,0.0,2.0,false\n"
+    );
+
+    // nothing processed, so not even a header
+    let output = payengine(&args);
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "");
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .contains("it was processed already"));
+    assert_eq!(
+        std::fs::read_to_string(&manifest).unwrap().lines().count(),
+        1
+    );
+}
+
+#[test]
+fn integer_amounts() {
+    let dir = TempDir::new("integer_amounts");
+    let input = dir.write_input("input", SAMPLE);
+    let output = payengine(&[input.to_str().unwrap(), "--integer-amounts"]);
+
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "client,available,held,total,locked\n1,15000,0,15000,false\n2,20000,0,20000,false\n"
+    );
+
+    let output = payengine(&[
+        input.to_str().unwrap(),
+        "--integer-amounts",
+        "query",
+        "--client",
+        "1",
+    ]);
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "client,available,held,total,locked\n1,15000,0,15000,false\n"
+    );
+}
+
+#[test]
+fn skip_empty_type() {
+    let dir = TempDir::new("skip_empty_type");
+    let input = dir.write_input(
+        "input",
+        "type,client,tx,amount
+deposit,1,1,1.0
+  ,,,
+transfer,1,2,1.0
+deposit,2,3,2.0
+",
+    );
+    let output = payengine(&[input.to_str().unwrap(), "--skip-empty-type"]);
+
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "client,available,held,total,locked\n1,1.0,0.0,1.0,false\n2,2.0,0.0,2.0,false\n"
+    );
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("Skipped 1 records without a type"));
+    // unknown types are still reported
+    assert!(stderr.contains("invalid type value: transfer"));
+
+    let output = payengine(&[
+        input.to_str().unwrap(),
+        "--skip-empty-type",
+        "--ignore-unknown-types",
+    ]);
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(!stderr.contains("invalid type value"));
+    assert!(stderr.contains("Skipped 1 records of unknown types"));
+}
+
+#[test]
+fn missing_input_file() {
+    let output = payengine(&["/nonexistent/transactions.csv"]);
+
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "terminated due to error: failed to open input file /nonexistent/transactions.csv: \
+         file does not exist, is the path correct?\n"
+    );
+}
+
+#[test]
+fn multiple_input_files() {
+    let dir = TempDir::new("multiple_input_files");
+    let first = dir.write_input("first", SAMPLE);
+    let second = dir.write_input(
+        "second",
+        "type,client,tx,amount
+dispute,2,2,
+deposit,2,6,1.0
+resolve,1,1,
+",
+    );
+    let output = payengine(&[
+        first.to_str().unwrap(),
+        second.to_str().unwrap(),
+        "query",
+        "--client",
+        "2",
+    ]);
+
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "client,available,held,total,locked\n2,1.0,2.0,3.0,false\n"
+    );
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains(&format!(
+        "Error while processing record 2 of {}",
+        second.display()
+    )));
+    assert!(stderr.contains(&format!(
+        "Error while processing record 4 of {}",
+        first.display()
+    )));
+}
+
+#[test]
+fn two_pass_resolve_before_dispute() {
+    let dir = TempDir::new("two_pass_resolve_before_dispute");
+    let input = dir.write_input(
+        "input",
+        "type,client,tx,amount
+resolve,1,1,
+chargeback,2,2,
+dispute,1,1,
+dispute,2,2,
+deposit,1,1,1.0
+deposit,2,2,1.0
+",
+    );
+
+    let output = payengine(&[input.to_str().unwrap()]);
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "client,available,held,total,locked\n1,1.0,0.0,1.0,false\n2,1.0,0.0,1.0,false\n"
+    );
+
+    let output = payengine(&[input.to_str().unwrap(), "--two-pass"]);
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "client,available,held,total,locked\n1,1.0,0.0,1.0,false\n2,0.0,0.0,0.0,true\n"
+    );
+    assert_eq!(String::from_utf8(output.stderr).unwrap(), "");
+}
+
+#[test]
+fn two_pass_redispute() {
+    let dir = TempDir::new("two_pass_redispute");
+    let input = dir.write_input(
+        "input",
+        "type,client,tx,amount
+dispute,1,1,
+resolve,1,1,
+dispute,1,1,
+chargeback,1,1,
+deposit,1,1,1.0
+deposit,1,2,2.0
+",
+    );
+
+    // the second dispute is charged back, not resolved
+    let output = payengine(&[input.to_str().unwrap(), "--two-pass"]);
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "client,available,held,total,locked\n1,2.0,0.0,2.0,true\n"
+    );
+    assert_eq!(String::from_utf8(output.stderr).unwrap(), "");
+}
+
+#[test]
+fn dedup_identical_records() {
+    let dir = TempDir::new("dedup_identical_records");
+    let input = dir.write_input(
+        "input",
+        "type,client,tx,amount
+deposit,1,1,1.0
+deposit,1,1,1.0
+withdrawal,1,2,0.5
+",
+    );
+
+    let output = payengine(&[input.to_str().unwrap()]);
+    assert!(output.status.success());
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .contains("Error while processing record 1"));
+
+    let output = payengine(&[input.to_str().unwrap(), "--dedup"]);
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "client,available,held,total,locked\n1,0.5,0.0,0.5,false\n"
+    );
+    assert_eq!(
+        String::from_utf8(output.stderr).unwrap(),
+        "Skipped 1 duplicate records\n"
+    );
+}
+
+#[test]
+fn dedup_compares_timestamps() {
+    let dir = TempDir::new("dedup_compares_timestamps");
+    let input = dir.write_input(
+        "input",
+        "type,client,tx,amount,timestamp
+deposit,1,1,1.0,2021-03-01T00:00:00Z
+deposit,1,1,1.00,2021-03-01T01:00:00+01:00
+deposit,1,1,1.0,2021-03-01T00:00:01Z
+",
+    );
+
+    // the last one is not a re-delivery, but a conflicting deposit
+    let output = payengine(&[input.to_str().unwrap(), "--dedup"]);
+    assert!(output.status.success());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("Error while processing record 2"));
+    assert!(stderr.contains("Skipped 1 duplicate records"));
+}
+
+#[test]
+fn broken_pipe_on_output() {
+    let dir = TempDir::new("broken_pipe_on_output");
+    let mut contents = String::from("type,client,tx,amount\n");
+    for client in 0..10_000 {
+        contents.push_str(&format!("deposit,{},{},1.0\n", client, client));
+    }
+    let input = dir.write_input("input", &contents);
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_payengine"))
+        .arg(&input)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    // close the reading end before anything gets written
+    drop(child.stdout.take());
+    let output = child.wait_with_output().unwrap();
+
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8(output.stderr).unwrap(), "");
+}
+
+#[test]
+fn multi_currency_balances() {
+    let dir = TempDir::new("multi_currency_balances");
+    let input = dir.write_input(
+        "input",
+        "type,client,tx,amount,currency
+deposit,1,1,10.0,USD
+deposit,1,2,3.0,EUR
+withdrawal,1,3,1.0,EUR
+dispute,1,1,,
+deposit,2,4,1.0,EUR
+deposit,3,5,1.0,EUR
+withdrawal,3,6,1.0,EUR
+",
+    );
+
+    let output = payengine(&[input.to_str().unwrap(), "--multi-currency"]);
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "client,currency,available,held,total,locked
+1,EUR,2.0,0.0,2.0,false
+1,USD,0.0,10.0,10.0,false
+2,EUR,1.0,0.0,1.0,false
+3,EUR,0.0,0.0,0.0,false
+"
+    );
+
+    let output = payengine(&[
+        input.to_str().unwrap(),
+        "--multi-currency",
+        "--sort-by",
+        "total",
+        "--with-counts",
+        "--skip-zero",
+    ]);
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "client,currency,available,held,total,locked,transactions
+1,USD,0.0,10.0,10.0,false,1
+1,EUR,2.0,0.0,2.0,false,2
+2,EUR,1.0,0.0,1.0,false,1
+"
+    );
+
+    // without the flag the column is ignored
+    let output = payengine(&[input.to_str().unwrap(), "query", "--client", "1"]);
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "client,available,held,total,locked\n1,2.0,10.0,12.0,false\n"
+    );
+}
+
+#[test]
+fn progress_reports() {
+    let dir = TempDir::new("progress_reports");
+    let input = dir.write_input("input", SAMPLE);
+
+    let output = payengine(&[input.to_str().unwrap(), "--progress", "2"]);
+    assert!(output.status.success());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    let progress: Vec<_> = stderr
+        .lines()
+        .filter(|line| line.starts_with("Processed"))
+        .collect();
+    assert_eq!(
+        progress,
+        [
+            "Processed 2 records, 0 errors",
+            "Processed 4 records, 0 errors"
+        ]
+    );
+
+    let output = payengine(&[input.to_str().unwrap()]);
+    assert!(!String::from_utf8(output.stderr)
+        .unwrap()
+        .contains("Processed"));
+}
+
+#[test]
+fn preview_table() {
+    let dir = TempDir::new("preview_table");
+    let input = dir.write_input("input", SAMPLE);
+    let output = payengine(&[input.to_str().unwrap(), "--preview"]);
+
+    assert!(output.status.success());
+    // the CSV output is unchanged
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "client,available,held,total,locked\n1,1.5,0.0,1.5,false\n2,2.0,0.0,2.0,false\n"
+    );
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    // after the report of the rejected withdrawal
+    let table: Vec<_> = stderr.lines().skip(1).collect();
+    assert_eq!(
+        table,
+        [
+            "client | available |   held |  total | locked",
+            "     1 |    1.5000 | 0.0000 | 1.5000 |  false",
+            "     2 |    2.0000 | 0.0000 | 2.0000 |  false",
+        ]
+    );
+}
+
+#[test]
+fn held_alerts() {
+    let dir = TempDir::new("held_alerts");
+    let input = dir.write_input(
+        "input",
+        "type,client,tx,amount
+deposit,1,1,1.0
+deposit,2,2,1.0
+dispute,2,2,
+",
+    );
+    let output = payengine(&[input.to_str().unwrap(), "--alerts", "50"]);
+
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stderr).unwrap(),
+        "Alert: client 2 has over 50% of funds held\n"
+    );
+}
+
+#[test]
+fn bad_records_are_skipped() {
+    let dir = TempDir::new("bad_records_are_skipped");
+    let input = dir.write_input(
+        "input",
+        "type,client,tx,amount
+deposit,1,1,1.0
+deposit,1,2,lots
+transfer,1,3,1.0
+deposit,1,4
+deposit,1,5,2.0
+",
+    );
+    let output = payengine(&[input.to_str().unwrap(), "--progress", "5"]);
+
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "client,available,held,total,locked\n1,3.0,0.0,3.0,false\n"
+    );
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    for i in 1..=3 {
+        assert!(stderr.contains(&format!(
+            "Error while parsing record {} of {}",
+            i,
+            input.display()
+        )));
+    }
+    assert!(stderr.contains("Processed 5 records, 3 errors"));
+}
+
+#[test]
+fn amount_thousands_separator() {
+    let dir = TempDir::new("amount_thousands_separator");
+    let input = dir.write_input(
+        "input",
+        "type,client,tx,amount
+deposit,1,1,\"1,234.5\"
+",
+    );
+
+    let output = payengine(&[input.to_str().unwrap(), "--amount-thousands-sep", ","]);
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "client,available,held,total,locked\n1,1234.5,0.0,1234.5,false\n"
+    );
+
+    let output = payengine(&[input.to_str().unwrap()]);
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "");
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .contains("Error while parsing record 0"));
+}
+
+#[test]
+fn strict_precision() {
+    let dir = TempDir::new("strict_precision");
+    let input = dir.write_input(
+        "input",
+        "type,client,tx,amount
+deposit,1,1,1.00001
+deposit,1,2,1.00005
+",
+    );
+
+    let output = payengine(&[input.to_str().unwrap(), "--strict-precision"]);
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "");
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .contains("amount has more decimal places than supported: 1.00001"));
+
+    // rounded half up by default
+    let output = payengine(&[input.to_str().unwrap()]);
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "client,available,held,total,locked\n1,2.0001,0.0,2.0001,false\n"
+    );
+
+    let output = payengine(&[input.to_str().unwrap(), "--round-amounts", "truncate"]);
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "client,available,held,total,locked\n1,2.0,0.0,2.0,false\n"
+    );
+
+    let output = payengine(&[
+        input.to_str().unwrap(),
+        "--strict-precision",
+        "--round-amounts",
+        "truncate",
+    ]);
+    assert!(!output.status.success());
+}
+
+#[test]
+fn amount_decimal_places() {
+    let dir = TempDir::new("amount_decimal_places");
+    let input = dir.write_input(
+        "input",
+        "type,client,tx,amount
+deposit,1,1,1.12345678
+deposit,1,2,0.00000001
+dispute,1,2,
+",
+    );
+
+    let output = payengine(&[
+        input.to_str().unwrap(),
+        "--amount-decimal-places",
+        "8",
+        "--preview",
+        "--explain",
+    ]);
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "client,available,held,total,locked\n1,1.12345678,0.00000001,1.12345679,false\n"
+    );
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("| 1.12345678 | 0.00000001 | 1.12345679 |"));
+    assert!(stderr.contains("deposited 1.12345679, withdrawn 0.00000000"));
+}
+
+#[test]
+fn fail_on_locked() {
+    let dir = TempDir::new("fail_on_locked");
+    let input = dir.write_input(
+        "input",
+        "type,client,tx,amount
+deposit,1,1,1.0
+deposit,2,2,1.0
+dispute,2,2,
+chargeback,2,2,
+",
+    );
+
+    let output = payengine(&[input.to_str().unwrap(), "--fail-on-locked"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "client,available,held,total,locked
+1,1.0,0.0,1.0,false
+2,0.0,0.0,0.0,true
+terminated due to error: 1 account(s) ended up locked
+"
+    );
+
+    let clean = dir.write_input("clean", SAMPLE);
+    let output = payengine(&[clean.to_str().unwrap(), "--fail-on-locked"]);
+    assert!(output.status.success());
+}
+
+#[test]
+fn skip_zero_accounts() {
+    let dir = TempDir::new("skip_zero_accounts");
+    let input = dir.write_input(
+        "input",
+        "type,client,tx,amount
+deposit,1,1,1.0
+withdrawal,1,2,1.0
+deposit,2,3,1.0
+deposit,3,4,1.0
+dispute,3,4,
+chargeback,3,4,
+",
+    );
+
+    let output = payengine(&[input.to_str().unwrap()]);
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "client,available,held,total,locked
+1,0.0,0.0,0.0,false
+2,1.0,0.0,1.0,false
+3,0.0,0.0,0.0,true
+"
+    );
+
+    // the locked one stays
+    let output = payengine(&[input.to_str().unwrap(), "--skip-zero"]);
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "client,available,held,total,locked
+2,1.0,0.0,1.0,false
+3,0.0,0.0,0.0,true
+"
+    );
+}
+
+#[test]
+fn max_rows_and_bytes() {
+    let dir = TempDir::new("max_rows_and_bytes");
+    let mut contents = String::from("type,client,tx,amount\n");
+    for tx in 1..=10 {
+        contents.push_str(&format!("deposit,1,{},1.0\n", tx));
+    }
+    let input = dir.write_input("input", &contents);
+
+    let output = payengine(&[
+        input.to_str().unwrap(),
+        "--max-rows",
+        "5",
+        "--progress",
+        "1",
+    ]);
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "terminated due to error: input has more than 5 rows\n"
+    );
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .ends_with("Processed 5 records, 0 errors\n"));
+
+    let output = payengine(&[input.to_str().unwrap(), "--max-rows", "10"]);
+    assert!(output.status.success());
+
+    let output = payengine(&[input.to_str().unwrap(), "--max-bytes", "100"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8(output.stdout)
+        .unwrap()
+        .contains("input file is larger than 100 bytes"));
+
+    let output = payengine(&[input.to_str().unwrap(), "--max-bytes", "1000"]);
+    assert!(output.status.success());
+}
+
+#[test]
+fn reconcile() {
+    let dir = TempDir::new("reconcile");
+    let input = dir.write_input(
+        "input",
+        "type,client,tx,amount
+deposit,1,1,5.0
+deposit,2,2,2.0
+withdrawal,1,3,1.5
+dispute,2,2,
+chargeback,2,2,
+withdrawal,1,4,10.0
+",
+    );
+    let output = payengine(&[input.to_str().unwrap(), "--reconcile"]);
+    assert!(output.status.success());
+    // the rejected withdrawal is reported before
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .ends_with("\nReconciliation: expected 3.5000, actual 3.5000, discrepancy 0.0000\n"));
+}
+
+#[test]
+fn dead_letter() {
+    let dir = TempDir::new("dead_letter");
+    let input = dir.write_input(
+        "input",
+        "type,client,tx,amount,timestamp
+deposit,1,1,5.0,2021-03-01T00:00:00Z
+withdrawal,1,2,7.5,2021-03-01T00:00:01Z
+refund,1,3,1.0,
+deposit,2,4,1.0,2021-03-01T00:00:03Z
+",
+    );
+    let dead_letter = dir.path("dead-letter-out.csv");
+    let output = payengine(&[
+        input.to_str().unwrap(),
+        "--dead-letter",
+        dead_letter.to_str().unwrap(),
+    ]);
+    assert!(output.status.success());
+    assert_eq!(
+        std::fs::read_to_string(&dead_letter).unwrap(),
+        "type,client,tx,amount,currency,timestamp,error
+withdrawal,1,2,7.5,,2021-03-01T00:00:01Z,balance underflow in account of client 1
+refund,1,3,1.0,,,invalid type value: refund
+"
+    );
+}
+
+#[test]
+fn invalid_utf8_rows_are_skipped() {
+    let dir = TempDir::new("invalid_utf8_rows_are_skipped");
+    let input = dir.write_input("input", "");
+    std::fs::write(
+        &input,
+        b"type,client,tx,amount\ndeposit,1,1,1.0\ndeposit,2,2,\xff2.0\ndeposit,2,3,2.0\n",
+    )
+    .unwrap();
+    let dead_letter = dir.path("invalid-utf8-dead-letter.csv");
+    let output = payengine(&[
+        input.to_str().unwrap(),
+        "--dead-letter",
+        dead_letter.to_str().unwrap(),
+    ]);
+
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "client,available,held,total,locked\n1,1.0,0.0,1.0,false\n2,2.0,0.0,2.0,false\n"
+    );
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .contains("record 1 of"));
+    assert_eq!(
+        std::fs::read_to_string(&dead_letter).unwrap(),
+        "type,client,tx,amount,currency,timestamp,error
+deposit,2,2,\u{fffd}2.0,,,invalid UTF-8 in the amount column
+"
+    );
+}
+
+#[test]
+fn audit() {
+    let dir = TempDir::new("audit");
+    let input = dir.write_input(
+        "input",
+        "type,client,tx,amount
+deposit,2,1,5.0
+deposit,1,2,2.0
+withdrawal,2,3,1.5
+dispute,1,2,
+chargeback,1,2,
+withdrawal,2,4,10.0
+",
+    );
+    let audit = dir.path("audit-out.csv");
+    let output = payengine(&[input.to_str().unwrap(), "--audit", audit.to_str().unwrap()]);
+    assert!(output.status.success());
+    assert_eq!(
+        std::fs::read_to_string(&audit).unwrap(),
+        "client,tx,type,amount,status
+1,2,deposit,2.0000,charged_back
+2,1,deposit,5.0000,normal
+2,3,withdrawal,1.5000,normal
+"
+    );
+}
+
+#[test]
+fn quiet() {
+    let dir = TempDir::new("quiet");
+    let input = dir.write_input(
+        "input",
+        "type,client,tx,amount
+deposit,1,1,5.0
+withdrawal,1,2,7.5
+refund,1,3,1.0
+",
+    );
+    let output = payengine(&[input.to_str().unwrap(), "--quiet"]);
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stderr).unwrap(),
+        "Processed 3 records, 2 errors\n"
+    );
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "client,available,held,total,locked\n1,5.0,0.0,5.0,false\n"
+    );
+}
+
+#[test]
+fn diff_output_files() {
+    let dir = TempDir::new("diff_output_files");
+    let a = dir.write_input(
+        "a",
+        "client,available,held,total,locked
+1,1.5,0.0,1.5,false
+2,1.0,1.0,2.0,false
+",
+    );
+    let b = dir.write_input(
+        "b",
+        "client,available,held,total,locked
+1,1.5,0.0,1.5,false
+2,0.0,2.0,2.0,false
+",
+    );
+
+    let output = payengine(&["diff", a.to_str().unwrap(), b.to_str().unwrap()]);
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "client 2: available differs (1.0000 vs 0.0000)
+client 2: held differs (1.0000 vs 2.0000)
+terminated due to error: 1 client(s) differ
+"
+    );
+
+    let output = payengine(&["diff", a.to_str().unwrap(), a.to_str().unwrap()]);
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "");
+}
+
+#[test]
+fn verify_output_file() {
+    let dir = TempDir::new("verify_output_file");
+    let output_file = dir.write_input(
+        "input",
+        "client,available,held,total,locked
+1,1.5,0.0,1.5,false
+2,1.0,0.5,2.0,false
+3,-1.0,0.0,-1.0,true
+",
+    );
+
+    let output = payengine(&["verify", output_file.to_str().unwrap()]);
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "client 2: available 1.0000 is not total 2.0000 minus held 0.5000
+client 3: total -1.0000 is negative
+terminated due to error: 2 row(s) are inconsistent
+"
+    );
+
+    let consistent = dir.write_input(
+        "consistent",
+        "client,available,held,total,locked
+1,1.5,0.0,1.5,false
+2,1.5,0.5,2.0,true
+",
+    );
+    let output = payengine(&["verify", consistent.to_str().unwrap()]);
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "");
+}
+
+#[cfg(feature = "json")]
+#[test]
+fn seed_balance() {
+    let dir = TempDir::new("seed_balance");
+    let seed = dir.path("seed_balance.json");
+    std::fs::write(
+        &seed,
+        r#"[{"client": 1, "deposits": [{"tx": 1, "amount": 10.0}, {"tx": 2, "amount": 5.0}]}]"#,
+    )
+    .unwrap();
+    let input = dir.write_input(
+        "input",
+        "type,client,tx,amount
+dispute,1,1,
+withdrawal,1,3,2.0
+",
+    );
+
+    let output = payengine(&[
+        input.to_str().unwrap(),
+        "--seed-balance",
+        seed.to_str().unwrap(),
+    ]);
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "client,available,held,total,locked\n1,3.0,10.0,13.0,false\n"
+    );
+    assert_eq!(String::from_utf8(output.stderr).unwrap(), "");
+}
+
+#[cfg(feature = "json")]
+#[test]
+fn seed_balance_excess_precision() {
+    let dir = TempDir::new("seed_balance_excess_precision");
+    let seed = dir.path("seed_balance_excess_precision.json");
+    std::fs::write(
+        &seed,
+        r#"[{"client": 1, "deposits": [{"tx": 1, "amount": "10.0"}, {"tx": 2, "amount": 0.00005}]}]"#,
+    )
+    .unwrap();
+    let input = dir.write_input("input", SAMPLE);
+
+    let output = payengine(&[
+        input.to_str().unwrap(),
+        "--seed-balance",
+        seed.to_str().unwrap(),
+    ]);
+    assert_eq!(output.status.code(), Some(1));
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains("failed to seed deposit 2 of client 1"));
+    assert!(stdout.contains("more decimal places than supported"));
+}
+
+#[cfg(not(feature = "json"))]
+#[test]
+fn seed_balance_unsupported() {
+    let dir = TempDir::new("seed_balance_unsupported");
+    let input = dir.write_input("input", SAMPLE);
+    let output = payengine(&[input.to_str().unwrap(), "--seed-balance", "seed.json"]);
+
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8(output.stdout)
+        .unwrap()
+        .contains("seed balance files are not supported"));
+}
+
+#[cfg(feature = "gzip")]
+#[test]
+fn gzip_input() {
+    let dir = TempDir::new("gzip_input");
+    use std::io::Write;
+
+    let mut encoder = flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+    encoder.write_all(SAMPLE.as_bytes()).unwrap();
+    let compressed = encoder.finish().unwrap();
+    let path = dir.path("input.csv.gz");
+    std::fs::write(&path, &compressed).unwrap();
+    let plain = dir.write_input("input", SAMPLE);
+
+    let expected = payengine(&[plain.to_str().unwrap()]);
+    let output = payengine(&[path.to_str().unwrap()]);
+    assert!(output.status.success());
+    assert_eq!(output.stdout, expected.stdout);
+
+    // any name, with the flag
+    let renamed = dir.write_input("renamed", "");
+    std::fs::write(&renamed, &compressed).unwrap();
+    let output = payengine(&[renamed.to_str().unwrap(), "--gzip"]);
+    assert!(output.status.success());
+    assert_eq!(output.stdout, expected.stdout);
+}
+
+#[cfg(not(feature = "gzip"))]
+#[test]
+fn gzip_input_unsupported() {
+    let dir = TempDir::new("gzip_input_unsupported");
+    let input = dir.write_input("input", SAMPLE);
+    let output = payengine(&[input.to_str().unwrap(), "--gzip"]);
+
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8(output.stdout)
+        .unwrap()
+        .contains("gzip input is not supported"));
+}
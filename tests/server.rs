@@ -0,0 +1,82 @@
+#![cfg(feature = "server")]
+
+use axum::body::Body;
+use axum::http::{Request, StatusCode};
+use payengine::async_processor::ProcessorHandle;
+use payengine::processor::{Config, InMemoryProcessor};
+use tower::ServiceExt;
+
+async fn send(app: &axum::Router, request: Request<Body>) -> (StatusCode, String) {
+    let response = app.clone().oneshot(request).await.unwrap();
+    let status = response.status();
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    (status, String::from_utf8(body.to_vec()).unwrap())
+}
+
+fn post_payment(json: &str) -> Request<Body> {
+    Request::post("/payments")
+        .header("content-type", "application/json")
+        .body(Body::from(json.to_string()))
+        .unwrap()
+}
+
+fn get(uri: &str) -> Request<Body> {
+    Request::get(uri).body(Body::empty()).unwrap()
+}
+
+#[tokio::test]
+async fn deposit_and_query() {
+    let app = payengine::server::router(ProcessorHandle::spawn(InMemoryProcessor::default(), 16));
+
+    let deposit = r#"{"type": "deposit", "client": 1, "tx": 1, "amount": 1.5}"#;
+    assert_eq!(
+        send(&app, post_payment(deposit)).await,
+        (StatusCode::OK, String::new())
+    );
+    assert_eq!(
+        send(&app, post_payment(deposit)).await,
+        (
+            StatusCode::CONFLICT,
+            "transaction 1 of client 1 already exists".to_string()
+        )
+    );
+
+    let (status, body) = send(&app, get("/accounts/1")).await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(body.contains(r#""total_funds":15000"#));
+    assert!(body.contains(r#""held_funds":0"#));
+
+    assert_eq!(
+        send(&app, get("/accounts/2")).await.0,
+        StatusCode::NOT_FOUND
+    );
+}
+
+#[tokio::test]
+async fn amounts_follow_the_processor_config() {
+    let processor = InMemoryProcessor::new(Config {
+        amount_decimal_places: Some(2),
+        ..Default::default()
+    });
+    let app = payengine::server::router(ProcessorHandle::spawn(processor, 16));
+
+    let deposit = r#"{"type": "deposit", "client": 1, "tx": 1, "amount": 1.5}"#;
+    assert_eq!(
+        send(&app, post_payment(deposit)).await,
+        (StatusCode::OK, String::new())
+    );
+    let deposit = r#"{"type": "deposit", "client": 1, "tx": 2, "amount": 1.505}"#;
+    assert_eq!(
+        send(&app, post_payment(deposit)).await,
+        (
+            StatusCode::BAD_REQUEST,
+            "amount has more decimal places than supported: 1.505".to_string()
+        )
+    );
+
+    let (status, body) = send(&app, get("/accounts/1")).await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(body.contains(r#""total_funds":150"#));
+}